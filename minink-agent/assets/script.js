@@ -1,4 +1,6 @@
 let sockets = null;
+// cursor of the next older page of each host, null once there are no more
+let next_pages = new Map();
 
 const debounce = (callback, wait) => {
     let timeoutId = null;
@@ -38,6 +40,11 @@ function build_url(hostname, ws) {
         url.searchParams.append("message_keywords", message_keywords);
     }
 
+    var level = document.getElementById("level-filter").value;
+    if (level) {
+        url.searchParams.append("level", level);
+    }
+
     return url;
 }
 
@@ -50,11 +57,37 @@ function clear_table() {
 }
 
 function populate_a_bit() {
+    next_pages.clear();
     for (const host of get_hosts()) {
         let url = build_url(host, false);
         fetch(url)
             .then((response) => response.json())
-            .then((entries) => entries.forEach(add_entry));
+            .then((page) => {
+                next_pages.set(host, page.next);
+                // newest first
+                page.entries.reverse().forEach(entry => add_entry(entry));
+            });
+    }
+}
+
+function fetch_older() {
+    for (const [host, cursor] of next_pages) {
+        if (cursor === null) {
+            continue;
+        }
+        let url = build_url(host, false);
+        url.searchParams.append("cursor", cursor);
+        // only once per page
+        next_pages.set(host, null);
+        fetch(url)
+            .then((response) => response.json())
+            .then((page) => {
+                next_pages.set(host, page.next);
+                let height = document.body.scrollHeight;
+                page.entries.forEach(entry => add_entry(entry, 0));
+                // keep the entries on screen where they were
+                window.scrollBy(0, document.body.scrollHeight - height);
+            });
     }
 }
 
@@ -79,6 +112,17 @@ function connect() {
 
         socket.addEventListener('message', (event) => {
             let entry = JSON.parse(event.data);
+            if (entry.dropped !== undefined) {
+                // the agent dropped entries because this client was too slow
+                add_entry_and_scroll({
+                    timestamp: new Date().toISOString(),
+                    hostname: host,
+                    service: "minink",
+                    level: "warning",
+                    message: `${entry.dropped} entries were dropped`,
+                });
+                return;
+            }
             add_entry_and_scroll(entry);
         });
 
@@ -98,15 +142,16 @@ function add_entry_and_scroll(entry) {
     }
 }
 
-function add_entry(entry) {
+function add_entry(entry, index = -1) {
     var table = document.getElementById("loglist-body");
-    var row = table.insertRow(-1);
+    var row = table.insertRow(index);
     row.insertCell(0).innerHTML = entry.timestamp;
     row.insertCell(1).innerHTML = entry.hostname;
     row.insertCell(2).innerHTML = entry.service;
+    row.insertCell(3).innerHTML = entry.level ?? "";
     var message = document.createElement("pre");
     message.appendChild(document.createTextNode(entry.message));
-    row.insertCell(3).appendChild(message);
+    row.insertCell(4).appendChild(message);
 }
 
 window.addEventListener("load", () => {
@@ -140,9 +185,17 @@ window.addEventListener("load", () => {
         sockets = connect();
     }, 250);
 
+    var level_filter = document.getElementById("level-filter");
+    level_filter.onchange = (e) => {
+        if (sockets !== null) {
+            sockets.forEach(s => s.close());
+        }
+        sockets = connect();
+    };
+
     window.addEventListener("wheel", debounce((e) => {
         if (e.deltaY < 0 && window.scrollY == 0) {
-            console.log("fetch some");
+            fetch_older();
         }
     }, 200));
 
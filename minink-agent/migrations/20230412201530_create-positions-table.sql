@@ -0,0 +1,4 @@
+create table source_positions (
+    source text primary key not null,
+    position text not null
+);
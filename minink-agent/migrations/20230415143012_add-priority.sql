@@ -0,0 +1,4 @@
+-- syslog priority of the entry, from 0 (emerg) to 7 (debug)
+alter table logs add column priority integer;
+
+create index idx_logs_priority on logs(priority);
@@ -0,0 +1,2 @@
+-- structured fields of the entry, as a json object
+alter table logs add column fields text;
@@ -0,0 +1,54 @@
+-- every entry keeps its own service and message, the full-text index refers
+-- to the entries by their id and is kept in sync by triggers
+alter table logs rename to logs_old;
+
+create table logs (
+    id integer primary key,
+    hostname text not null,
+    service text not null,
+    message text not null,
+    timestamp timestamp not null,
+    -- syslog priority of the entry, from 0 (emerg) to 7 (debug)
+    priority integer,
+    -- structured fields of the entry, as a json object
+    fields text
+);
+
+insert into logs(hostname, service, message, timestamp, priority, fields)
+select logs_old.hostname, logsfts.service, logsfts.message, logs_old.timestamp,
+    logs_old.priority, logs_old.fields
+from logs_old
+join logsfts on logsfts.rowid = logs_old.logsfts_id
+order by logs_old.rowid;
+
+drop table logs_old;
+drop table logsfts;
+
+create virtual table logsfts using fts5(
+    service,
+    message,
+    content = 'logs',
+    content_rowid = 'id'
+);
+insert into logsfts(logsfts) values ('rebuild');
+
+create trigger logs_after_insert after insert on logs begin
+    insert into logsfts(rowid, service, message)
+    values (new.id, new.service, new.message);
+end;
+
+create trigger logs_after_delete after delete on logs begin
+    insert into logsfts(logsfts, rowid, service, message)
+    values ('delete', old.id, old.service, old.message);
+end;
+
+create trigger logs_after_update after update on logs begin
+    insert into logsfts(logsfts, rowid, service, message)
+    values ('delete', old.id, old.service, old.message);
+    insert into logsfts(rowid, service, message)
+    values (new.id, new.service, new.message);
+end;
+
+create index idx_logs_timestamp on logs(timestamp);
+create index idx_logs_hostname on logs(hostname);
+create index idx_logs_priority on logs(priority);
@@ -0,0 +1,52 @@
+-- timestamps as microseconds since the epoch, compared directly to the
+-- bounds of the queries so that the index is used
+drop trigger logs_after_insert;
+drop trigger logs_after_delete;
+drop trigger logs_after_update;
+
+alter table logs rename to logs_old;
+
+create table logs (
+    id integer primary key,
+    hostname text not null,
+    service text not null,
+    message text not null,
+    -- microseconds since the epoch
+    timestamp integer not null,
+    -- syslog priority of the entry, from 0 (emerg) to 7 (debug)
+    priority integer,
+    -- structured fields of the entry, as a json object
+    fields text
+);
+
+-- the text is "YYYY-MM-DD HH:MM:SS" with an optional fraction of a second
+insert into logs(id, hostname, service, message, timestamp, priority, fields)
+select id, hostname, service, message,
+    unixepoch(timestamp) * 1000000
+        + cast(substr(substr(timestamp, 21) || '000000', 1, 6) as integer),
+    priority, fields
+from logs_old;
+
+drop table logs_old;
+
+-- the full-text index refers to the same ids, it does not change
+create trigger logs_after_insert after insert on logs begin
+    insert into logsfts(rowid, service, message)
+    values (new.id, new.service, new.message);
+end;
+
+create trigger logs_after_delete after delete on logs begin
+    insert into logsfts(logsfts, rowid, service, message)
+    values ('delete', old.id, old.service, old.message);
+end;
+
+create trigger logs_after_update after update on logs begin
+    insert into logsfts(logsfts, rowid, service, message)
+    values ('delete', old.id, old.service, old.message);
+    insert into logsfts(rowid, service, message)
+    values (new.id, new.service, new.message);
+end;
+
+create index idx_logs_timestamp on logs(timestamp);
+create index idx_logs_hostname on logs(hostname);
+create index idx_logs_priority on logs(priority);
@@ -0,0 +1,503 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Bound,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use chrono::{NaiveDateTime, Utc};
+
+use minink_common::{Filter, LogEntry, Order};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ArchiveConfig, database::LogDatabase};
+
+/// Magic number of the zstd skippable frame holding the index, which `zstd -d`
+/// ignores, so that an archive decompresses to the NDJSON of its entries.
+const INDEX_FRAME_MAGIC: u32 = 0x184D_2A5E;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Entries compressed together, and what the searches need to skip them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Chunk {
+    /// of the compressed frame in the file
+    offset: u64,
+    length: u64,
+    /// of the first entry, the next ones follow
+    first_id: i64,
+    entries: u64,
+    /// timestamps of the first and last entries, in microseconds
+    first: i64,
+    last: i64,
+    services: BTreeSet<String>,
+    hosts: BTreeSet<String>,
+}
+
+impl Chunk {
+    /// Whether some entries of the chunk may be accepted by `filter`.
+    fn may_accept(&self, filter: &Filter) -> bool {
+        let after_start = match &filter.timerange.0 {
+            Bound::Included(t) | Bound::Excluded(t) => self.last >= t.timestamp_micros(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &filter.timerange.1 {
+            Bound::Included(t) | Bound::Excluded(t) => self.first <= t.timestamp_micros(),
+            Bound::Unbounded => true,
+        };
+        after_start
+            && before_end
+            && self
+                .services
+                .iter()
+                .any(|service| filter.accept_service(service))
+            && self.hosts.iter().any(|host| filter.accept_host(host))
+    }
+
+    /// Timestamp and id of the first entry.
+    fn first_position(&self) -> (i64, i64) {
+        (self.first, self.first_id)
+    }
+
+    fn last_position(&self) -> (i64, i64) {
+        (self.last, self.first_id + self.entries as i64 - 1)
+    }
+}
+
+fn micros(entry: &LogEntry) -> i64 {
+    entry.timestamp.timestamp_micros()
+}
+
+/// The entries of a partition, in timestamp order, as zstd-compressed NDJSON
+/// chunks followed by the index of the chunks. The entries are numbered in
+/// their order, which tells apart the entries with the same timestamp.
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    /// of the file, to notice that it was replaced
+    length: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl Archive {
+    /// Write `entries`, in timestamp order, to a new archive at `path`.
+    pub fn create(
+        path: &Path,
+        entries: impl Iterator<Item = Result<LogEntry>>,
+        chunk_entries: usize,
+    ) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut chunks = vec![];
+        let mut chunk = Vec::with_capacity(chunk_entries);
+        let mut last = i64::MIN;
+        for entry in entries {
+            let entry = entry?;
+            anyhow::ensure!(
+                micros(&entry) >= last,
+                "archived entries must be in timestamp order"
+            );
+            last = micros(&entry);
+            chunk.push(entry);
+            if chunk.len() == chunk_entries {
+                write_chunk(&mut file, &mut chunks, &chunk)?;
+                chunk.clear();
+            }
+        }
+        if !chunk.is_empty() {
+            write_chunk(&mut file, &mut chunks, &chunk)?;
+        }
+
+        let index = serde_json::to_vec(&chunks)?;
+        let index_length = u32::try_from(index.len())?;
+        file.write_all(&INDEX_FRAME_MAGIC.to_le_bytes())?;
+        file.write_all(&(index_length + 4).to_le_bytes())?;
+        file.write_all(&index)?;
+        // read first to find the index from the end of the file
+        file.write_all(&index_length.to_le_bytes())?;
+        let file = file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            length: file.metadata()?.len(),
+            chunks,
+        })
+    }
+
+    /// Read the index of an existing archive.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let invalid = || anyhow::format_err!("{} is not a valid archive", path.display());
+        let read_u32 = |offset: u64| -> Result<u32> {
+            let mut bytes = [0; 4];
+            file.read_exact_at(&mut bytes, offset)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+
+        let index_length = u64::from(read_u32(length.checked_sub(4).ok_or_else(invalid)?)?);
+        let frame = length.checked_sub(index_length + 12).ok_or_else(invalid)?;
+        if read_u32(frame)? != INDEX_FRAME_MAGIC
+            || u64::from(read_u32(frame + 4)?) != index_length + 4
+        {
+            return Err(invalid());
+        }
+        let mut index = vec![0; index_length as usize];
+        file.read_exact_at(&mut index, frame + 8)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            length,
+            chunks: serde_json::from_slice(&index)?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the archive, replacing the file at `path` if any.
+    pub fn persist(mut self, path: &Path) -> Result<Self> {
+        std::fs::rename(&self.path, path)?;
+        self.path = path.to_path_buf();
+        Ok(self)
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.entries).sum()
+    }
+
+    /// Timestamp of the newest entry in microseconds, None if empty.
+    pub fn last_timestamp(&self) -> Option<i64> {
+        self.chunks.last().map(|chunk| chunk.last)
+    }
+
+    pub fn has_service(&self, service: &str) -> bool {
+        self.chunks
+            .iter()
+            .any(|chunk| chunk.services.contains(service))
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn file(&self) -> Result<File> {
+        let file = File::open(&self.path)?;
+        anyhow::ensure!(
+            file.metadata()?.len() == self.length,
+            "{} was replaced while reading it",
+            self.path.display()
+        );
+        Ok(file)
+    }
+
+    fn decode(&self, file: &File, chunk: &Chunk) -> Result<Vec<LogEntry>> {
+        let mut compressed = vec![0; chunk.length as usize];
+        file.read_exact_at(&mut compressed, chunk.offset)?;
+        let ndjson = zstd::stream::decode_all(&compressed[..])?;
+        let entries = ndjson
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<LogEntry>, _>>()?;
+        anyhow::ensure!(
+            entries.len() as u64 == chunk.entries,
+            "the chunk at {} of {} has {} entries instead of {}",
+            chunk.offset,
+            self.path.display(),
+            entries.len(),
+            chunk.entries
+        );
+        Ok(entries)
+    }
+
+    /// The entries of the `i`th chunk accepted by `filter`.
+    pub fn read(&self, i: usize, filter: &Filter) -> Result<Vec<LogEntry>> {
+        let chunk = &self.chunks[i];
+        if !chunk.may_accept(filter) {
+            return Ok(vec![]);
+        }
+        let mut entries = self.decode(&self.file()?, chunk)?;
+        entries.retain(|entry| filter.accept(entry));
+        Ok(entries)
+    }
+
+    /// Up to `limit` entries accepted by `filter` after the entry at `after`
+    /// in `order`, with their timestamp and id.
+    pub fn search(
+        &self,
+        filter: &Filter,
+        order: Order,
+        after: Option<(i64, i64)>,
+        limit: usize,
+    ) -> Result<Vec<((i64, i64), LogEntry)>> {
+        let file = self.file()?;
+        let mut chunks: Vec<&Chunk> = self.chunks.iter().collect();
+        if order == Order::Desc {
+            chunks.reverse();
+        }
+        let is_after = |position: (i64, i64)| {
+            after.is_none_or(|after| match order {
+                Order::Asc => position > after,
+                Order::Desc => position < after,
+            })
+        };
+
+        let mut found = vec![];
+        for chunk in chunks {
+            if found.len() >= limit {
+                break;
+            }
+            let passed = match order {
+                Order::Asc => !is_after(chunk.last_position()),
+                Order::Desc => !is_after(chunk.first_position()),
+            };
+            if passed || !chunk.may_accept(filter) {
+                continue;
+            }
+            let mut rows: Vec<_> = self
+                .decode(&file, chunk)?
+                .into_iter()
+                .enumerate()
+                .map(|(i, entry)| ((micros(&entry), chunk.first_id + i as i64), entry))
+                .collect();
+            if order == Order::Desc {
+                rows.reverse();
+            }
+            let rows = rows
+                .into_iter()
+                .filter(|(position, entry)| is_after(*position) && filter.accept(entry));
+            found.extend(rows.take(limit - found.len()));
+        }
+        Ok(found)
+    }
+
+    /// Every entry, chunk by chunk.
+    pub fn iter(&self) -> impl Iterator<Item = Result<LogEntry>> + '_ {
+        let filter = Filter::default();
+        (0..self.chunks.len()).flat_map(move |i| match self.read(i, &filter) {
+            Ok(entries) => entries.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(err) => vec![Err(err)],
+        })
+    }
+
+    /// Decompress every chunk and compare it with the index.
+    pub fn verify(&self) -> Result<()> {
+        let file = self.file()?;
+        for chunk in &self.chunks {
+            let entries = self.decode(&file, chunk)?;
+            let in_range = entries
+                .iter()
+                .all(|entry| (chunk.first..=chunk.last).contains(&micros(entry)));
+            anyhow::ensure!(
+                in_range,
+                "the chunk at {} of {} has entries out of its time range",
+                chunk.offset,
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn write_chunk(file: &mut impl Write, chunks: &mut Vec<Chunk>, entries: &[LogEntry]) -> Result<()> {
+    let mut ndjson = vec![];
+    for entry in entries {
+        serde_json::to_writer(&mut ndjson, entry)?;
+        ndjson.push(b'\n');
+    }
+    let compressed = zstd::stream::encode_all(&ndjson[..], COMPRESSION_LEVEL)?;
+    file.write_all(&compressed)?;
+
+    let (offset, first_id) = match chunks.last() {
+        Some(last) => (
+            last.offset + last.length,
+            last.first_id + last.entries as i64,
+        ),
+        None => (0, 1),
+    };
+    chunks.push(Chunk {
+        offset,
+        length: compressed.len() as u64,
+        first_id,
+        entries: entries.len() as u64,
+        first: entries.first().map(micros).unwrap_or_default(),
+        last: entries.last().map(micros).unwrap_or_default(),
+        services: entries.iter().map(|entry| entry.service.clone()).collect(),
+        hosts: entries.iter().map(|entry| entry.hostname.clone()).collect(),
+    });
+    Ok(())
+}
+
+/// Merge two sequences of entries in timestamp order, the entries of `a`
+/// first for the same timestamp. An error is returned as soon as it is seen.
+pub fn merge(
+    a: impl Iterator<Item = Result<LogEntry>>,
+    b: impl Iterator<Item = Result<LogEntry>>,
+) -> impl Iterator<Item = Result<LogEntry>> {
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+    std::iter::from_fn(move || {
+        let from_a = match (a.peek(), b.peek()) {
+            (Some(Ok(x)), Some(Ok(y))) => x.timestamp <= y.timestamp,
+            (_, Some(Err(_))) => false,
+            (Some(_), _) => true,
+            (None, _) => false,
+        };
+        if from_a {
+            a.next()
+        } else {
+            b.next()
+        }
+    })
+}
+
+/// Moves the partitions older than the configured age to archives.
+#[derive(Debug)]
+pub struct Archiver {
+    database: LogDatabase,
+    config: ArchiveConfig,
+}
+
+impl Archiver {
+    pub fn new(database: LogDatabase, config: ArchiveConfig) -> Self {
+        Self { database, config }
+    }
+
+    /// Archive the old partitions every interval, until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.config.interval());
+        loop {
+            interval.tick().await;
+            match self.archive(Utc::now().naive_utc()).await {
+                Ok(0) => {}
+                Ok(archived) => tracing::info!("archived {} partitions", archived),
+                // the next attempt may succeed, the ingestion goes on anyway
+                Err(err) => tracing::error!("archiving failed: {:#}", err),
+            }
+        }
+    }
+
+    /// Returns the number of archived partitions.
+    async fn archive(&self, now: NaiveDateTime) -> Result<usize> {
+        let Some(before) = now.checked_sub_signed(self.config.after()) else {
+            return Ok(0);
+        };
+        self.database
+            .archive(before, self.config.chunk_entries)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{Duration, NaiveDateTime};
+
+    use minink_common::{Filter, LogEntry, Order};
+
+    use super::{merge, Archive};
+
+    fn entries(services: &[&str], count: i64) -> Vec<LogEntry> {
+        let start = NaiveDateTime::from_timestamp_opt(1_682_380_800, 0).unwrap();
+        (0..count)
+            .map(|i| LogEntry {
+                message: format!("line {i}"),
+                hostname: "localhost".to_string(),
+                service: services[i as usize % services.len()].to_string(),
+                // two entries per second, to have the same timestamps
+                timestamp: start + Duration::seconds(i / 2),
+                level: None,
+                fields: Default::default(),
+            })
+            .collect()
+    }
+
+    /// Every page of a search, following the positions.
+    fn pages(archive: &Archive, filter: &Filter, order: Order) -> Result<Vec<LogEntry>> {
+        let mut all = vec![];
+        let mut after = None;
+        loop {
+            let page = archive.search(filter, order, after, 4)?;
+            match page.last() {
+                Some((position, _)) => after = Some(*position),
+                None => return Ok(all),
+            }
+            all.extend(page.into_iter().map(|(_, entry)| entry));
+        }
+    }
+
+    #[test]
+    fn test_archive() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("minink-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("logs.2023-04-25.archive");
+        let entries = entries(&["nginx", "kernel"], 25);
+        let created = Archive::create(&path, entries.clone().into_iter().map(Ok), 3)?;
+        assert_eq!(created.chunks(), 9);
+
+        let archive = Archive::open(&path)?;
+        assert_eq!(archive.entries(), 25);
+        archive.verify()?;
+        assert_eq!(archive.iter().collect::<Result<Vec<_>>>()?, entries);
+
+        let filter = Filter::default();
+        assert_eq!(pages(&archive, &filter, Order::Asc)?, entries);
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        assert_eq!(pages(&archive, &filter, Order::Desc)?, reversed);
+
+        let nginx = Filter {
+            services: Some(vec!["ngin".to_string()]),
+            ..Default::default()
+        };
+        let expected: Vec<_> = entries
+            .iter()
+            .filter(|e| e.service == "nginx")
+            .cloned()
+            .collect();
+        assert_eq!(pages(&archive, &nginx, Order::Asc)?, expected);
+        let none = Filter {
+            hosts: Some(vec!["web-".to_string()]),
+            ..Default::default()
+        };
+        assert!(archive.search(&none, Order::Desc, None, 10)?.is_empty());
+
+        // without the index, the file decompresses to the NDJSON of the entries
+        let ndjson = zstd::stream::decode_all(std::fs::File::open(&path)?)?;
+        let lines: Vec<LogEntry> = ndjson
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines, entries);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let all = entries(&["kernel"], 10);
+        let a = all.iter().step_by(3).cloned().map(Ok);
+        let b = all.iter().skip(1).step_by(3).cloned().map(Ok);
+        let merged = merge(a, b).collect::<Result<Vec<_>>>()?;
+        let messages: Vec<_> = merged.iter().map(|e| e.message.as_str()).collect();
+        // the entries of the first one first for the same timestamp
+        assert_eq!(
+            messages,
+            ["line 0", "line 1", "line 3", "line 4", "line 6", "line 7", "line 9"]
+        );
+
+        let failing = std::iter::once(Err(anyhow::format_err!("unreadable")));
+        assert!(merge(all.into_iter().map(Ok), failing)
+            .next()
+            .unwrap()
+            .is_err());
+        Ok(())
+    }
+}
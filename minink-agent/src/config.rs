@@ -0,0 +1,752 @@
+use std::{
+    collections::HashSet, net::SocketAddr, path::Path, path::PathBuf, sync::Arc, time::Duration,
+};
+
+use anyhow::Result;
+
+use regex::Regex;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    filetail::FileLogSource, journald::JournaldLogSource, journaldir::JournalDirectoryLogSource,
+    logdispatcher::OverflowPolicy, multiline::MultilineLogSource, source::LogSource,
+    stdin::StdinLogSource, syslog::SyslogLogSource,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_sources")]
+    pub sources: Vec<SourceConfig>,
+    #[serde(default)]
+    pub restart: RestartConfig,
+    /// send all the entries to another agent
+    #[serde(default)]
+    pub forward: Option<ForwardConfig>,
+    #[serde(default)]
+    pub live: LiveConfig,
+    /// delete the old entries, they are kept forever if not set
+    #[serde(default)]
+    pub retention: Option<RetentionConfig>,
+    /// compress the old partitions, they stay in the databases if not set
+    #[serde(default)]
+    pub archive: Option<ArchiveConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sources: default_sources(),
+            restart: RestartConfig::default(),
+            forward: None,
+            live: LiveConfig::default(),
+            retention: None,
+            archive: None,
+        }
+    }
+}
+
+/// Queue of each live client, the WebSocket connections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LiveConfig {
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 4096,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// How stopped sources are restarted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RestartConfig {
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// if set, give up after that many consecutive failures
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5 * 60 * 1000,
+            max_attempts: None,
+        }
+    }
+}
+
+impl RestartConfig {
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+}
+
+/// Where and how the entries are forwarded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// base URL of the upstream agent, such as http://central:3000
+    pub url: String,
+    /// batches not acknowledged by the upstream agent yet
+    #[serde(default = "default_spool")]
+    pub spool: PathBuf,
+    /// identifies this agent to the upstream agent, the hostname if not set
+    #[serde(default)]
+    pub sender: Option<String>,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_batch_interval_ms")]
+    pub batch_interval_ms: u64,
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+    /// a batch not acknowledged within that time is sent again later
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_spool() -> PathBuf {
+    PathBuf::from("forward.spool")
+}
+
+fn default_batch_size() -> usize {
+    1000
+}
+
+fn default_batch_interval_ms() -> u64 {
+    1000
+}
+
+fn default_retry_interval_ms() -> u64 {
+    5000
+}
+
+fn default_timeout_ms() -> u64 {
+    30_000
+}
+
+impl ForwardConfig {
+    pub fn batch_interval(&self) -> Duration {
+        Duration::from_millis(self.batch_interval_ms)
+    }
+
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_millis(self.retry_interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Which entries are deleted, and how.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// entries older than that are deleted, unless their service has its own rule
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+    /// the oldest entries are deleted while the database uses more than that
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+    #[serde(default)]
+    pub services: Vec<ServiceRetentionConfig>,
+    /// how often the entries are checked
+    #[serde(default = "default_retention_interval_ms")]
+    pub interval_ms: u64,
+    /// entries deleted by each transaction
+    #[serde(default = "default_retention_batch_size")]
+    pub batch_size: usize,
+}
+
+/// Maximum age of the entries of a service, instead of the global one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceRetentionConfig {
+    pub service: String,
+    /// the entries of the service are only deleted by `max_size_mb` if not set
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+}
+
+fn default_retention_interval_ms() -> u64 {
+    60 * 1000
+}
+
+fn default_retention_batch_size() -> usize {
+    1000
+}
+
+fn days(days: u64) -> chrono::Duration {
+    // the largest chrono::Duration
+    chrono::Duration::days(days.min(i64::MAX as u64 / 86_400_000) as i64)
+}
+
+impl RetentionConfig {
+    pub fn max_age(&self) -> Option<chrono::Duration> {
+        self.max_age_days.map(days)
+    }
+
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size_mb.map(|mb| mb.saturating_mul(1024 * 1024))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl ServiceRetentionConfig {
+    pub fn max_age(&self) -> Option<chrono::Duration> {
+        self.max_age_days.map(days)
+    }
+}
+
+/// When the partitions are moved to compressed archives.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// partitions ending more than that many days ago are archived
+    pub after_days: u64,
+    /// how often the partitions are checked
+    #[serde(default = "default_archive_interval_ms")]
+    pub interval_ms: u64,
+    /// entries of each compressed chunk, the unit read by the searches
+    #[serde(default = "default_chunk_entries")]
+    pub chunk_entries: usize,
+}
+
+fn default_archive_interval_ms() -> u64 {
+    10 * 60 * 1000
+}
+
+fn default_chunk_entries() -> usize {
+    10_000
+}
+
+impl ArchiveConfig {
+    pub fn after(&self) -> chrono::Duration {
+        days(self.after_days)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+fn default_sources() -> Vec<SourceConfig> {
+    vec![SourceConfig::Journald(JournaldConfig::default())]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    Journald(JournaldConfig),
+    #[serde(rename = "journal-directory")]
+    JournalDirectory(JournalDirectoryConfig),
+    Syslog(SyslogConfig),
+    File(FileConfig),
+    Stdin(StdinConfig),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournaldConfig {
+    #[serde(default = "default_journald_name")]
+    pub name: String,
+    /// if not empty, only follow these systemd units
+    #[serde(default)]
+    pub units: Vec<String>,
+    /// read the journal files from this directory instead of the system journal
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    #[serde(default)]
+    pub multiline: Option<MultilineConfig>,
+}
+
+fn default_journald_name() -> String {
+    "journald".to_string()
+}
+
+impl Default for JournaldConfig {
+    fn default() -> Self {
+        Self {
+            name: default_journald_name(),
+            units: vec![],
+            directory: None,
+            multiline: None,
+        }
+    }
+}
+
+/// Journal files read directly, for example copied from another machine.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournalDirectoryConfig {
+    pub name: String,
+    /// directory containing the *.journal files, such as /var/log/journal/<machine-id>
+    pub directory: PathBuf,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default)]
+    pub multiline: Option<MultilineConfig>,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+/// Syslog messages received over the network, at least one of `udp` and `tcp` is required.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    #[serde(default = "default_syslog_name")]
+    pub name: String,
+    #[serde(default)]
+    pub udp: Option<SocketAddr>,
+    #[serde(default)]
+    pub tcp: Option<SocketAddr>,
+    /// TCP connections handled at once, the others wait to be accepted
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    #[serde(default)]
+    pub multiline: Option<MultilineConfig>,
+}
+
+fn default_syslog_name() -> String {
+    "syslog".to_string()
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+/// Text files followed line by line, such as `/var/log/nginx/*.log`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub name: String,
+    /// glob patterns of the files to follow
+    pub paths: Vec<String>,
+    /// service of the entries, the file name without extension if not set
+    #[serde(default)]
+    pub service: Option<String>,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default)]
+    pub multiline: Option<MultilineConfig>,
+}
+
+/// The lines written to the standard input of the agent.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StdinConfig {
+    #[serde(default = "default_stdin_name")]
+    pub name: String,
+    /// service of the entries, the name of the source if not set
+    #[serde(default)]
+    pub service: Option<String>,
+    #[serde(default)]
+    pub multiline: Option<MultilineConfig>,
+}
+
+fn default_stdin_name() -> String {
+    "stdin".to_string()
+}
+
+/// Lines grouped into a single entry, such as stack traces.
+/// A line starts a new entry if it matches `start`; otherwise it is added to the
+/// previous one if it matches `continuation`, or if only `start` is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultilineConfig {
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub start: Option<Regex>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub continuation: Option<Regex>,
+    #[serde(default = "default_max_lines")]
+    pub max_lines: usize,
+    /// an entry is sent when no line was added to it for that long
+    #[serde(default = "default_flush_timeout_ms")]
+    pub flush_timeout_ms: u64,
+}
+
+fn default_max_lines() -> usize {
+    500
+}
+
+fn default_flush_timeout_ms() -> u64 {
+    1000
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl MultilineConfig {
+    pub fn flush_timeout(&self) -> Duration {
+        Duration::from_millis(self.flush_timeout_ms)
+    }
+}
+
+impl SourceConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            SourceConfig::Journald(c) => &c.name,
+            SourceConfig::JournalDirectory(c) => &c.name,
+            SourceConfig::Syslog(c) => &c.name,
+            SourceConfig::File(c) => &c.name,
+            SourceConfig::Stdin(c) => &c.name,
+        }
+    }
+
+    fn multiline(&self) -> Option<&MultilineConfig> {
+        match self {
+            SourceConfig::Journald(c) => c.multiline.as_ref(),
+            SourceConfig::JournalDirectory(c) => c.multiline.as_ref(),
+            SourceConfig::Syslog(c) => c.multiline.as_ref(),
+            SourceConfig::File(c) => c.multiline.as_ref(),
+            SourceConfig::Stdin(c) => c.multiline.as_ref(),
+        }
+    }
+
+    pub fn build(&self) -> Arc<dyn LogSource> {
+        let source = self.build_source();
+        match self.multiline() {
+            Some(multiline) => Arc::new(MultilineLogSource::new(source, multiline.clone())),
+            None => source,
+        }
+    }
+
+    fn build_source(&self) -> Arc<dyn LogSource> {
+        match self {
+            SourceConfig::Journald(c) => Arc::new(JournaldLogSource::new(c)),
+            SourceConfig::JournalDirectory(c) => Arc::new(JournalDirectoryLogSource::new(c)),
+            SourceConfig::Syslog(c) => Arc::new(SyslogLogSource::new(c)),
+            SourceConfig::File(c) => Arc::new(FileLogSource::new(c)),
+            SourceConfig::Stdin(c) => Arc::new(StdinLogSource::new(c)),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Self> {
+        let config: Config = toml::from_str(content)?;
+        let mut names = HashSet::new();
+        let stdin_sources = config
+            .sources
+            .iter()
+            .filter(|source| matches!(source, SourceConfig::Stdin(_)))
+            .count();
+        // they would read each other's lines
+        anyhow::ensure!(stdin_sources <= 1, "only one stdin source can be used");
+        for source in &config.sources {
+            if !names.insert(source.name()) {
+                anyhow::bail!("duplicate source name '{}'", source.name());
+            }
+            if let Some(multiline) = source.multiline() {
+                if multiline.start.is_none() && multiline.continuation.is_none() {
+                    anyhow::bail!(
+                        "multiline of source '{}' needs a start or continuation pattern",
+                        source.name()
+                    );
+                }
+            }
+            if let SourceConfig::Syslog(c) = source {
+                if c.udp.is_none() && c.tcp.is_none() {
+                    anyhow::bail!("syslog source '{}' needs a udp or tcp address", c.name);
+                }
+                anyhow::ensure!(
+                    c.max_connections > 0,
+                    "max_connections of syslog source '{}' must be positive",
+                    c.name
+                );
+            }
+        }
+        if let Some(retention) = &config.retention {
+            anyhow::ensure!(
+                retention.batch_size > 0 && retention.interval_ms > 0,
+                "retention batch_size and interval_ms must be positive"
+            );
+            let mut services = HashSet::new();
+            for service in &retention.services {
+                if !services.insert(&service.service) {
+                    anyhow::bail!("duplicate retention of service '{}'", service.service);
+                }
+            }
+        }
+        if let Some(forward) = &config.forward {
+            anyhow::ensure!(
+                forward.batch_size > 0
+                    && forward.batch_interval_ms > 0
+                    && forward.retry_interval_ms > 0
+                    && forward.timeout_ms > 0,
+                "forward batch_size, batch_interval_ms, retry_interval_ms and timeout_ms must be positive"
+            );
+        }
+        if let Some(archive) = &config.archive {
+            anyhow::ensure!(
+                archive.chunk_entries > 0 && archive.interval_ms > 0,
+                "archive chunk_entries and interval_ms must be positive"
+            );
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, SourceConfig};
+
+    use crate::logdispatcher::OverflowPolicy;
+
+    #[test]
+    fn test_parse_sources() {
+        let config = Config::parse(
+            r#"
+            [[sources]]
+            type = "journald"
+
+            [[sources]]
+            type = "journald"
+            name = "nginx"
+            units = ["nginx.service"]
+
+            [[sources]]
+            type = "journal-directory"
+            name = "crashed-host"
+            directory = "/srv/journals/crashed-host"
+
+            [[sources]]
+            type = "syslog"
+            udp = "0.0.0.0:514"
+
+            [[sources]]
+            type = "file"
+            name = "nginx-files"
+            paths = ["/var/log/nginx/*.log"]
+
+            [sources.multiline]
+            continuation = '^\s+at '
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.sources.len(), 5);
+        let SourceConfig::Journald(c) = &config.sources[1] else {
+            panic!("expected a journald source");
+        };
+        assert_eq!(c.units, vec!["nginx.service"]);
+        let SourceConfig::JournalDirectory(c) = &config.sources[2] else {
+            panic!("expected a journal-directory source");
+        };
+        assert_eq!(c.name, "crashed-host");
+        assert_eq!(c.poll_interval_ms, 1000);
+        let SourceConfig::Syslog(c) = &config.sources[3] else {
+            panic!("expected a syslog source");
+        };
+        assert_eq!(c.name, "syslog");
+        assert_eq!(c.udp, Some("0.0.0.0:514".parse().unwrap()));
+        assert_eq!(c.tcp, None);
+        assert_eq!(c.max_connections, 256);
+        let SourceConfig::File(c) = &config.sources[4] else {
+            panic!("expected a file source");
+        };
+        assert_eq!(c.paths, vec!["/var/log/nginx/*.log"]);
+        assert_eq!(c.service, None);
+        let multiline = c.multiline.as_ref().unwrap();
+        assert!(multiline
+            .continuation
+            .as_ref()
+            .unwrap()
+            .is_match("    at Main.run"));
+        assert_eq!(multiline.max_lines, 500);
+    }
+
+    #[test]
+    fn test_default_sources() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.sources[0].name(), "journald");
+        assert_eq!(config.restart.max_attempts, None);
+        assert!(config.forward.is_none());
+        assert_eq!(config.live.overflow, OverflowPolicy::DropOldest);
+    }
+
+    #[test]
+    fn test_parse_live() {
+        let config = Config::parse(
+            r#"
+            [live]
+            queue_size = 100
+            overflow = "disconnect"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.live.queue_size, 100);
+        assert_eq!(config.live.overflow, OverflowPolicy::Disconnect);
+    }
+
+    #[test]
+    fn test_parse_forward() {
+        let config = Config::parse(
+            r#"
+            [forward]
+            url = "http://central:3000"
+            "#,
+        )
+        .unwrap();
+        let forward = config.forward.unwrap();
+        assert_eq!(forward.url, "http://central:3000");
+        assert_eq!(forward.batch_size, 1000);
+        assert_eq!(forward.sender, None);
+
+        for zero in [
+            "batch_size",
+            "batch_interval_ms",
+            "retry_interval_ms",
+            "timeout_ms",
+        ] {
+            let content = format!("[forward]\nurl = \"http://central:3000\"\n{zero} = 0");
+            assert!(Config::parse(&content).is_err(), "{zero}");
+        }
+    }
+
+    #[test]
+    fn test_parse_retention() {
+        let config = Config::parse(
+            r#"
+            [retention]
+            max_age_days = 30
+            max_size_mb = 500
+
+            [[retention.services]]
+            service = "nginx"
+            max_age_days = 7
+
+            [[retention.services]]
+            service = "audit"
+            "#,
+        )
+        .unwrap();
+        let retention = config.retention.unwrap();
+        assert_eq!(retention.max_age(), Some(chrono::Duration::days(30)));
+        assert_eq!(retention.max_size(), Some(500 * 1024 * 1024));
+        assert_eq!(retention.batch_size, 1000);
+        assert_eq!(
+            retention.services[0].max_age(),
+            Some(chrono::Duration::days(7))
+        );
+        assert_eq!(retention.services[1].max_age(), None);
+
+        let duplicate = Config::parse(
+            r#"
+            [[retention.services]]
+            service = "nginx"
+            [[retention.services]]
+            service = "nginx"
+            "#,
+        );
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn test_parse_archive() {
+        let config = Config::parse("[archive]\nafter_days = 7").unwrap();
+        let archive = config.archive.unwrap();
+        assert_eq!(archive.after(), chrono::Duration::days(7));
+        assert_eq!(archive.chunk_entries, 10_000);
+
+        assert!(Config::parse("[archive]\nafter_days = 7\nchunk_entries = 0").is_err());
+        assert!(Config::parse("[archive]").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let config = Config::parse(
+            r#"
+            [[sources]]
+            type = "journald"
+            [[sources]]
+            type = "journald"
+            "#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_invalid_multiline() {
+        for multiline in ["", "start = '('"] {
+            let config = Config::parse(&format!(
+                r#"
+                [[sources]]
+                type = "journald"
+                [sources.multiline]
+                {multiline}
+                "#
+            ));
+            assert!(config.is_err());
+        }
+    }
+
+    #[test]
+    fn test_stdin() {
+        let config = Config::parse(
+            r#"
+            [[sources]]
+            type = "stdin"
+            service = "app"
+            "#,
+        )
+        .unwrap();
+        let SourceConfig::Stdin(c) = &config.sources[0] else {
+            panic!("expected a stdin source");
+        };
+        assert_eq!(c.name, "stdin");
+        assert_eq!(c.service.as_deref(), Some("app"));
+
+        let config = Config::parse(
+            r#"
+            [[sources]]
+            type = "stdin"
+
+            [[sources]]
+            type = "stdin"
+            name = "other"
+            "#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_syslog_without_address() {
+        let config = Config::parse(
+            r#"
+            [[sources]]
+            type = "syslog"
+            "#,
+        );
+        assert!(config.is_err());
+    }
+}
@@ -1,22 +1,217 @@
-use std::{ops::Bound, str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::Result;
 
 use chrono::NaiveDateTime;
 
-use minink_common::{Filter, LogEntry};
+use futures_lite::StreamExt;
+
+use minink_common::{ExtractPage, Filter, GroupBy, Histogram, Level, LogEntry, Order};
 
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow},
-    ConnectOptions, QueryBuilder, Row, Sqlite, SqlitePool,
+    pool::PoolConnection,
+    sqlite::{SqliteAutoVacuum, SqliteConnectOptions, SqliteRow, SqliteSynchronous},
+    ConnectOptions, Connection, QueryBuilder, Row, Sqlite, SqliteExecutor, SqlitePool, Transaction,
 };
 
-use tokio::sync::Mutex;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use crate::{
+    archive::{merge, Archive},
+    forward::ForwardedBatch,
+    logdispatcher::LogRecord,
+    partition::{Partitioning, Partitions, Segment},
+    store::{level_group, HistogramCounts},
+};
+
+/// Entries not yet written to the database, along with the latest position
+/// of each source that produced them.
+#[derive(Debug, Default)]
+struct PendingLogs {
+    entries: Vec<LogEntry>,
+    positions: BTreeMap<String, String>,
+}
+
+/// Entries are written when this many are pending, even before the flush interval.
+const MAX_PENDING_ENTRIES: usize = 8192;
+
+/// A failed write of the pending entries is retried after that long, twice
+/// as long after each failure, up to `MAX_WRITE_BACKOFF`.
+const INITIAL_WRITE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_WRITE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The writer stops after that many failed writes in a row, about 6 minutes.
+const MAX_WRITE_ATTEMPTS: u32 = 20;
+
+/// How much can be lost if the agent or the machine stops abruptly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum Durability {
+    /// entries are written in batches, which may not be on disk yet after a
+    /// power loss (`synchronous=NORMAL`)
+    Buffered,
+    /// entries are written in batches, each one synced to disk (`synchronous=FULL`)
+    #[default]
+    Batch,
+    /// every entry is written and synced to disk as soon as it is received
+    /// (`synchronous=FULL`, no batches)
+    Full,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions {
+    pub durability: Durability,
+    /// pending entries are written at least that often
+    pub flush_interval: Duration,
+    pub partitioning: Partitioning,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            flush_interval: Duration::from_secs(1),
+            partitioning: Partitioning::default(),
+        }
+    }
+}
+
+/// Entries of a page when the client does not ask for a size, and at most.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 10_000;
+
+/// Position of an entry in the order of the pages, the id telling apart the
+/// entries with the same timestamp. Opaque to the clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    /// in microseconds
+    pub timestamp: i64,
+    pub id: i64,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}{:016x}", self.timestamp as u64, self.id as u64)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        anyhow::ensure!(
+            s.len() == 32 && s.is_char_boundary(16),
+            "invalid cursor {s}"
+        );
+        let parse = |hex| {
+            u64::from_str_radix(hex, 16).map_err(|_| anyhow::format_err!("invalid cursor {s}"))
+        };
+        Ok(Self {
+            timestamp: parse(&s[..16])? as i64,
+            id: parse(&s[16..])? as i64,
+        })
+    }
+}
+
+/// Which entries of the filtered ones are returned.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub size: usize,
+    pub order: Order,
+    /// start right after this entry, from the newest or oldest one if None
+    pub after: Option<Cursor>,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_PAGE_SIZE,
+            order: Order::default(),
+            after: None,
+        }
+    }
+}
+
+/// Entries deleted by the retention, oldest first.
+#[derive(Debug, Clone)]
+pub enum Expired {
+    /// entries of `service` older than `before`
+    Service {
+        service: String,
+        before: NaiveDateTime,
+    },
+    /// entries of every service but `except` older than `before`
+    Others {
+        except: Vec<String>,
+        before: NaiveDateTime,
+    },
+    /// the oldest entries, whatever their age
+    Oldest,
+}
+
+/// Entries moved from the main database to the partitions by each transaction.
+const MOVE_BATCH_SIZE: i64 = 10_000;
+
+/// Pages of the full-text index merged by each step of a vacuum, about 4MB.
+const FTS_MERGE_PAGES: i64 = 1000;
+
+/// The journal mode of all the databases, kept by the files once set.
+pub const ENABLE_WAL: &str = "pragma journal_mode = wal";
+
+/// Free pages given back to the file system by each step of a vacuum, about 4MB.
+const VACUUM_PAGES: i64 = 1000;
+
+/// Space used by the database file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatabaseSize {
+    /// bytes of the pages in use, the file is larger until the free pages are vacuumed
+    pub used: u64,
+    pub entries: u64,
+}
+
+/// Requests to the writer task, the only one writing to the database.
+enum Command {
+    Log(LogRecord),
+    Forwarded {
+        batch: ForwardedBatch,
+        entries: Vec<LogEntry>,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    Delete {
+        expired: Expired,
+        limit: usize,
+        reply: oneshot::Sender<Result<u64>>,
+    },
+    /// one step of the vacuum, answers whether there is more to do
+    Vacuum(oneshot::Sender<Result<bool>>),
+    /// the entries of the partition up to `max_id` were written to an archive
+    Archived {
+        key: i64,
+        archive: Archive,
+        max_id: i64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Sync(oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<Result<()>>),
+}
 
 #[derive(Debug, Clone)]
 pub struct LogDatabase {
+    /// of the main database
     pool: SqlitePool,
-    entries: Arc<Mutex<Vec<LogEntry>>>,
+    partitions: Arc<Partitions>,
+    writer: mpsc::Sender<Command>,
+    /// why the writer stopped, once it did
+    stopped: Arc<Mutex<Option<String>>>,
+    durability: Durability,
 }
 
 fn convert_to_fts_match<S: AsRef<str>>(filter: &[S]) -> String {
@@ -46,182 +241,1269 @@ fn to_sqlite_phrase(m: &[String]) -> Option<String> {
     }
 }
 
+/// GLOB pattern of the values starting with `prefix`, which can use an index.
+fn glob_prefix(prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        match c {
+            '*' | '?' | '[' => pattern.extend(['[', c, ']']),
+            _ => pattern.push(c),
+        }
+    }
+    pattern.push('*');
+    pattern
+}
+
 trait PushToQuery {
     fn push_to_query(&self, column: &str, query: &mut QueryBuilder<Sqlite>);
 }
 
+/// Stored timestamps, in microseconds since the epoch, rounded down.
+fn to_micros(t: &NaiveDateTime) -> i64 {
+    t.timestamp_micros()
+}
+
+fn from_micros(micros: i64) -> Result<NaiveDateTime, sqlx::Error> {
+    NaiveDateTime::from_timestamp_micros(micros)
+        .ok_or_else(|| sqlx::Error::Decode(format!("invalid timestamp {micros}").into()))
+}
+
+/// `t` in microseconds, rounded up.
+fn to_micros_ceil(t: &NaiveDateTime) -> i64 {
+    to_micros(t) + i64::from(!t.timestamp_subsec_nanos().is_multiple_of(1000))
+}
+
+/// Compared to the integer column, so that its index is used; the bounds are
+/// rounded so that the stored entries are accepted as by `Filter::accept`.
 impl PushToQuery for (Bound<NaiveDateTime>, Bound<NaiveDateTime>) {
+    fn push_to_query(&self, column: &str, query: &mut QueryBuilder<Sqlite>) {
+        match &self.0 {
+            Bound::Included(t) => {
+                query
+                    .push(format!(" and {column} >= "))
+                    .push_bind(to_micros_ceil(t));
+            }
+            Bound::Excluded(t) => {
+                query
+                    .push(format!(" and {column} > "))
+                    .push_bind(to_micros(t));
+            }
+            Bound::Unbounded => (),
+        }
+        match &self.1 {
+            Bound::Included(t) => {
+                query
+                    .push(format!(" and {column} <= "))
+                    .push_bind(to_micros(t));
+            }
+            Bound::Excluded(t) => {
+                query
+                    .push(format!(" and {column} < "))
+                    .push_bind(to_micros_ceil(t));
+            }
+            Bound::Unbounded => (),
+        }
+    }
+}
+
+impl PushToQuery for (Bound<Level>, Bound<Level>) {
     fn push_to_query(&self, column: &str, query: &mut QueryBuilder<Sqlite>) {
         match self.0 {
-            std::ops::Bound::Included(t) => {
+            Bound::Included(l) => {
                 query
-                    .push(format!(" and unixepoch({column}) >= "))
-                    .push_bind(t.timestamp());
+                    .push(format!(" and {column} >= "))
+                    .push_bind(l.priority());
             }
-            std::ops::Bound::Excluded(t) => {
+            Bound::Excluded(l) => {
                 query
-                    .push(format!(" and unixepoch({column}) > "))
-                    .push_bind(t.timestamp());
+                    .push(format!(" and {column} > "))
+                    .push_bind(l.priority());
             }
-            std::ops::Bound::Unbounded => (),
+            Bound::Unbounded => (),
         }
         match self.1 {
-            std::ops::Bound::Included(t) => {
+            Bound::Included(l) => {
                 query
-                    .push(format!(" and unixepoch({column}) <= "))
-                    .push_bind(t.timestamp());
+                    .push(format!(" and {column} <= "))
+                    .push_bind(l.priority());
             }
-            std::ops::Bound::Excluded(t) => {
+            Bound::Excluded(l) => {
                 query
-                    .push(format!(" and unixepoch({column}) < "))
-                    .push_bind(t.timestamp());
+                    .push(format!(" and {column} < "))
+                    .push_bind(l.priority());
+            }
+            Bound::Unbounded => (),
+        }
+    }
+}
+
+/// Rows inserted by each statement. Statements are prepared and cached by
+/// their SQL, so that there is one for this many rows and one for a single row.
+const ROWS_PER_STATEMENT: usize = 64;
+
+/// `insert into table(a, b) values (?, ?), (?, ?)` for `rows` rows.
+fn insert_sql(into: &str, columns: usize, rows: usize) -> String {
+    let row = format!("({})", vec!["?"; columns].join(", "));
+    format!("{into} values {}", vec![row; rows].join(", "))
+}
+
+async fn insert_positions(
+    tx: &mut Transaction<'_, Sqlite>,
+    positions: &BTreeMap<String, String>,
+) -> Result<()> {
+    for (source, position) in positions {
+        sqlx::query(
+            "insert into source_positions(source, position) values (?, ?)
+            on conflict(source) do update set position = excluded.position",
+        )
+        .bind(source)
+        .bind(position)
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn insert_entries(tx: &mut Transaction<'_, Sqlite>, entries: &[&LogEntry]) -> Result<()> {
+    let mut chunks = entries.chunks_exact(ROWS_PER_STATEMENT);
+    for chunk in &mut chunks {
+        insert_rows(tx, chunk).await?;
+    }
+    for entry in chunks.remainder() {
+        insert_rows(tx, std::slice::from_ref(entry)).await?;
+    }
+    Ok(())
+}
+
+async fn insert_rows(tx: &mut Transaction<'_, Sqlite>, entries: &[&LogEntry]) -> Result<()> {
+    // the full-text index is updated by a trigger
+    let sql = insert_sql(
+        "insert into logs(hostname, service, message, timestamp, priority, fields)",
+        6,
+        entries.len(),
+    );
+    let mut query = sqlx::query(&sql);
+    for entry in entries {
+        let fields = if entry.fields.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&entry.fields)?)
+        };
+        query = query
+            .bind(&entry.hostname)
+            .bind(&entry.service)
+            .bind(&entry.message)
+            .bind(to_micros(&entry.timestamp))
+            .bind(entry.level.map(Level::priority))
+            .bind(fields);
+    }
+    query.execute(&mut *tx).await?;
+    Ok(())
+}
+
+/// Send the result of a request back, the requester handles its errors.
+fn answer<T>(reply: oneshot::Sender<Result<T>>, result: Result<T>) {
+    let _ = reply.send(result);
+}
+
+/// Owns the connections used for all the writes, so that the sources, the
+/// flushes and the readers never wait for each other on a lock.
+struct Writer {
+    /// of the main database, with the positions of the sources
+    conn: PoolConnection<Sqlite>,
+    partitions: Arc<Partitions>,
+    /// of the partitions written by the last flush, or since
+    partition_conns: BTreeMap<i64, PoolConnection<Sqlite>>,
+    /// databases with deleted entries since the last vacuum, None for the main one
+    deleted: BTreeSet<Option<i64>>,
+    pending: PendingLogs,
+    flush_interval: Duration,
+}
+
+impl Writer {
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) -> Result<()> {
+        // set when the first entry of a batch is received
+        let mut deadline: Option<Instant> = None;
+        loop {
+            let command = tokio::select! {
+                command = commands.recv() => command,
+                _ = async { tokio::time::sleep_until(deadline.unwrap()).await }, if deadline.is_some() => {
+                    self.flush_until_written().await?;
+                    deadline = None;
+                    continue;
+                },
+            };
+            match command {
+                Some(Command::Log(record)) => {
+                    self.pending.entries.push(record.entry);
+                    if let Some(checkpoint) = record.checkpoint {
+                        self.pending
+                            .positions
+                            .insert(checkpoint.source, checkpoint.position);
+                    }
+                    if self.pending.entries.len() >= MAX_PENDING_ENTRIES {
+                        self.flush_until_written().await?;
+                        deadline = None;
+                    } else if deadline.is_none() {
+                        deadline = Some(Instant::now() + self.flush_interval);
+                    }
+                }
+                Some(Command::Forwarded {
+                    batch,
+                    entries,
+                    reply,
+                }) => {
+                    let result = self.insert_forwarded(&batch, entries).await;
+                    answer(reply, result);
+                }
+                Some(Command::Delete {
+                    expired,
+                    limit,
+                    reply,
+                }) => {
+                    let result = self.delete(&expired, limit).await;
+                    answer(reply, result);
+                }
+                Some(Command::Vacuum(reply)) => {
+                    let result = self.vacuum().await;
+                    answer(reply, result);
+                }
+                Some(Command::Archived {
+                    key,
+                    archive,
+                    max_id,
+                    reply,
+                }) => {
+                    let result = self.archived(key, archive, max_id).await;
+                    answer(reply, result);
+                }
+                Some(Command::Sync(reply)) => {
+                    let result = self.flush_until_written().await;
+                    deadline = None;
+                    if let Err(err) = result {
+                        answer(reply, Err(anyhow::format_err!("{err:#}")));
+                        return Err(err);
+                    }
+                    answer(reply, Ok(()));
+                }
+                Some(Command::Close(reply)) => {
+                    let result = self.close().await;
+                    answer(reply, result);
+                    return Ok(());
+                }
+                // every LogDatabase was dropped
+                None => return self.flush_until_written().await,
+            }
+        }
+    }
+
+    /// Write the pending entries, which are kept for the next attempt if it fails.
+    async fn flush(&mut self) -> Result<()> {
+        if self.pending.entries.is_empty() && self.pending.positions.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let result = self.write(&pending.entries, &pending.positions).await;
+        if result.is_err() {
+            self.pending = pending;
+        }
+        result
+    }
+
+    /// Retry the write of the pending entries with an exponential backoff,
+    /// meanwhile the sources wait as no other entry is received. Fails after
+    /// `MAX_WRITE_ATTEMPTS`, the writer then stops.
+    async fn flush_until_written(&mut self) -> Result<()> {
+        let mut backoff = INITIAL_WRITE_BACKOFF;
+        let mut attempts = 1;
+        loop {
+            let Err(err) = self.flush().await else {
+                return Ok(());
+            };
+            if attempts == MAX_WRITE_ATTEMPTS {
+                return Err(err.context(format!("writing the entries failed {attempts} times")));
+            }
+            tracing::error!(
+                "writing {} entries failed, retrying in {:?}: {:#}",
+                self.pending.entries.len(),
+                backoff,
+                err
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = Duration::min(backoff * 2, MAX_WRITE_BACKOFF);
+            attempts += 1;
+        }
+    }
+
+    /// The connection of the main database for None, or of a partition,
+    /// created if needed.
+    async fn conn(&mut self, key: Option<i64>) -> Result<&mut PoolConnection<Sqlite>> {
+        let Some(key) = key else {
+            return Ok(&mut self.conn);
+        };
+        if !self.partition_conns.contains_key(&key) {
+            let partition = self.partitions.get_or_create(key).await?;
+            let conn = partition.pool.acquire().await?;
+            self.partition_conns.insert(key, conn);
+        }
+        Ok(self.partition_conns.get_mut(&key).unwrap())
+    }
+
+    /// Write each entry to the partition of its timestamp, then the positions
+    /// along with the entries of the main database.
+    async fn write(
+        &mut self,
+        entries: &[LogEntry],
+        positions: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let mut main = vec![];
+        let mut partitioned: BTreeMap<i64, Vec<&LogEntry>> = BTreeMap::new();
+        for entry in entries {
+            match self.partitions.key(to_micros(&entry.timestamp)) {
+                Some(key) => partitioned.entry(key).or_default().push(entry),
+                None => main.push(entry),
+            }
+        }
+        let keys = partitioned.keys().copied().collect::<BTreeSet<_>>();
+        for (key, entries) in partitioned {
+            let mut tx = self.conn(Some(key)).await?.begin().await?;
+            insert_entries(&mut tx, &entries).await?;
+            tx.commit().await?;
+        }
+        // only the partitions still written keep a connection
+        self.partition_conns.retain(|key, _| keys.contains(key));
+
+        let mut tx = self.conn.begin().await?;
+        // the positions are committed along with the entries, so that a source
+        // never resumes before or after what was actually stored; with
+        // partitions, after them, so that it may resume before but never after
+        insert_positions(&mut tx, positions).await?;
+        insert_entries(&mut tx, &main).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_forwarded(
+        &mut self,
+        batch: &ForwardedBatch,
+        entries: Vec<LogEntry>,
+    ) -> Result<bool> {
+        let source = batch.source();
+        let last: Option<String> =
+            sqlx::query_scalar("select position from source_positions where source = ?")
+                .bind(&source)
+                .fetch_optional(&mut self.conn)
+                .await?;
+        if batch.is_stored(last.as_deref()) {
+            return Ok(false);
+        }
+
+        self.write(&entries, &BTreeMap::from([(source, batch.position())]))
+            .await?;
+        Ok(true)
+    }
+
+    /// Move the entries written before the database was partitioned to their
+    /// partitions. If the agent stops meanwhile, the last batch may be moved twice.
+    async fn move_to_partitions(&mut self) -> Result<()> {
+        let filter = Filter::default();
+        let mut moved = 0;
+        loop {
+            let mut query = filter_query(&filter);
+            query.push(" order by id limit ").push_bind(MOVE_BATCH_SIZE);
+            let rows: Vec<(Cursor, LogEntry)> = query
+                .build()
+                .try_map(from_row)
+                .fetch_all(&mut self.conn)
+                .await?;
+            let Some((last, _)) = rows.last() else {
+                break;
+            };
+            if moved == 0 {
+                tracing::info!("moving the entries of the main database to the partitions");
+            }
+            let last = last.id;
+            moved += rows.len();
+            let entries: Vec<_> = rows.into_iter().map(|(_, entry)| entry).collect();
+            self.write(&entries, &BTreeMap::new()).await?;
+            sqlx::query("delete from logs where id <= ?")
+                .bind(last)
+                .execute(&mut self.conn)
+                .await?;
+        }
+        if moved > 0 {
+            tracing::info!("moved {} entries to the partitions", moved);
+            self.deleted.insert(None);
+            // before the writer task starts, nothing waits for it
+            while self.vacuum().await? {}
+        }
+        Ok(())
+    }
+
+    /// Delete `limit` entries, oldest partitions first, or less if there are
+    /// no more expired ones. A partition or an archive whose entries all
+    /// expired is removed at once, even if it has more.
+    async fn delete(&mut self, expired: &Expired, limit: usize) -> Result<u64> {
+        let limit = limit as u64;
+        let mut deleted = 0;
+        let before = match expired {
+            Expired::Service { before, .. } | Expired::Others { before, .. } => {
+                Some(to_micros(before))
             }
-            std::ops::Bound::Unbounded => (),
+            Expired::Oldest => None,
+        };
+        // the entries of the archives cannot be deleted one by one, an archive
+        // is kept while it has entries of the excepted services
+        if let (Expired::Others { except, .. }, Some(before)) = (expired, before) {
+            for segment in self.partitions.archives() {
+                let Some(archive) = segment.archive else {
+                    continue;
+                };
+                if deleted >= limit || segment.end > before {
+                    break;
+                }
+                if except.iter().any(|service| archive.has_service(service)) {
+                    continue;
+                }
+                deleted += archive.entries();
+                self.partitions.remove_archive(segment.start)?;
+            }
+        }
+        let partitions = self.partitions.all();
+        let count = partitions.len();
+        for (i, partition) in partitions.into_iter().enumerate() {
+            if deleted >= limit || before.is_some_and(|before| partition.start() >= before) {
+                break;
+            }
+            let whole = match expired {
+                Expired::Service { .. } => false,
+                Expired::Others { except, .. } => {
+                    except.is_empty() && before.is_some_and(|before| partition.end <= before)
+                }
+                // the newest partition is never removed
+                Expired::Oldest => i + 1 < count,
+            };
+            if let (true, Some(key)) = (whole, partition.key) {
+                let count: i64 = sqlx::query_scalar("select count(*) from logs")
+                    .fetch_one(&partition.pool)
+                    .await?;
+                self.partition_conns.remove(&key);
+                self.partitions.remove(key)?;
+                deleted += count as u64;
+                continue;
+            }
+
+            // the full-text index is updated by a trigger
+            let mut query = delete_query(expired, limit - deleted);
+            let conn = self.conn(partition.key).await?;
+            let rows = query.build().execute(conn).await?.rows_affected();
+            if rows > 0 {
+                self.deleted.insert(partition.key);
+                deleted += rows;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Replace the entries of a partition up to `max_id` by their archive. The
+    /// entries received since stay in the partition until the next time.
+    async fn archived(&mut self, key: i64, archive: Archive, max_id: i64) -> Result<()> {
+        // removed by the retention meanwhile
+        if !self.partitions.contains(key) {
+            std::fs::remove_file(archive.path())?;
+            return Ok(());
         }
+        let conn = self.conn(Some(key)).await?;
+        let received: bool = sqlx::query_scalar("select exists(select 1 from logs where id > ?)")
+            .bind(max_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if received {
+            sqlx::query("delete from logs where id <= ?")
+                .bind(max_id)
+                .execute(&mut *conn)
+                .await?;
+            self.deleted.insert(Some(key));
+        } else {
+            self.partition_conns.remove(&key);
+        }
+        self.partitions.archived(key, archive, !received)
+    }
+
+    /// One step of the vacuum of the databases with deleted entries since
+    /// the last time. Returns whether some of them need more steps.
+    async fn vacuum(&mut self) -> Result<bool> {
+        for key in std::mem::take(&mut self.deleted) {
+            // removed since
+            if key.is_some_and(|key| !self.partitions.contains(key)) {
+                continue;
+            }
+            if vacuum_step(self.conn(key).await?).await? {
+                self.deleted.insert(key);
+            }
+        }
+        Ok(!self.deleted.is_empty())
+    }
+
+    /// Write the pending entries and merge the WAL into the database files.
+    async fn close(mut self) -> Result<()> {
+        self.flush_until_written().await?;
+        for conn in std::iter::once(&mut self.conn).chain(self.partition_conns.values_mut()) {
+            sqlx::query("pragma wal_checkpoint(truncate)")
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
     }
 }
 
+/// `delete` of up to `limit` entries, oldest first.
+fn delete_query(expired: &Expired, limit: u64) -> QueryBuilder<'_, Sqlite> {
+    let mut query = QueryBuilder::new("delete from logs where id in (select id from logs where 1");
+    match expired {
+        Expired::Service { service, before } => {
+            query
+                .push(" and service = ")
+                .push_bind(service)
+                .push(" and timestamp < ")
+                .push_bind(to_micros(before));
+        }
+        Expired::Others { except, before } => {
+            query.push(" and timestamp < ").push_bind(to_micros(before));
+            if !except.is_empty() {
+                let mut separated = query.push(" and service not in (").separated(", ");
+                for service in except {
+                    separated.push_bind(service);
+                }
+                query.push(")");
+            }
+        }
+        Expired::Oldest => {}
+    }
+    query
+        .push(" order by timestamp limit ")
+        .push_bind(limit as i64)
+        .push(")");
+    query
+}
+
+/// Whether the free pages of the database can be given back to the file system.
+async fn is_incremental(conn: impl SqliteExecutor<'_>) -> Result<bool> {
+    let auto_vacuum: i64 = sqlx::query_scalar("pragma auto_vacuum")
+        .fetch_one(conn)
+        .await?;
+    // 2 is incremental
+    Ok(auto_vacuum == 2)
+}
+
+/// Merge the segments of the full-text index and give the free pages back to
+/// the file system, a bounded number of pages at a time. Returns whether
+/// there is more to do.
+async fn vacuum_step(conn: &mut PoolConnection<Sqlite>) -> Result<bool> {
+    // the full-text index keeps the deleted entries until its segments are
+    // merged; negative to merge them all, at most that many pages at a time,
+    // and the merge changed at least 2 rows if it did something
+    let changes = "select total_changes()";
+    let before: i64 = sqlx::query_scalar(changes).fetch_one(&mut *conn).await?;
+    sqlx::query("insert into logsfts(logsfts, rank) values ('merge', ?)")
+        .bind(-FTS_MERGE_PAGES)
+        .execute(&mut *conn)
+        .await?;
+    let after: i64 = sqlx::query_scalar(changes).fetch_one(&mut *conn).await?;
+    let merging = after - before >= 2;
+
+    // a database created before incremental vacuum was enabled only reuses
+    // its free pages, converting it would rewrite the whole file
+    if !is_incremental(&mut *conn).await? {
+        return Ok(merging);
+    }
+    sqlx::query(&format!("pragma incremental_vacuum({VACUUM_PAGES})"))
+        .execute(&mut *conn)
+        .await?;
+    let free: i64 = sqlx::query_scalar("pragma freelist_count")
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(merging || free > 0)
+}
+
 impl LogDatabase {
-    pub async fn new(url: &str) -> Result<Self> {
-        let mut options = SqliteConnectOptions::from_str(url)?.journal_mode(SqliteJournalMode::Wal);
-        options.log_statements(tracing::log::LevelFilter::Info);
-        let pool = SqlitePool::connect_with(options).await?;
+    pub async fn new(url: &str, options: WriteOptions) -> Result<Self> {
+        let synchronous = match options.durability {
+            Durability::Buffered => SqliteSynchronous::Normal,
+            Durability::Batch | Durability::Full => SqliteSynchronous::Full,
+        };
+        // auto_vacuum only applies to the databases created with it; the
+        // options set it after the journal mode, which would create the file
+        // first, so the journal mode is set once connected
+        let mut connect_options = SqliteConnectOptions::from_str(url)?
+            .synchronous(synchronous)
+            .auto_vacuum(SqliteAutoVacuum::Incremental);
+        connect_options.log_statements(tracing::log::LevelFilter::Info);
+        let pool = SqlitePool::connect_with(connect_options.clone()).await?;
+        sqlx::query(ENABLE_WAL).execute(&pool).await?;
         sqlx::migrate!().run(&pool).await?;
+        if !is_incremental(&pool).await? {
+            tracing::warn!(
+                "the database was created without incremental vacuum, the space of the deleted \
+                entries is reused but not given back to the file system"
+            );
+        }
+        let partitions = Partitions::open(url, pool.clone(), connect_options, options.partitioning);
+        let partitions = Arc::new(partitions.await?);
+
+        let mut writer = Writer {
+            conn: pool.acquire().await?,
+            partitions: partitions.clone(),
+            partition_conns: BTreeMap::new(),
+            deleted: BTreeSet::new(),
+            pending: PendingLogs::default(),
+            flush_interval: options.flush_interval,
+        };
+        if partitions.is_partitioned() {
+            writer.move_to_partitions().await?;
+        }
+        let (sender, commands) = mpsc::channel(MAX_PENDING_ENTRIES);
+        let stopped = Arc::new(Mutex::new(None));
+        let reason = stopped.clone();
+        tokio::spawn(async move {
+            if let Err(err) = writer.run(commands).await {
+                tracing::error!("database writer stopped: {:#}", err);
+                *reason.lock().unwrap() = Some(format!("{err:#}"));
+            }
+        });
+
         Ok(Self {
             pool,
-            entries: Arc::new(Mutex::new(Vec::new())),
+            partitions,
+            writer: sender,
+            stopped,
+            durability: options.durability,
         })
     }
 
     pub async fn last_timestamp(&self) -> Result<Option<NaiveDateTime>> {
-        // for some reasons the type cannot be inferred correctly on 'timestamp'
-        let record =
-            sqlx::query!(r#"select max(timestamp) as 'timestamp: NaiveDateTime' from logs"#)
-                .fetch_one(&self.pool)
+        let mut last = None;
+        // the newest partitions may be empty
+        for partition in self.partitions.all().iter().rev() {
+            let record = sqlx::query!(r#"select max(timestamp) as 'timestamp: i64' from logs"#)
+                .fetch_one(&partition.pool)
                 .await?;
+            if record.timestamp.is_some() {
+                last = record.timestamp;
+                break;
+            }
+        }
+        let archived = self.partitions.archives().pop().and_then(|segment| {
+            let archive = segment.archive?;
+            archive.last_timestamp()
+        });
+        Ok(last.max(archived).map(from_micros).transpose()?)
+    }
+
+    /// Position of the last entry of `source` that was committed to the database.
+    pub async fn source_position(&self, source: &str) -> Result<Option<String>> {
+        let position = sqlx::query_scalar("select position from source_positions where source = ?")
+            .bind(source)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(position)
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.writer
+            .send(command)
+            .await
+            .map_err(|_| self.writer_stopped())
+    }
 
-        Ok(record.timestamp)
+    fn writer_stopped(&self) -> anyhow::Error {
+        match &*self.stopped.lock().unwrap() {
+            Some(reason) => anyhow::format_err!("database writer stopped: {reason}"),
+            None => anyhow::format_err!("database writer stopped"),
+        }
+    }
+
+    /// Send a request to the writer and wait for its answer.
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T>>) -> Command,
+    ) -> Result<T> {
+        let (reply, answer) = oneshot::channel();
+        self.send(command(reply)).await?;
+        answer.await.map_err(|_| self.writer_stopped())?
     }
 
-    async fn insert_logs(&self, entries: &[LogEntry]) -> Result<()> {
-        if entries.is_empty() {
+    /// Store a batch forwarded by another agent, unless a batch with the same
+    /// or a later sequence number from the same spool of the sender was
+    /// already stored. Returns false for such duplicates.
+    pub async fn insert_forwarded(
+        &self,
+        batch: &ForwardedBatch,
+        entries: &[LogEntry],
+    ) -> Result<bool> {
+        self.request(|reply| Command::Forwarded {
+            batch: batch.clone(),
+            entries: entries.to_vec(),
+            reply,
+        })
+        .await
+    }
+
+    /// Queue an entry for the writer, which waits when too many are queued.
+    pub async fn add_log(&self, record: LogRecord) -> Result<()> {
+        // already stored, for example forwarded by another agent
+        if record.stored {
             return Ok(());
         }
+        self.send(Command::Log(record)).await?;
+        if self.durability == Durability::Full {
+            self.sync_logs().await?;
+        }
+        Ok(())
+    }
 
-        assert!(entries.len() < 65535 / 4);
+    /// Write the pending entries now, rather than at the end of the flush interval.
+    pub async fn sync_logs(&self) -> Result<()> {
+        self.request(Command::Sync).await
+    }
 
-        let mut tx = self.pool.begin().await?;
-        let r = QueryBuilder::new("insert into logsfts(service, message) ")
-            .push_values(entries, |mut b, entry| {
-                b.push_bind(&entry.service).push_bind(&entry.message);
-            })
-            .build()
-            .execute(&mut tx)
+    /// Delete up to `limit` expired entries, the writer handles the other
+    /// requests in between. Returns the number of deleted entries.
+    pub async fn delete_expired(&self, expired: Expired, limit: usize) -> Result<u64> {
+        self.request(|reply| Command::Delete {
+            expired,
+            limit,
+            reply,
+        })
+        .await
+    }
+
+    /// Shrink the database file after entries were deleted, in steps between
+    /// which the new entries are still written.
+    pub async fn vacuum(&self) -> Result<()> {
+        while self.request(Command::Vacuum).await? {}
+        Ok(())
+    }
+
+    /// Of all the database files.
+    pub async fn size(&self) -> Result<DatabaseSize> {
+        let mut size = DatabaseSize {
+            used: 0,
+            entries: 0,
+        };
+        for database in self.partitions.databases() {
+            let (used, entries): (i64, i64) = sqlx::query_as(
+                "select (page_count - freelist_count) * page_size, (select count(*) from logs)
+                from pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+            )
+            .fetch_one(&database.pool)
             .await?;
+            size.used += used as u64;
+            size.entries += entries as u64;
+        }
+        Ok(size)
+    }
+
+    /// Write the pending entries and merge the WAL into the database files.
+    pub async fn close(&self) -> Result<()> {
+        self.request(Command::Close).await?;
+        for database in self.partitions.databases() {
+            database.pool.close().await;
+        }
+        Ok(())
+    }
+
+    /// Problems found in the database files and between the entries and their
+    /// full-text index, empty if the database is consistent.
+    pub async fn check(&self) -> Result<Vec<String>> {
+        let mut problems = vec![];
+        for database in self.partitions.databases() {
+            let found = check(&database.pool).await?;
+            if self.partitions.is_partitioned() {
+                let name = self.partitions.name(&database);
+                problems.extend(
+                    found
+                        .into_iter()
+                        .map(|problem| format!("{name}: {problem}")),
+                );
+            } else {
+                problems.extend(found);
+            }
+        }
+        for segment in self.partitions.archives() {
+            let Some(archive) = segment.archive else {
+                continue;
+            };
+            let verified = tokio::task::spawn_blocking(move || archive.verify()).await?;
+            if let Err(err) = verified {
+                problems.push(format!("{err:#}"));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Move the partitions ending before `before` to archives of
+    /// `chunk_entries` entries per chunk. Returns the number of archived
+    /// partitions.
+    pub async fn archive(&self, before: NaiveDateTime, chunk_entries: usize) -> Result<usize> {
+        let before = to_micros(&before);
+        let mut archived = 0;
+        for partition in self.partitions.all() {
+            let Some(key) = partition.key else {
+                break;
+            };
+            if partition.end > before {
+                break;
+            }
+            let max_id: Option<i64> = sqlx::query_scalar("select max(id) from logs")
+                .fetch_one(&partition.pool)
+                .await?;
+            // the entries received from now on are archived next time
+            let max_id = max_id.unwrap_or(0);
 
-        // I can't find a way to fetch the rowid for each inserts, so instead
-        // I'm assuming that the rowid is only increasing on a bulk insert
-        let lastid = r.last_insert_rowid();
-        let numinserts = r.rows_affected();
-        assert!(numinserts == entries.len() as u64);
-        let mut firstid = (lastid + 1).wrapping_sub(numinserts.try_into().unwrap());
-
-        QueryBuilder::new("insert into logs(hostname, timestamp, logsfts_id) ")
-            .push_values(entries, |mut b, entry| {
-                b.push_bind(&entry.hostname)
-                    .push_bind(entry.timestamp)
-                    .push_bind(firstid);
-                firstid += 1;
+            let (sender, mut receiver) = mpsc::channel(EXPORT_BUFFER);
+            let pool = partition.pool.clone();
+            tokio::spawn(async move {
+                let filter = Filter::default();
+                let mut query = filter_query(&filter);
+                query
+                    .push(" and id <= ")
+                    .push_bind(max_id)
+                    .push(" order by timestamp, id");
+                let mut rows = query.build().try_map(from_row).fetch(&pool);
+                while let Some(row) = rows.next().await {
+                    let failed = row.is_err();
+                    let row = row.map(|(_, entry)| entry).map_err(Into::into);
+                    if sender.send(row).await.is_err() || failed {
+                        return;
+                    }
+                }
+            });
+
+            // written next to the archive, then moved in place by the writer
+            let mut temp = self.partitions.archive_path(key).into_os_string();
+            temp.push(".tmp");
+            let existing = self.partitions.archive(key);
+            let archive = tokio::task::spawn_blocking(move || {
+                let entries = std::iter::from_fn(|| receiver.blocking_recv());
+                let temp = std::path::Path::new(&temp);
+                match existing {
+                    // entries received after the partition was archived
+                    Some(existing) => {
+                        Archive::create(temp, merge(existing.iter(), entries), chunk_entries)
+                    }
+                    None => Archive::create(temp, entries, chunk_entries),
+                }
+            })
+            .await??;
+
+            self.request(|reply| Command::Archived {
+                key,
+                archive,
+                max_id,
+                reply,
             })
-            .build()
-            .execute(&mut tx)
             .await?;
-        Ok(tx.commit().await?)
+            archived += 1;
+        }
+        Ok(archived)
     }
 
-    pub async fn add_log(&self, entry: LogEntry) -> Result<()> {
-        let sync = {
-            let mut entries = self.entries.lock().await;
-            entries.push(entry);
-            entries.len() > 1024
-        };
-        if sync {
-            self.sync_logs().await?;
+    /// Only the committed entries are returned, the pending ones are written
+    /// within the flush interval.
+    pub async fn extract(&self, filter: &Filter, page: &Page) -> Result<ExtractPage> {
+        let size = page.size.clamp(1, MAX_PAGE_SIZE);
+        let mut segments = self.segments(filter);
+        if page.order == Order::Desc {
+            segments.reverse();
+        }
+
+        // the segments are in the order of the page, so they are read until
+        // the page is complete
+        let mut rows: Vec<(Cursor, LogEntry)> = vec![];
+        for segment in segments {
+            let passed = page.after.is_some_and(|after| match page.order {
+                Order::Asc => segment.end <= after.timestamp,
+                Order::Desc => segment.start > after.timestamp,
+            });
+            if passed {
+                continue;
+            }
+            // one more to know if there is a next page
+            let limit = size + 1 - rows.len();
+            let mut found = match &segment.partition {
+                Some(partition) => {
+                    let mut query = page_query(filter, page, limit);
+                    query
+                        .build()
+                        .try_map(from_row)
+                        .fetch_all(&partition.pool)
+                        .await?
+                }
+                None => vec![],
+            };
+            if let Some(archive) = segment.archive {
+                let (filter, order) = (filter.clone(), page.order);
+                let after = page.after.map(|after| (after.timestamp, after.id));
+                let archived = tokio::task::spawn_blocking(move || {
+                    archive.search(&filter, order, after, limit)
+                })
+                .await??;
+                found.extend(
+                    archived
+                        .into_iter()
+                        .map(|((timestamp, id), entry)| (Cursor { timestamp, id }, entry)),
+                );
+                if segment.partition.is_some() {
+                    found.sort_by_key(|(cursor, _)| (cursor.timestamp, cursor.id));
+                    if page.order == Order::Desc {
+                        found.reverse();
+                    }
+                    found.truncate(limit);
+                }
+            }
+            rows.extend(found);
+            if rows.len() > size {
+                break;
+            }
+        }
+
+        let next = if rows.len() > size {
+            rows.truncate(size);
+            rows.last().map(|(cursor, _)| cursor.to_string())
+        } else {
+            None
+        };
+        Ok(ExtractPage {
+            entries: rows.into_iter().map(|(_, entry)| entry).collect(),
+            next,
+        })
+    }
+
+    /// Every entry matching `filter`, oldest first. They are read from the
+    /// database as they are received, which stops when the receiver is dropped.
+    pub fn export(&self, filter: Filter) -> mpsc::Receiver<Result<LogEntry>> {
+        let (sender, receiver) = mpsc::channel(EXPORT_BUFFER);
+        let segments = self.segments(&filter);
+        tokio::spawn(async move {
+            for segment in segments {
+                // the entries received after the partition was archived come
+                // after the archived ones, until they are archived too
+                if let Some(archive) = segment.archive {
+                    for i in 0..archive.chunks() {
+                        let (archive, filter) = (archive.clone(), filter.clone());
+                        let entries = tokio::task::spawn_blocking(move || archive.read(i, &filter))
+                            .await
+                            .map_err(Into::into)
+                            .and_then(|entries| entries);
+                        let (entries, failed) = match entries {
+                            Ok(entries) => (entries.into_iter().map(Ok).collect(), false),
+                            Err(err) => (vec![Err(err)], true),
+                        };
+                        for entry in entries {
+                            if sender.send(entry).await.is_err() {
+                                return;
+                            }
+                        }
+                        if failed {
+                            return;
+                        }
+                    }
+                }
+                let Some(partition) = segment.partition else {
+                    continue;
+                };
+                let mut query = filter_query(&filter);
+                query.push(" order by timestamp, id");
+                let mut rows = query.build().try_map(from_row).fetch(&partition.pool);
+                while let Some(row) = rows.next().await {
+                    let failed = row.is_err();
+                    let row = row.map(|(_, entry)| entry).map_err(Into::into);
+                    if sender.send(row).await.is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Counted by SQLite in the partitions, but from the entries of the chunks
+    /// that may match in the archives.
+    pub async fn histogram(
+        &self,
+        filter: &Filter,
+        interval_ms: u64,
+        group_by: Option<GroupBy>,
+    ) -> Result<Histogram> {
+        let mut counts = HistogramCounts::new(interval_ms, group_by);
+        for segment in self.segments(filter) {
+            if let Some(partition) = &segment.partition {
+                let mut query = histogram_query(filter, counts.interval(), group_by);
+                let mut rows = query.build().fetch(&partition.pool);
+                while let Some(row) = rows.next().await {
+                    let row = row?;
+                    let group = match group_by {
+                        Some(GroupBy::Level) => {
+                            let priority: Option<i64> = row.try_get(1)?;
+                            let level = priority
+                                .and_then(|priority| u8::try_from(priority).ok())
+                                .and_then(Level::from_priority);
+                            level_group(level).to_string()
+                        }
+                        _ => row.try_get(1)?,
+                    };
+                    let count: i64 = row.try_get(2)?;
+                    counts.add(row.try_get(0)?, &group, count as u64)?;
+                }
+            }
+            if let Some(archive) = segment.archive {
+                for i in 0..archive.chunks() {
+                    let (archive, filter) = (archive.clone(), filter.clone());
+                    let entries =
+                        tokio::task::spawn_blocking(move || archive.read(i, &filter)).await??;
+                    for entry in &entries {
+                        counts.add_entry(entry)?;
+                    }
+                }
+            }
+        }
+        Ok(counts.finish())
+    }
+
+    /// The partitions and archives that may hold entries in the time range
+    /// of `filter`, oldest first.
+    fn segments(&self, filter: &Filter) -> Vec<Segment> {
+        let from = match &filter.timerange.0 {
+            Bound::Included(t) | Bound::Excluded(t) => Some(to_micros(t)),
+            Bound::Unbounded => None,
+        };
+        let to = match &filter.timerange.1 {
+            Bound::Included(t) | Bound::Excluded(t) => Some(to_micros_ceil(t)),
+            Bound::Unbounded => None,
+        };
+        self.partitions.segments(from, to)
+    }
+}
+
+/// Problems of a single database file.
+async fn check(pool: &SqlitePool) -> Result<Vec<String>> {
+    let mut problems = vec![];
+
+    let integrity: Vec<String> = sqlx::query_scalar("pragma integrity_check")
+        .fetch_all(pool)
+        .await?;
+    problems.extend(integrity.into_iter().filter(|row| row != "ok"));
+
+    let entries: i64 = sqlx::query_scalar("select count(*) from logs")
+        .fetch_one(pool)
+        .await?;
+    let indexed: i64 = sqlx::query_scalar("select count(*) from logsfts_docsize")
+        .fetch_one(pool)
+        .await?;
+    if entries != indexed {
+        problems.push(format!(
+            "{entries} entries but {indexed} in the full-text index"
+        ));
+    }
+
+    // rank 1 also compares the index with the content of the entries
+    let fts = sqlx::query("insert into logsfts(logsfts, rank) values ('integrity-check', 1)")
+        .execute(pool)
+        .await;
+    if let Err(err) = fts {
+        problems.push(format!("full-text index: {err}"));
+    }
+
+    Ok(problems)
+}
+
+/// Up to `limit` entries matching `filter` after the cursor of `page`, in its order.
+fn page_query<'a>(filter: &'a Filter, page: &Page, limit: usize) -> QueryBuilder<'a, Sqlite> {
+    let mut query = filter_query(filter);
+    let (comparison, direction) = match page.order {
+        Order::Asc => (">", "asc"),
+        Order::Desc => ("<", "desc"),
+    };
+    if let Some(after) = page.after {
+        query
+            .push(format!(" and (timestamp, id) {comparison} ("))
+            .push_bind(after.timestamp)
+            .push(", ")
+            .push_bind(after.id)
+            .push(")");
+    }
+    query
+        .push(format!(
+            " order by timestamp {direction}, id {direction} limit "
+        ))
+        .push_bind(limit as i64);
+    query
+}
+
+/// Number of entries matching `filter` by start of the interval of `interval`
+/// microseconds, then by service, host or priority with `group_by`.
+fn histogram_query(
+    filter: &Filter,
+    interval: i64,
+    group_by: Option<GroupBy>,
+) -> QueryBuilder<'_, Sqlite> {
+    let group = match group_by {
+        None => "''",
+        Some(GroupBy::Service) => "service",
+        Some(GroupBy::Host) => "hostname",
+        Some(GroupBy::Level) => "priority",
+    };
+    // the remainder of `%` has the sign of the timestamp, the intervals start
+    // before the timestamps before 1970 too
+    let start = format!("timestamp - ((timestamp % {interval}) + {interval}) % {interval}");
+    let mut query = select_query(&format!("{start}, {group}, count(*)"), filter);
+    query.push(" group by 1, 2");
+    query
+}
+
+/// Entries read ahead of an export.
+const EXPORT_BUFFER: usize = 1024;
+
+/// `select` of the entries matching `filter`, to be completed with an order.
+fn filter_query(filter: &Filter) -> QueryBuilder<'_, Sqlite> {
+    select_query(
+        "message, hostname, service, timestamp, priority, fields, id",
+        filter,
+    )
+}
+
+/// `select` of `columns` from the entries matching `filter`.
+fn select_query<'a>(columns: &str, filter: &'a Filter) -> QueryBuilder<'a, Sqlite> {
+    let message = filter
+        .message_keywords
+        .as_ref()
+        .and_then(|a| to_sqlite_phrase(a))
+        .map(|p| format!("(message: {p})"));
+    let service = filter
+        .services
+        .as_ref()
+        .and_then(|a| to_sqlite_phrase(a))
+        .map(|p| format!("(service: {p})"));
+
+    let mut matches = vec![];
+    matches.extend(message);
+    matches.extend(service);
+    let matches = matches.join(" AND ");
+
+    let mut query = QueryBuilder::new(format!("select {columns} from logs where 1"));
+    if !matches.is_empty() {
+        query
+            .push(" and id in (select rowid from logsfts where logsfts = ")
+            .push_bind(matches)
+            .push(")");
+    }
+    match filter.hosts.as_deref() {
+        None => {}
+        Some([]) => {
+            query.push(" and 0");
+        }
+        // a term for each host, so that each one is a range of the index
+        Some(hosts) => {
+            let mut separated = query.push(" and (").separated(" or ");
+            for host in hosts {
+                separated
+                    .push("hostname glob ")
+                    .push_bind_unseparated(glob_prefix(host));
+            }
+            query.push(")");
         }
-        Ok(())
     }
-
-    async fn sync_logs(&self) -> Result<()> {
-        let mut entries = self.entries.lock().await;
-        self.insert_logs(&entries).await?;
-        entries.clear();
-        Ok(())
+    filter.timerange.push_to_query("timestamp", &mut query);
+    filter.levels.push_to_query("priority", &mut query);
+    // matched by key rather than by JSON path, in which some names such as
+    // those with '"' cannot be written
+    for (name, value) in filter.fields.iter().flatten() {
+        query
+            .push(" and exists (select 1 from json_each(fields) where key = ")
+            .push_bind(name)
+            .push(" and value = ")
+            .push_bind(value)
+            .push(")");
     }
+    query
+}
 
-    pub async fn extract(&self, filter: &Filter) -> Result<Vec<LogEntry>> {
-        self.sync_logs().await?;
-
-        let message = filter
-            .message_keywords
-            .as_ref()
-            .and_then(|a| to_sqlite_phrase(a))
-            .map(|p| format!("(message: {p})"));
-        let service = filter
-            .services
-            .as_ref()
-            .and_then(|a| to_sqlite_phrase(a))
-            .map(|p| format!("(service: {p})"));
-
-        let mut matches = vec![];
-        matches.extend(message);
-        matches.extend(service);
-        let matches = matches.join(" AND ");
-
-        let mut query = QueryBuilder::new(
-            r#"
-            select message as "message!: String", hostname as 'hostname!', service as 'service!: String', timestamp as 'timestamp!'
-            from logs
-            join logsfts fts on fts.rowid == logs.logsfts_id
-            where 1"#,
-        );
-        if !matches.is_empty() {
-            query.push(" and logsfts = ").push_bind(matches);
+fn from_row(a: SqliteRow) -> Result<(Cursor, LogEntry), sqlx::Error> {
+    let fields = match a.get::<Option<&str>, _>(5) {
+        Some(fields) => {
+            serde_json::from_str(fields).map_err(|e| sqlx::Error::Decode(Box::new(e)))?
         }
-        filter.timerange.push_to_query("timestamp", &mut query);
-        query.push(
-            r#" order by timestamp desc
-            limit 100;"#,
-        );
-
-        let mut entries: Vec<LogEntry> = query
-            .build()
-            .map(|a: SqliteRow| LogEntry {
-                message: a.get(0),
-                hostname: a.get(1),
-                service: a.get(2),
-                timestamp: a.get(3),
-            })
-            .fetch_all(&self.pool)
-            .await?;
-
-        entries.reverse();
-        Ok(entries)
-    }
+        None => Default::default(),
+    };
+    let cursor = Cursor {
+        timestamp: a.get(3),
+        id: a.get(6),
+    };
+    let entry = LogEntry {
+        message: a.get(0),
+        hostname: a.get(1),
+        service: a.get(2),
+        timestamp: from_micros(a.get(3))?,
+        level: a.get::<Option<u8>, _>(4).and_then(Level::from_priority),
+        fields,
+    };
+    Ok((cursor, entry))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{ops::Bound, path::PathBuf, str::FromStr, time::Duration};
+
     use anyhow::Result;
     use chrono::NaiveDateTime;
-    use minink_common::{Filter, LogEntry};
+    use minink_common::{Filter, Level, LogEntry, Order};
+
+    use crate::{
+        database::convert_to_fts_match,
+        forward::ForwardedBatch,
+        logdispatcher::{Checkpoint, LogRecord},
+        partition::Partitioning,
+    };
 
-    use crate::database::convert_to_fts_match;
+    use sqlx::{
+        sqlite::{SqliteConnectOptions, SqliteRow},
+        QueryBuilder, Row, SqlitePool,
+    };
+
+    use super::{
+        filter_query, glob_prefix, Cursor, Durability, Expired, LogDatabase, Page, PushToQuery,
+        WriteOptions, ROWS_PER_STATEMENT,
+    };
+
+    async fn insert_logs(
+        db: &LogDatabase,
+        entries: &[LogEntry],
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<()> {
+        for entry in entries {
+            db.add_log(LogRecord {
+                entry: entry.clone(),
+                checkpoint: checkpoint.clone(),
+                stored: false,
+            })
+            .await?;
+        }
+        db.sync_logs().await
+    }
 
-    use super::LogDatabase;
+    /// The first page, oldest first.
+    async fn extract(db: &LogDatabase, filter: &Filter) -> Result<Vec<LogEntry>> {
+        let page = Page {
+            order: Order::Asc,
+            ..Default::default()
+        };
+        Ok(db.extract(filter, &page).await?.entries)
+    }
 
     async fn prep_db(entries: &[LogEntry]) -> Result<LogDatabase> {
-        let db = LogDatabase::new(":memory:").await?;
-        db.insert_logs(entries).await?;
+        let db = LogDatabase::new(":memory:", WriteOptions::default()).await?;
+        insert_logs(&db, entries, None).await?;
         Ok(db)
     }
 
@@ -232,18 +1514,28 @@ mod tests {
                 hostname: "localhost".to_string(),
                 service: "nginx".to_string(),
                 timestamp: NaiveDateTime::from_timestamp_micros(0).unwrap(),
+                level: Some(Level::Info),
+                fields: [("_PID".to_string(), "42".to_string())].into(),
             },
             LogEntry {
                 message: "TOTO-200".to_string(),
                 hostname: "localhost".to_string(),
                 service: "NGINX".to_string(),
                 timestamp: NaiveDateTime::from_timestamp_micros(1).unwrap(),
+                level: Some(Level::Err),
+                fields: [
+                    ("_PID".to_string(), "42".to_string()),
+                    ("REQUEST_ID".to_string(), "abc-123".to_string()),
+                ]
+                .into(),
             },
             LogEntry {
                 message: "titi 20020".to_string(),
                 hostname: "localhost".to_string(),
                 service: "kernel".to_string(),
                 timestamp: NaiveDateTime::from_timestamp_micros(2).unwrap(),
+                level: None,
+                fields: Default::default(),
             },
         ]
     }
@@ -258,7 +1550,7 @@ mod tests {
     async fn test_extract_all() -> Result<()> {
         let db = prep_db(&default_entries()).await?;
         let filter = Filter::default();
-        let found = db.extract(&filter).await?;
+        let found = extract(&db, &filter).await?;
         assert_eq!(found.len(), 3);
         let found2 = default_entries()
             .into_iter()
@@ -275,7 +1567,7 @@ mod tests {
             message_keywords: Some(vec!["200".to_string()]),
             ..Filter::default()
         };
-        let found = db.extract(&filter).await?;
+        let found = extract(&db, &filter).await?;
         assert_eq!(found.len(), 2);
         let found2 = default_entries()
             .into_iter()
@@ -292,7 +1584,7 @@ mod tests {
             services: Some(vec!["n".to_string()]),
             ..Filter::default()
         };
-        let found = db.extract(&filter).await?;
+        let found = extract(&db, &filter).await?;
         assert_eq!(found.len(), 2);
         let found2 = default_entries()
             .into_iter()
@@ -310,7 +1602,7 @@ mod tests {
             message_keywords: Some(vec!["200".to_string()]),
             ..Default::default()
         };
-        let found = db.extract(&filter).await?;
+        let found = extract(&db, &filter).await?;
         assert_eq!(found.len(), 1);
         let found2 = default_entries()
             .into_iter()
@@ -320,6 +1612,782 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_extract_filter_by_level() -> Result<()> {
+        let db = prep_db(&default_entries()).await?;
+        for levels in [
+            (Bound::Unbounded, Bound::Included(Level::Err)),
+            (Bound::Unbounded, Bound::Excluded(Level::Err)),
+            (Bound::Included(Level::Err), Bound::Included(Level::Debug)),
+            (Bound::Excluded(Level::Err), Bound::Unbounded),
+        ] {
+            let filter = Filter {
+                levels,
+                ..Filter::default()
+            };
+            let found = extract(&db, &filter).await?;
+            let found2 = default_entries()
+                .into_iter()
+                .filter(|e| filter.accept(e))
+                .collect::<Vec<_>>();
+            assert_eq!(found, found2);
+        }
+
+        let filter = Filter {
+            levels: (Bound::Unbounded, Bound::Included(Level::Err)),
+            ..Filter::default()
+        };
+        let found = extract(&db, &filter).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].level, Some(Level::Err));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_filter_by_fields() -> Result<()> {
+        let db = prep_db(&default_entries()).await?;
+        for (fields, count) in [
+            (vec![("_PID", "42")], 2),
+            (vec![("_PID", "42"), ("REQUEST_ID", "abc-123")], 1),
+            (vec![("REQUEST_ID", "abc")], 0),
+            (vec![("\"", "42")], 0),
+        ] {
+            let filter = Filter {
+                fields: Some(
+                    fields
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Filter::default()
+            };
+            let found = extract(&db, &filter).await?;
+            assert_eq!(found.len(), count);
+            let found2 = default_entries()
+                .into_iter()
+                .filter(|e| filter.accept(e))
+                .collect::<Vec<_>>();
+            assert_eq!(found, found2);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_source_positions() -> Result<()> {
+        let db = LogDatabase::new(":memory:", WriteOptions::default()).await?;
+        assert_eq!(db.source_position("journald").await?, None);
+
+        let entries = default_entries();
+        let checkpoint = |position: &str| Checkpoint {
+            source: "journald".to_string(),
+            position: position.to_string(),
+        };
+        insert_logs(&db, &entries[..1], Some(checkpoint("s=1"))).await?;
+        assert_eq!(db.source_position("journald").await?.unwrap(), "s=1");
+
+        insert_logs(&db, &entries[1..], Some(checkpoint("s=2"))).await?;
+        assert_eq!(db.source_position("journald").await?.unwrap(), "s=2");
+        assert_eq!(db.source_position("other").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_writes_pending() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("minink-close-{}.db", std::process::id()));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        let db = LogDatabase::new(&url, WriteOptions::default()).await?;
+        for entry in default_entries() {
+            db.add_log(LogRecord {
+                entry,
+                checkpoint: None,
+                stored: false,
+            })
+            .await?;
+        }
+        db.close().await?;
+
+        let db = LogDatabase::new(&url, WriteOptions::default()).await?;
+        assert_eq!(extract(&db, &Filter::default()).await?.len(), 3);
+        db.close().await?;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_full_durability() -> Result<()> {
+        for (durability, stored) in [(Durability::Batch, 0), (Durability::Full, 1)] {
+            let options = WriteOptions {
+                durability,
+                ..Default::default()
+            };
+            let db = LogDatabase::new(":memory:", options).await?;
+            let entry = default_entries().remove(0);
+            db.add_log(LogRecord {
+                entry,
+                checkpoint: None,
+                stored: false,
+            })
+            .await?;
+            let count: i64 = sqlx::query_scalar("select count(*) from logs")
+                .fetch_one(&db.pool)
+                .await?;
+            assert_eq!(count, stored);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insert_forwarded() -> Result<()> {
+        let db = LogDatabase::new(":memory:", WriteOptions::default()).await?;
+        let entries = default_entries();
+        let batch = |sender: &str, epoch, seq| ForwardedBatch {
+            sender: sender.to_string(),
+            epoch,
+            seq,
+        };
+        assert!(
+            db.insert_forwarded(&batch("host1", 7, 0), &entries[..1])
+                .await?
+        );
+        assert!(
+            db.insert_forwarded(&batch("host1", 7, 1), &entries[1..])
+                .await?
+        );
+        // sent again after a lost acknowledgement
+        assert!(
+            !db.insert_forwarded(&batch("host1", 7, 1), &entries[1..])
+                .await?
+        );
+        assert!(
+            db.insert_forwarded(&batch("host2", 7, 0), &entries[..1])
+                .await?
+        );
+
+        let found = extract(&db, &Filter::default()).await?;
+        assert_eq!(found.len(), entries.len() + 1);
+        assert_eq!(db.source_position("forward:host1").await?.unwrap(), "7:1");
+
+        // the numbering starts again with a new spool
+        assert!(
+            db.insert_forwarded(&batch("host1", 8, 0), &entries[..1])
+                .await?
+        );
+        assert!(
+            !db.insert_forwarded(&batch("host1", 8, 0), &entries[..1])
+                .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_retried() -> Result<()> {
+        let db = LogDatabase::new(":memory:", WriteOptions::default()).await?;
+        sqlx::query(
+            "create trigger fail before insert on logs begin select raise(abort, 'disk full'); end",
+        )
+        .execute(&db.pool)
+        .await?;
+
+        // a failed request does not stop the writer
+        let batch = ForwardedBatch {
+            sender: "host1".to_string(),
+            epoch: 1,
+            seq: 0,
+        };
+        let entries = default_entries();
+        assert!(db.insert_forwarded(&batch, &entries[..1]).await.is_err());
+
+        let checkpoint = Checkpoint {
+            source: "journald".to_string(),
+            position: "s=1".to_string(),
+        };
+        let writer = db.clone();
+        let entries = default_entries()[1..].to_vec();
+        let inserted =
+            tokio::spawn(async move { insert_logs(&writer, &entries, Some(checkpoint)).await });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!inserted.is_finished());
+        assert_eq!(db.source_position("journald").await?, None);
+
+        sqlx::query("drop trigger fail").execute(&db.pool).await?;
+        inserted.await??;
+        assert!(db.insert_forwarded(&batch, &default_entries()[..1]).await?);
+        assert_eq!(extract(&db, &Filter::default()).await?, default_entries());
+        assert_eq!(db.source_position("journald").await?.unwrap(), "s=1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_large_batch() -> Result<()> {
+        // more than the bound parameters of a single statement
+        let count = 20_000 + ROWS_PER_STATEMENT / 2;
+        let entries = (0..count)
+            .map(|i| LogEntry {
+                message: format!("line {i}"),
+                ..default_entries().remove(i % 3)
+            })
+            .collect::<Vec<_>>();
+        let db = prep_db(&entries).await?;
+        let total: i64 = sqlx::query_scalar("select count(*) from logs")
+            .fetch_one(&db.pool)
+            .await?;
+        assert_eq!(total as usize, count);
+
+        let filter = Filter {
+            message_keywords: Some(vec!["19999".to_string()]),
+            ..Default::default()
+        };
+        let found = extract(&db, &filter).await?;
+        assert_eq!(found, [entries[19999].clone()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_filter_by_hosts() -> Result<()> {
+        let entries = ["web-1", "web-2", "db-1", "we*b"]
+            .into_iter()
+            .map(|hostname| LogEntry {
+                hostname: hostname.to_string(),
+                ..default_entries().remove(0)
+            })
+            .collect::<Vec<_>>();
+        let db = prep_db(&entries).await?;
+        for (hosts, count) in [
+            (vec!["web-"], 2),
+            (vec!["web-1", "db"], 2),
+            (vec!["we*"], 1),
+            (vec!["Web"], 0),
+            (vec![], 0),
+        ] {
+            let filter = Filter {
+                hosts: Some(hosts.into_iter().map(str::to_string).collect()),
+                ..Default::default()
+            };
+            let found = extract(&db, &filter).await?;
+            assert_eq!(found.len(), count, "{:?}", filter.hosts);
+            let accepted = entries
+                .iter()
+                .filter(|e| filter.accept(e))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(found, accepted);
+        }
+
+        let filter = Filter {
+            hosts: Some(vec!["web-".to_string(), "db".to_string()]),
+            ..Default::default()
+        };
+        let mut query = filter_query(&filter);
+        query.push(" order by timestamp");
+        let plan: Vec<String> = sqlx::QueryBuilder::new("explain query plan ")
+            .push(query.sql())
+            .build()
+            .bind(glob_prefix("web-"))
+            .bind(glob_prefix("db"))
+            .try_map(|row: SqliteRow| row.try_get(3))
+            .fetch_all(&db.pool)
+            .await?;
+        assert!(
+            plan.iter()
+                .any(|p| p.contains("idx_logs_hostname (hostname>?")),
+            "{plan:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_timerange() -> Result<()> {
+        let entries = default_entries();
+        let db = prep_db(&entries).await?;
+        let micros = |us| NaiveDateTime::from_timestamp_micros(us).unwrap();
+        let half = micros(0) + chrono::Duration::nanoseconds(500);
+        for timerange in [
+            (Bound::Excluded(micros(0)), Bound::Included(micros(1))),
+            (Bound::Included(half), Bound::Excluded(micros(2))),
+            (
+                Bound::Excluded(half),
+                Bound::Excluded(micros(1) + (half - micros(0))),
+            ),
+        ] {
+            let filter = Filter {
+                timerange,
+                ..Default::default()
+            };
+            let expected = entries
+                .iter()
+                .filter(|e| filter.accept(e))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(expected, [entries[1].clone()]);
+            assert_eq!(extract(&db, &filter).await?, expected);
+        }
+
+        let mut query = QueryBuilder::new("explain query plan select id from logs where 1");
+        (Bound::Included(micros(1)), Bound::Unbounded).push_to_query("timestamp", &mut query);
+        let plan: Vec<String> = query
+            .build()
+            .try_map(|row: SqliteRow| row.try_get(3))
+            .fetch_all(&db.pool)
+            .await?;
+        assert!(
+            plan.iter().any(|p| p.contains("idx_logs_timestamp")),
+            "{plan:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pages() -> Result<()> {
+        // several entries with the same timestamp
+        let entries = (0..7)
+            .map(|i| LogEntry {
+                message: format!("line {i}"),
+                timestamp: NaiveDateTime::from_timestamp_micros(i / 3).unwrap(),
+                ..default_entries().remove(0)
+            })
+            .collect::<Vec<_>>();
+        let db = prep_db(&entries).await?;
+
+        for order in [Order::Asc, Order::Desc] {
+            let mut page = Page {
+                size: 3,
+                order,
+                after: None,
+            };
+            let mut found = vec![];
+            loop {
+                let result = db.extract(&Filter::default(), &page).await?;
+                assert!(result.entries.len() <= 3);
+                found.extend(result.entries);
+                match result.next {
+                    Some(next) => page.after = Some(next.parse()?),
+                    None => break,
+                }
+            }
+            if order == Order::Desc {
+                found.reverse();
+            }
+            assert_eq!(found, entries);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cursor() {
+        let cursor = Cursor {
+            timestamp: -1,
+            id: 42,
+        };
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        assert!("12".parse::<Cursor>().is_err());
+        assert!("é".repeat(16).parse::<Cursor>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export() -> Result<()> {
+        let entries = default_entries();
+        let db = prep_db(&entries).await?;
+        let filter = Filter {
+            services: Some(vec!["nginx".to_string()]),
+            ..Default::default()
+        };
+        let mut exported = db.export(filter);
+        let mut found = vec![];
+        while let Some(entry) = exported.recv().await {
+            found.push(entry?);
+        }
+        assert_eq!(found, entries[..2]);
+        Ok(())
+    }
+
+    /// An empty directory for the files of a database.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minink-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn partitioned(partitioning: Partitioning) -> WriteOptions {
+        WriteOptions {
+            partitioning,
+            ..Default::default()
+        }
+    }
+
+    /// An entry at `hour` hours after 2023-04-25.
+    fn entry_at(hour: i64) -> LogEntry {
+        LogEntry {
+            message: format!("hour {hour}"),
+            timestamp: NaiveDateTime::from_timestamp_opt(1_682_380_800 + hour * 3600, 0).unwrap(),
+            ..default_entries().remove(0)
+        }
+    }
+
+    /// Every page of two entries.
+    async fn pages(db: &LogDatabase, filter: &Filter, order: Order) -> Result<Vec<LogEntry>> {
+        let mut page = Page {
+            size: 2,
+            order,
+            after: None,
+        };
+        let mut found = vec![];
+        loop {
+            let result = db.extract(filter, &page).await?;
+            found.extend(result.entries);
+            match result.next {
+                Some(next) => page.after = Some(next.parse()?),
+                None => return Ok(found),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partitions() -> Result<()> {
+        let dir = temp_dir("partitions");
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        let db = LogDatabase::new(&url, partitioned(Partitioning::Day)).await?;
+        // two entries at the same time, and entries older than the newest one
+        let hours = [50, 1, 26, 2, 2, 49, 25];
+        let entries = hours.iter().map(|&hour| entry_at(hour)).collect::<Vec<_>>();
+        insert_logs(&db, &entries, None).await?;
+        for day in ["2023-04-25", "2023-04-26", "2023-04-27"] {
+            assert!(dir.join(format!("logs.{day}.db")).exists());
+        }
+
+        // pages across the partitions, in both orders
+        for order in [Order::Asc, Order::Desc] {
+            let found = pages(&db, &Filter::default(), order).await?;
+            let mut expected = entries.clone();
+            expected.sort_by_key(|entry| entry.timestamp);
+            if order == Order::Desc {
+                expected.reverse();
+            }
+            let found = found.iter().map(|e| e.timestamp).collect::<Vec<_>>();
+            let expected = expected.iter().map(|e| e.timestamp).collect::<Vec<_>>();
+            assert_eq!(found, expected);
+        }
+
+        let filter = Filter {
+            timerange: (
+                Bound::Included(entry_at(25).timestamp),
+                Bound::Excluded(entry_at(49).timestamp),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(db.segments(&filter).len(), 2);
+        assert_eq!(extract(&db, &filter).await?, [entry_at(25), entry_at(26)]);
+
+        let mut exported = db.export(Filter::default());
+        let mut count = 0;
+        while let Some(entry) = exported.recv().await {
+            entry?;
+            count += 1;
+        }
+        assert_eq!(count, entries.len());
+        assert_eq!(db.last_timestamp().await?, Some(entry_at(50).timestamp));
+        assert_eq!(db.size().await?.entries, entries.len() as u64);
+        assert!(db.check().await?.is_empty());
+        db.close().await?;
+
+        assert!(LogDatabase::new(&url, partitioned(Partitioning::Hour))
+            .await
+            .is_err());
+        assert!(LogDatabase::new(&url, WriteOptions::default())
+            .await
+            .is_err());
+        let db = LogDatabase::new(&url, partitioned(Partitioning::Day)).await?;
+        assert_eq!(extract(&db, &Filter::default()).await?.len(), entries.len());
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_move_to_partitions() -> Result<()> {
+        let dir = temp_dir("move-to-partitions");
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        let db = LogDatabase::new(&url, WriteOptions::default()).await?;
+        let entries = [1, 30, 2].map(entry_at);
+        let checkpoint = Checkpoint {
+            source: "journald".to_string(),
+            position: "s=1".to_string(),
+        };
+        insert_logs(&db, &entries, Some(checkpoint)).await?;
+        db.close().await?;
+
+        let db = LogDatabase::new(&url, partitioned(Partitioning::Hour)).await?;
+        let remaining: i64 = sqlx::query_scalar("select count(*) from logs")
+            .fetch_one(&db.pool)
+            .await?;
+        assert_eq!(remaining, 0);
+        assert_eq!(db.partitions.all().len(), 3);
+        assert_eq!(
+            extract(&db, &Filter::default()).await?,
+            [entry_at(1), entry_at(2), entry_at(30)]
+        );
+        assert_eq!(db.source_position("journald").await?.unwrap(), "s=1");
+        assert!(db.check().await?.is_empty());
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_archive() -> Result<()> {
+        let dir = temp_dir("archive");
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        let db = LogDatabase::new(&url, partitioned(Partitioning::Day)).await?;
+        let entries = [1, 2, 2, 25, 26, 49, 50].map(entry_at);
+        insert_logs(&db, &entries, None).await?;
+
+        // the first two days
+        let before = entry_at(48).timestamp;
+        assert_eq!(db.archive(before, 2).await?, 2);
+        assert_eq!(db.archive(before, 2).await?, 0);
+        assert!(!dir.join("logs.2023-04-25.db").exists());
+        assert!(dir.join("logs.2023-04-25.archive").exists());
+        assert!(dir.join("logs.2023-04-27.db").exists());
+
+        let mut reversed = entries.to_vec();
+        reversed.reverse();
+        assert_eq!(pages(&db, &Filter::default(), Order::Asc).await?, entries);
+        assert_eq!(pages(&db, &Filter::default(), Order::Desc).await?, reversed);
+        // filtered the same way as in the partitions
+        let filter = Filter {
+            message_keywords: Some(vec!["2".to_string()]),
+            timerange: (Bound::Unbounded, Bound::Excluded(entry_at(26).timestamp)),
+            ..Default::default()
+        };
+        assert_eq!(
+            extract(&db, &filter).await?,
+            [entry_at(2), entry_at(2), entry_at(25)]
+        );
+        assert_eq!(db.last_timestamp().await?, Some(entry_at(50).timestamp));
+        assert!(db.check().await?.is_empty());
+
+        // an entry received late is merged with the archived ones
+        insert_logs(&db, &[entry_at(3)], None).await?;
+        assert!(dir.join("logs.2023-04-25.db").exists());
+        let mut with_late = entries.to_vec();
+        with_late.insert(3, entry_at(3));
+        assert_eq!(pages(&db, &Filter::default(), Order::Asc).await?, with_late);
+        // counted in the archives as in the partitions
+        let days = db
+            .histogram(&Filter::default(), 24 * 3600 * 1000, None)
+            .await?;
+        let counts: Vec<_> = days
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.start, bucket.count))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (entry_at(0).timestamp, 4),
+                (entry_at(24).timestamp, 2),
+                (entry_at(48).timestamp, 2)
+            ]
+        );
+        assert_eq!(db.archive(before, 2).await?, 1);
+        assert!(!dir.join("logs.2023-04-25.db").exists());
+        let mut exported = db.export(Filter::default());
+        let mut found = vec![];
+        while let Some(entry) = exported.recv().await {
+            found.push(entry?);
+        }
+        assert_eq!(found, with_late);
+        db.close().await?;
+
+        let db = LogDatabase::new(&url, partitioned(Partitioning::Day)).await?;
+        assert_eq!(pages(&db, &Filter::default(), Order::Desc).await?.len(), 8);
+        // the archives are removed whole
+        let expired = Expired::Others {
+            except: vec![],
+            before,
+        };
+        assert_eq!(db.delete_expired(expired, 100).await?, 6);
+        assert!(!dir.join("logs.2023-04-25.archive").exists());
+        assert_eq!(extract(&db, &Filter::default()).await?.len(), 2);
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    /// `count` entries with long messages, then all of them deleted.
+    async fn insert_and_delete(db: &LogDatabase, count: i64) -> Result<()> {
+        let entries: Vec<LogEntry> = (0..count)
+            .map(|hour| LogEntry {
+                message: format!("hour {hour} {}", "x".repeat(2000)),
+                ..entry_at(hour)
+            })
+            .collect();
+        insert_logs(db, &entries, None).await?;
+        db.delete_expired(Expired::Oldest, count as usize).await?;
+        Ok(())
+    }
+
+    async fn pragma(db: &LogDatabase, name: &str) -> Result<i64> {
+        Ok(sqlx::query_scalar(&format!("pragma {name}"))
+            .fetch_one(&db.pool)
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn test_vacuum() -> Result<()> {
+        let dir = temp_dir("vacuum");
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        let db = LogDatabase::new(&url, WriteOptions::default()).await?;
+        assert_eq!(pragma(&db, "auto_vacuum").await?, 2);
+        let journal_mode: String = sqlx::query_scalar("pragma journal_mode")
+            .fetch_one(&db.pool)
+            .await?;
+        assert_eq!(journal_mode, "wal");
+        insert_and_delete(&db, 500).await?;
+        assert!(pragma(&db, "freelist_count").await? > 0);
+
+        db.vacuum().await?;
+        assert_eq!(pragma(&db, "freelist_count").await?, 0);
+        assert!(db.check().await?.is_empty());
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vacuum_without_incremental() -> Result<()> {
+        let dir = temp_dir("vacuum-without-incremental");
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        // created by an agent that did not enable incremental vacuum
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::from_str(&url)?).await?;
+        sqlx::migrate!().run(&pool).await?;
+        pool.close().await;
+
+        let db = LogDatabase::new(&url, WriteOptions::default()).await?;
+        insert_and_delete(&db, 100).await?;
+        let free = pragma(&db, "freelist_count").await?;
+        assert!(free > 0);
+
+        // not rewritten, the free pages are kept for the next entries
+        db.vacuum().await?;
+        assert_eq!(pragma(&db, "auto_vacuum").await?, 0);
+        assert!(pragma(&db, "freelist_count").await? >= free);
+        assert!(db.check().await?.is_empty());
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check() -> Result<()> {
+        let db = prep_db(&default_entries()).await?;
+        assert!(db.check().await?.is_empty());
+
+        // index the first entry as if it had another message
+        sqlx::query(
+            "insert into logsfts(logsfts, rowid, service, message)
+            select 'delete', id, service, message from logs where id = 1",
+        )
+        .execute(&db.pool)
+        .await?;
+        sqlx::query("insert into logsfts(rowid, service, message) values (1, 'nginx', 'other')")
+            .execute(&db.pool)
+            .await?;
+        assert_eq!(db.check().await?.len(), 1);
+        Ok(())
+    }
+
+    /// The last migration of an agent that stored the messages only in the
+    /// full-text index, with the timestamps as text.
+    const OLD_SCHEMA_VERSION: i64 = 20230418190245;
+
+    #[tokio::test]
+    async fn test_migrate_old_schema() -> Result<()> {
+        let dir = temp_dir("migrate-old-schema");
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::from_str(&url)?).await?;
+        let mut migrator = sqlx::migrate!();
+        migrator.migrations = migrator
+            .migrations
+            .iter()
+            .filter(|migration| migration.version <= OLD_SCHEMA_VERSION)
+            .cloned()
+            .collect();
+        migrator.run(&pool).await?;
+
+        // as sqlx stored them, without fraction when it was 0
+        let rows = [
+            ("2023-04-20 10:00:00", "nginx", "started"),
+            ("2023-04-20 10:00:00.5", "nginx", "listening"),
+            ("2023-04-20 10:00:01.123456", "kernel", "oom killer"),
+            ("2023-04-20 10:00:02.000001", "cron", "job done"),
+        ];
+        // the rowids of the index differ from those of the entries
+        sqlx::query("insert into logsfts(rowid, service, message) values (100, 'gone', 'gone')")
+            .execute(&pool)
+            .await?;
+        for (timestamp, service, message) in rows {
+            let logsfts_id = sqlx::query("insert into logsfts(service, message) values (?, ?)")
+                .bind(service)
+                .bind(message)
+                .execute(&pool)
+                .await?
+                .last_insert_rowid();
+            sqlx::query("insert into logs(hostname, timestamp, logsfts_id) values (?, ?, ?)")
+                .bind("localhost")
+                .bind(timestamp)
+                .bind(logsfts_id)
+                .execute(&pool)
+                .await?;
+        }
+        pool.close().await;
+
+        let db = LogDatabase::new(&url, WriteOptions::default()).await?;
+        let migrated: Vec<(i64, String, i64)> =
+            sqlx::query_as("select id, message, timestamp from logs order by id")
+                .fetch_all(&db.pool)
+                .await?;
+        let expected: Vec<(i64, String, i64)> = rows
+            .iter()
+            .zip(1..)
+            .map(|((timestamp, _, message), id)| {
+                let timestamp =
+                    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f").unwrap();
+                (id, message.to_string(), timestamp.timestamp_micros())
+            })
+            .collect();
+        assert_eq!(migrated, expected);
+        assert_eq!(expected[1].2 - expected[0].2, 500_000);
+        assert_eq!(expected[3].2 % 1_000_000, 1);
+
+        // the index refers to the ids of the entries
+        let indexed: Vec<(i64, String, String)> =
+            sqlx::query_as("select rowid, service, message from logsfts order by rowid")
+                .fetch_all(&db.pool)
+                .await?;
+        let expected: Vec<(i64, String, String)> = rows
+            .iter()
+            .zip(1..)
+            .map(|((_, service, message), id)| (id, service.to_string(), message.to_string()))
+            .collect();
+        assert_eq!(indexed, expected);
+        let filter = Filter {
+            message_keywords: Some(vec!["oom".to_string()]),
+            ..Default::default()
+        };
+        let found = extract(&db, &filter).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].service, "kernel");
+        assert_eq!(found[0].timestamp.timestamp_subsec_micros(), 123_456);
+
+        assert!(db.check().await?.is_empty());
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
     #[test]
     fn test_convert_to_fts_match() {
         assert_eq!(convert_to_fts_match::<&str>(&[]), "");
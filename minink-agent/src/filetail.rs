@@ -0,0 +1,551 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use serde::{Deserialize, Serialize};
+
+use minink_common::LogEntry;
+
+use crate::{
+    config::FileConfig,
+    logdispatcher::{Checkpoint, LogDispatcher},
+    source::{local_hostname, LogSource, SourceHealth, SourcePosition},
+};
+
+/// Maximum read from a file at each poll, the rest is read at the next poll.
+/// A line longer than that is split.
+const MAX_READ: u64 = 1024 * 1024;
+
+/// Follows files matching glob patterns like `tail -F`: rotated files are
+/// read until their end before following the new file at the same path,
+/// and truncated files are read again from their start.
+#[derive(Debug)]
+pub struct FileLogSource {
+    name: String,
+    patterns: Vec<String>,
+    service: Option<String>,
+    poll_interval: Duration,
+    hostname: String,
+    health: Mutex<SourceHealth>,
+    last_positions: Mutex<Option<String>>,
+}
+
+/// Where to continue reading a file, persisted for each file as the cursor of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct FilePosition {
+    /// 0 in the cursors saved before the devices were
+    #[serde(default)]
+    dev: u64,
+    inode: u64,
+    offset: u64,
+}
+
+impl FilePosition {
+    fn is_file(&self, id: FileId) -> bool {
+        (self.dev == 0 || self.dev == id.0) && self.inode == id.1
+    }
+}
+
+type FilePositions = BTreeMap<PathBuf, FilePosition>;
+
+/// Device and inode of a file, which stay the same when it is renamed.
+type FileId = (u64, u64);
+
+#[derive(Debug)]
+struct TailedFile {
+    file: File,
+    /// the current name of the file
+    path: PathBuf,
+    /// the name the file was first followed under, which gives the default
+    /// service even after the file is renamed
+    original: PathBuf,
+    position: FilePosition,
+}
+
+impl TailedFile {
+    fn open(path: &Path, id: FileId, offset: u64) -> Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            path: path.to_path_buf(),
+            original: path.to_path_buf(),
+            position: FilePosition {
+                dev: id.0,
+                inode: id.1,
+                offset,
+            },
+        })
+    }
+
+    /// Read the complete lines written since the last read, and the last
+    /// incomplete line if `last`. Returns whether the end of the file was read.
+    fn read_lines(&mut self, lines: &mut Vec<Line>, last: bool) -> Result<bool> {
+        let mut buf = vec![];
+        self.file.seek(SeekFrom::Start(self.position.offset))?;
+        (&mut self.file).take(MAX_READ).read_to_end(&mut buf)?;
+        let end = (buf.len() as u64) < MAX_READ;
+
+        let mut start = 0;
+        while let Some(len) = buf[start..].iter().position(|b| *b == b'\n') {
+            self.push_line(&buf[start..start + len], len + 1, lines);
+            start += len + 1;
+        }
+        // an incomplete line is read again at the next poll, unless it cannot get longer
+        if (buf.len() as u64 == MAX_READ && start == 0) || (last && end && start < buf.len()) {
+            self.push_line(&buf[start..], buf.len() - start, lines);
+        }
+        Ok(end)
+    }
+
+    /// Read the rest of a file that is not followed anymore, with its last line
+    /// even without a newline.
+    fn read_rest(&mut self, lines: &mut Vec<Line>) -> Result<()> {
+        while !self.read_lines(lines, true)? {}
+        Ok(())
+    }
+
+    fn push_line(&mut self, line: &[u8], consumed: usize, lines: &mut Vec<Line>) {
+        self.position.offset += consumed as u64;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        lines.push(Line {
+            path: self.path.clone(),
+            original: self.original.clone(),
+            text: String::from_utf8_lossy(line).into_owned(),
+            position: Some(self.position),
+        });
+    }
+}
+
+/// A line read from `path`, or the end of `path` when `position` is None:
+/// the file is not followed under that name anymore.
+#[derive(Debug)]
+struct Line {
+    path: PathBuf,
+    original: PathBuf,
+    text: String,
+    position: Option<FilePosition>,
+}
+
+impl Line {
+    fn end(path: PathBuf) -> Self {
+        Self {
+            original: path.clone(),
+            path,
+            text: String::new(),
+            position: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Tail {
+    /// by file rather than by name, so that a renamed file matching the
+    /// patterns is not read again
+    files: HashMap<FileId, TailedFile>,
+    /// positions loaded from the cursor, used when a file is opened
+    saved: FilePositions,
+    /// without any saved position, the existing files are followed from their end
+    from_end: bool,
+}
+
+fn matching_paths(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for pattern in patterns {
+        for path in glob::glob(pattern)? {
+            match path {
+                Ok(path) if path.is_file() => paths.push(path),
+                Ok(_) => {}
+                Err(err) => tracing::warn!("cannot read {}", err),
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+impl Tail {
+    /// Read the lines added to the files since the last poll: the files not
+    /// matching anymore first, then the files already followed, then the new ones.
+    fn poll(&mut self, patterns: &[String]) -> Result<Vec<Line>> {
+        let mut lines = vec![];
+        let mut matching: Vec<(FileId, PathBuf, u64)> = vec![];
+        for path in matching_paths(patterns)? {
+            match std::fs::metadata(&path) {
+                // the same file can match under several names, it is read once
+                Ok(metadata) if !matching.iter().any(|(id, ..)| *id == file_id(&metadata)) => {
+                    matching.push((file_id(&metadata), path, metadata.len()));
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("cannot read {}: {}", path.display(), err),
+            }
+        }
+
+        // deleted, or rotated to a name that does not match: read until the end
+        let gone = self
+            .files
+            .keys()
+            .filter(|id| !matching.iter().any(|(matching, ..)| matching == *id))
+            .copied()
+            .collect::<Vec<_>>();
+        for id in gone {
+            let mut tailed = self.files.remove(&id).unwrap();
+            tracing::info!("{} was rotated or removed", tailed.path.display());
+            if let Err(err) = tailed.read_rest(&mut lines) {
+                tracing::warn!("cannot read {}: {}", tailed.path.display(), err);
+            }
+            lines.push(Line::end(tailed.path));
+        }
+
+        matching.sort_by_key(|(id, ..)| !self.files.contains_key(id));
+        for (id, path, len) in matching {
+            if let Err(err) = self.poll_file(id, &path, len, &mut lines) {
+                tracing::warn!("cannot read {}: {}", path.display(), err);
+            }
+        }
+
+        self.from_end = false;
+        Ok(lines)
+    }
+
+    fn poll_file(
+        &mut self,
+        id: FileId,
+        path: &Path,
+        len: u64,
+        lines: &mut Vec<Line>,
+    ) -> Result<()> {
+        if !self.files.contains_key(&id) {
+            let offset = match self.saved.values().find(|saved| saved.is_file(id)) {
+                Some(saved) if saved.offset <= len => saved.offset,
+                Some(_) => 0,
+                None if self.from_end => len,
+                None => 0,
+            };
+            self.files.insert(id, TailedFile::open(path, id, offset)?);
+        }
+        let tailed = self.files.get_mut(&id).unwrap();
+        if tailed.path != path {
+            // renamed to a name that still matches, such as app.log.1 for app.log*
+            tracing::info!(
+                "{} was renamed to {}",
+                tailed.path.display(),
+                path.display()
+            );
+            let previous = std::mem::replace(&mut tailed.path, path.to_path_buf());
+            lines.push(Line::end(previous));
+        }
+        if len < tailed.position.offset {
+            tracing::info!("{} was truncated", path.display());
+            tailed.position.offset = 0;
+        }
+        tailed.read_lines(lines, false)?;
+        Ok(())
+    }
+}
+
+fn file_id(metadata: &std::fs::Metadata) -> FileId {
+    (metadata.dev(), metadata.ino())
+}
+
+impl FileLogSource {
+    pub fn new(config: &FileConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            patterns: config.paths.clone(),
+            service: config.service.clone(),
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            hostname: local_hostname(),
+            health: Mutex::new(SourceHealth::Starting),
+            last_positions: Mutex::new(None),
+        }
+    }
+
+    fn set_health(&self, health: SourceHealth) {
+        *self.health.lock().unwrap() = health;
+    }
+
+    /// The configured service, or the name of the file when it was first followed.
+    fn service(&self, original: &Path) -> String {
+        match &self.service {
+            Some(service) => service.clone(),
+            None => original
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for FileLogSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn health(&self) -> SourceHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn position(&self) -> Option<SourcePosition> {
+        self.last_positions
+            .lock()
+            .unwrap()
+            .clone()
+            .map(SourcePosition::Cursor)
+    }
+
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        position: Option<SourcePosition>,
+    ) -> Result<()> {
+        self.set_health(SourceHealth::Starting);
+
+        // a timestamp does not tell where to continue in the files
+        let mut positions: FilePositions = match position {
+            Some(SourcePosition::Cursor(cursor)) => serde_json::from_str(&cursor)?,
+            _ => FilePositions::new(),
+        };
+        let mut tail = Tail {
+            files: HashMap::new(),
+            saved: positions.clone(),
+            from_end: positions.is_empty(),
+        };
+
+        loop {
+            let patterns = self.patterns.clone();
+            let (result, returned_tail) = tokio::task::spawn_blocking(move || {
+                let result = tail.poll(&patterns);
+                (result, tail)
+            })
+            .await?;
+            tail = returned_tail;
+            self.set_health(SourceHealth::Running);
+
+            let lines = result?;
+            // the positions of all the files are only saved with the last line
+            // of the poll: after a crash, the lines since the previous poll
+            // are read again
+            let last = lines.iter().rposition(|line| line.position.is_some());
+            for (i, line) in lines.into_iter().enumerate() {
+                let Some(position) = line.position else {
+                    positions.remove(&line.path);
+                    continue;
+                };
+                positions.insert(line.path.clone(), position);
+                let entry = LogEntry {
+                    message: line.text,
+                    hostname: self.hostname.clone(),
+                    service: self.service(&line.original),
+                    timestamp: chrono::Utc::now().naive_utc(),
+                    level: None,
+                    fields: BTreeMap::from([(
+                        "FILE".to_string(),
+                        line.path.to_string_lossy().into_owned(),
+                    )]),
+                };
+                if Some(i) != last {
+                    dispatcher.send(entry).await;
+                    continue;
+                }
+                let cursor = serde_json::to_string(&positions)?;
+                let checkpoint = Checkpoint {
+                    source: self.name.clone(),
+                    position: cursor.clone(),
+                };
+                dispatcher.send_with_checkpoint(entry, checkpoint).await;
+                *self.last_positions.lock().unwrap() = Some(cursor);
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use crate::{
+        config::FileConfig,
+        logdispatcher::LogDispatcher,
+        logstream::LogStream,
+        source::{LogSource, SourcePosition},
+    };
+
+    use super::FileLogSource;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minink-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn append(path: &Path, content: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn start(dir: &Path, position: Option<SourcePosition>) -> (Arc<FileLogSource>, LogStream) {
+        start_matching(dir, "*.log", position)
+    }
+
+    fn start_matching(
+        dir: &Path,
+        pattern: &str,
+        position: Option<SourcePosition>,
+    ) -> (Arc<FileLogSource>, LogStream) {
+        let config = FileConfig {
+            name: "files".to_string(),
+            paths: vec![dir.join(pattern).to_string_lossy().into_owned()],
+            service: None,
+            poll_interval_ms: 10,
+            multiline: None,
+        };
+        let source = Arc::new(FileLogSource::new(&config));
+        let dispatcher = Arc::new(LogDispatcher::new());
+        let stream = dispatcher.stream("test");
+        tokio::spawn({
+            let source = source.clone();
+            async move { source.follow(dispatcher, position).await }
+        });
+        (source, stream)
+    }
+
+    async fn pull(stream: &mut LogStream, n: usize) -> Result<Vec<String>> {
+        let mut messages = vec![];
+        for _ in 0..n {
+            let entry = tokio::time::timeout(Duration::from_secs(5), stream.pull_one()).await??;
+            messages.push(entry.message);
+        }
+        // nothing else comes
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), stream.pull_one())
+                .await
+                .is_err()
+        );
+        Ok(messages)
+    }
+
+    #[tokio::test]
+    async fn test_follow_rotated_files() -> Result<()> {
+        let dir = test_dir("rotate");
+        let path = dir.join("app.log");
+        append(&path, "before start\n");
+
+        let (_source, mut stream) = start(&dir, None);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        append(&path, "first\nsecond\npartial");
+        assert_eq!(pull(&mut stream, 2).await?, ["first", "second"]);
+
+        append(&path, " line\r\n");
+        let entry = tokio::time::timeout(Duration::from_secs(5), stream.pull_one()).await??;
+        assert_eq!(entry.message, "partial line");
+        assert_eq!(entry.service, "app");
+        assert_eq!(entry.fields["FILE"], path.to_string_lossy());
+
+        // renamed and a new file created, written before the next poll,
+        // the last line of the old file has no newline
+        append(&path, "last of the old file");
+        std::fs::rename(&path, dir.join("app.log.1"))?;
+        append(&path, "new file\n");
+        assert_eq!(
+            pull(&mut stream, 2).await?,
+            ["last of the old file", "new file"]
+        );
+
+        std::fs::write(&path, "cut\n")?;
+        assert_eq!(pull(&mut stream, 1).await?, ["cut"]);
+
+        // new files are read from their start
+        append(&dir.join("other.log"), "other\n");
+        assert_eq!(pull(&mut stream, 1).await?, ["other"]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotated_name_matching() -> Result<()> {
+        let dir = test_dir("rotate-matching");
+        let path = dir.join("app.log");
+        let rotated = dir.join("app.log.1");
+
+        let (source, mut stream) = start_matching(&dir, "app.log*", None);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        append(&path, "first\n");
+        assert_eq!(pull(&mut stream, 1).await?, ["first"]);
+
+        // not read again under its new name
+        std::fs::rename(&path, &rotated)?;
+        append(&rotated, "late\n");
+        append(&path, "new file\n");
+        let entries = [
+            tokio::time::timeout(Duration::from_secs(5), stream.pull_one()).await??,
+            tokio::time::timeout(Duration::from_secs(5), stream.pull_one()).await??,
+        ];
+        assert_eq!(entries[0].message, "late");
+        assert_eq!(entries[0].fields["FILE"], rotated.to_string_lossy());
+        assert_eq!(entries[0].service, "app");
+        assert_eq!(entries[1].message, "new file");
+        assert!(pull(&mut stream, 0).await?.is_empty());
+
+        // both files are in the cursor, by their current name
+        let Some(SourcePosition::Cursor(cursor)) = source.position() else {
+            panic!("no cursor");
+        };
+        let positions: serde_json::Value = serde_json::from_str(&cursor)?;
+        assert_eq!(positions[rotated.to_str().unwrap()]["offset"], 11);
+        assert_eq!(positions[path.to_str().unwrap()]["offset"], 9);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_cursor() -> Result<()> {
+        let dir = test_dir("resume");
+        let path = dir.join("app.log");
+
+        let (source, mut stream) = start(&dir, None);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        append(&path, "one\ntwo\n");
+        assert_eq!(pull(&mut stream, 2).await?, ["one", "two"]);
+        let position = source.position();
+
+        // written while the agent is stopped
+        append(&path, "three\n");
+        append(&dir.join("new.log"), "four\n");
+        let (_source, mut stream) = start(&dir, position);
+        let mut messages = pull(&mut stream, 2).await?;
+        messages.sort();
+        assert_eq!(messages, ["four", "three"]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
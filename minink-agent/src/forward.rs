@@ -0,0 +1,571 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+
+use minink_common::LogEntry;
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    time::Instant,
+};
+
+use crate::{config::ForwardConfig, logstream::LogStream};
+
+/// Headers identifying a forwarded batch, so that the receiving agent can
+/// ignore the batches it already stored.
+pub const SENDER_HEADER: &str = "x-minink-sender";
+pub const BATCH_HEADER: &str = "x-minink-batch";
+pub const EPOCH_HEADER: &str = "x-minink-epoch";
+
+/// Longest wait for a connection to the upstream agent, below the timeout
+/// of the whole request.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies a batch received from another agent. The sequence numbers
+/// start again at 0 with a new spool, which has another epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedBatch {
+    pub sender: String,
+    /// 0 for the agents that do not send it
+    pub epoch: u64,
+    pub seq: u64,
+}
+
+impl ForwardedBatch {
+    /// Source whose position is the last batch stored.
+    pub fn source(&self) -> String {
+        format!("forward:{}", self.sender)
+    }
+
+    pub fn position(&self) -> String {
+        format!("{}:{}", self.epoch, self.seq)
+    }
+
+    /// Whether this batch, or a later one of the same spool, is the last
+    /// batch stored from the sender.
+    pub fn is_stored(&self, position: Option<&str>) -> bool {
+        // only the sequence number for the batches without epoch
+        let last = position.and_then(|position| match position.split_once(':') {
+            Some((epoch, seq)) => Some((epoch.parse().ok()?, seq.parse().ok()?)),
+            None => Some((0, position.parse().ok()?)),
+        });
+        last.is_some_and(|(epoch, seq): (u64, u64)| epoch == self.epoch && seq >= self.seq)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpoolBatch {
+    seq: u64,
+    entries: Vec<LogEntry>,
+}
+
+/// What was sent from the spool, saved next to it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SpoolState {
+    /// offset of the first batch not acknowledged by the upstream agent
+    sent_offset: u64,
+    next_seq: u64,
+    /// when the spool was created, in microseconds, so that the upstream agent
+    /// tells its batches from those of a spool that was lost
+    #[serde(default)]
+    epoch: u64,
+}
+
+impl SpoolState {
+    /// Written aside and renamed, so that the state is never partially written.
+    async fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("state.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+/// Batches waiting to be sent, one JSON object per line. Every batch is
+/// written to the spool before being sent, and is only skipped once the
+/// upstream agent acknowledged it, so that nothing is lost if the agent stops.
+#[derive(Debug)]
+struct Spool {
+    path: PathBuf,
+    state_path: PathBuf,
+    file: File,
+    state: SpoolState,
+}
+
+impl Spool {
+    async fn open(path: PathBuf) -> Result<Self> {
+        let state_path = path.with_extension("state");
+        let mut state: SpoolState = match tokio::fs::read(&state_path).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+                let state = SpoolState {
+                    epoch: since_epoch.as_micros() as u64,
+                    ..Default::default()
+                };
+                // before any batch is sent with it
+                state.save(&state_path).await?;
+                state
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .await?;
+
+        // batches written after the state was saved, the last one may be incomplete
+        let mut reader = BufReader::new(file.try_clone().await?);
+        let len = reader.seek(std::io::SeekFrom::End(0)).await?;
+        if state.sent_offset > len {
+            tracing::warn!(
+                "{} is shorter than expected, sending it again",
+                path.display()
+            );
+            state.sent_offset = 0;
+        }
+        reader
+            .seek(std::io::SeekFrom::Start(state.sent_offset))
+            .await?;
+        let mut complete = state.sent_offset;
+        let mut line = String::new();
+        while reader.read_line(&mut line).await? > 0 {
+            let Some(batch) = line
+                .strip_suffix('\n')
+                .and_then(|line| serde_json::from_str::<SpoolBatch>(line).ok())
+            else {
+                break;
+            };
+            state.next_seq = state.next_seq.max(batch.seq + 1);
+            complete += line.len() as u64;
+            line.clear();
+        }
+        if complete < len {
+            tracing::warn!(
+                "dropping an incomplete batch at the end of {}",
+                path.display()
+            );
+            file.set_len(complete).await?;
+        }
+
+        Ok(Self {
+            path,
+            state_path,
+            file,
+            state,
+        })
+    }
+
+    async fn push(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        let batch = SpoolBatch {
+            seq: self.state.next_seq,
+            entries,
+        };
+        let mut line = serde_json::to_vec(&batch)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.sync_data().await?;
+        self.state.next_seq += 1;
+        Ok(())
+    }
+
+    /// The oldest batch not sent yet, and the offset right after it.
+    async fn next_batch(&self) -> Result<Option<(SpoolBatch, u64)>> {
+        let mut reader = BufReader::new(File::open(&self.path).await?);
+        reader
+            .seek(std::io::SeekFrom::Start(self.state.sent_offset))
+            .await?;
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let batch = serde_json::from_str(&line)?;
+        Ok(Some((batch, self.state.sent_offset + line.len() as u64)))
+    }
+
+    async fn ack(&mut self, end: u64) -> Result<()> {
+        self.state.sent_offset = end;
+        if end == self.file.metadata().await?.len() {
+            self.file.set_len(0).await?;
+            self.state.sent_offset = 0;
+        }
+        self.state.save(&self.state_path).await
+    }
+}
+
+/// Sends the entries of the dispatcher to the ingest endpoint of another agent.
+#[derive(Debug)]
+pub struct Forwarder {
+    config: ForwardConfig,
+    sender: String,
+    client: reqwest::Client,
+    spool: Spool,
+    /// the upstream agent is not contacted again before that
+    retry_at: Option<Instant>,
+}
+
+enum SendError {
+    /// the batch can be sent again later
+    Retry(anyhow::Error),
+    /// the batch was refused and is dropped
+    Rejected(anyhow::Error),
+}
+
+impl Forwarder {
+    pub async fn new(config: ForwardConfig) -> Result<Self> {
+        let spool = Spool::open(config.spool.clone()).await?;
+        let sender = config
+            .sender
+            .clone()
+            .unwrap_or_else(crate::source::local_hostname);
+        // a hanging upstream agent must not block the forward queue, and
+        // with it the sources
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT.min(config.timeout()))
+            .timeout(config.timeout())
+            .build()?;
+        Ok(Self {
+            config,
+            sender,
+            client,
+            spool,
+            retry_at: None,
+        })
+    }
+
+    /// Spool and send the entries of `stream`, in batches. Once the stream
+    /// is closed, the remaining entries are spooled to be sent at the next start.
+    pub async fn run(mut self, mut stream: LogStream) -> Result<()> {
+        let mut entries = vec![];
+        let mut interval = tokio::time::interval(self.config.batch_interval());
+        loop {
+            tokio::select! {
+                record = stream.pull_record() => {
+                    let Ok(record) = record else {
+                        if !entries.is_empty() {
+                            self.spool.push(entries).await?;
+                        }
+                        return Ok(());
+                    };
+                    entries.push(record.entry);
+                    if entries.len() < self.config.batch_size {
+                        continue;
+                    }
+                },
+                _ = interval.tick() => {},
+            }
+            if !entries.is_empty() {
+                self.spool.push(std::mem::take(&mut entries)).await?;
+            }
+            self.send_spooled().await?;
+        }
+    }
+
+    /// Send the spooled batches in order, until one fails.
+    async fn send_spooled(&mut self) -> Result<()> {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(());
+        }
+        self.retry_at = None;
+
+        while let Some((batch, end)) = self.spool.next_batch().await? {
+            match self.send(&batch).await {
+                Ok(()) => {}
+                Err(SendError::Rejected(err)) => {
+                    tracing::error!("batch {} refused by the upstream agent: {}", batch.seq, err);
+                }
+                Err(SendError::Retry(err)) => {
+                    tracing::warn!(
+                        "cannot forward to {}: {}, retrying in {:?}",
+                        self.config.url,
+                        err,
+                        self.config.retry_interval()
+                    );
+                    self.retry_at = Some(Instant::now() + self.config.retry_interval());
+                    return Ok(());
+                }
+            }
+            self.spool.ack(end).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, batch: &SpoolBatch) -> Result<(), SendError> {
+        let body = ndjson_gzip(&batch.entries).map_err(SendError::Rejected)?;
+        let url = format!("{}/api/ingest", self.config.url.trim_end_matches('/'));
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .header(SENDER_HEADER, &self.sender)
+            .header(BATCH_HEADER, batch.seq)
+            .header(EPOCH_HEADER, self.spool.state.epoch)
+            .body(body)
+            .send()
+            .await
+            // including the timeouts
+            .map_err(|err| SendError::Retry(err.into()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let err = anyhow::format_err!("{}: {}", status, response.text().await.unwrap_or_default());
+        if status.is_client_error()
+            && status != reqwest::StatusCode::REQUEST_TIMEOUT
+            && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        {
+            Err(SendError::Rejected(err))
+        } else {
+            Err(SendError::Retry(err))
+        }
+    }
+}
+
+fn ndjson_gzip(entries: &[LogEntry]) -> Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    for entry in entries {
+        serde_json::to_writer(&mut encoder, entry)?;
+        encoder.write_all(b"\n")?;
+    }
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+
+    use chrono::NaiveDateTime;
+
+    use minink_common::LogEntry;
+
+    use crate::{config::ForwardConfig, logdispatcher::LogDispatcher};
+
+    use super::{ForwardedBatch, Forwarder, SendError, Spool, SpoolBatch, BATCH_HEADER};
+
+    fn test_spool(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minink-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("forward.spool")
+    }
+
+    fn entry(message: &str) -> LogEntry {
+        LogEntry {
+            message: message.to_string(),
+            hostname: "localhost".to_string(),
+            service: "test".to_string(),
+            timestamp: NaiveDateTime::default(),
+            level: None,
+            fields: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spool_recovery() -> Result<()> {
+        let path = test_spool("spool");
+        let mut spool = Spool::open(path.clone()).await?;
+        let epoch = spool.state.epoch;
+        assert!(epoch > 0);
+        spool.push(vec![entry("a")]).await?;
+        spool.push(vec![entry("b")]).await?;
+        let (batch, end) = spool.next_batch().await?.unwrap();
+        assert_eq!(batch.seq, 0);
+        spool.ack(end).await?;
+        drop(spool);
+
+        // interrupted while writing a batch
+        let mut content = std::fs::read(&path)?;
+        content.extend_from_slice(br#"{"seq":2,"entr"#);
+        std::fs::write(&path, content)?;
+
+        let mut spool = Spool::open(path.clone()).await?;
+        assert_eq!(spool.state.epoch, epoch);
+        let (batch, end) = spool.next_batch().await?.unwrap();
+        assert_eq!((batch.seq, batch.entries[0].message.as_str()), (1, "b"));
+        spool.ack(end).await?;
+        assert!(spool.next_batch().await?.is_none());
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+
+        // the sequence numbers continue after an empty spool
+        spool.push(vec![entry("c")]).await?;
+        drop(spool);
+        let spool = Spool::open(path.clone()).await?;
+        assert_eq!(spool.next_batch().await?.unwrap().0.seq, 2);
+
+        // a new spool, whose numbering starts again, has another epoch
+        std::fs::remove_file(path.with_extension("state"))?;
+        std::fs::remove_file(&path)?;
+        let spool = Spool::open(path.clone()).await?;
+        assert_ne!(spool.state.epoch, epoch);
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_batch_is_stored() {
+        let batch = ForwardedBatch {
+            sender: "web-1".to_string(),
+            epoch: 5,
+            seq: 3,
+        };
+        assert!(!batch.is_stored(None));
+        assert!(batch.is_stored(Some("5:3")));
+        assert!(batch.is_stored(Some("5:4")));
+        assert!(!batch.is_stored(Some("5:2")));
+        // another spool of the same sender
+        assert!(!batch.is_stored(Some("4:10")));
+        // stored by an agent without epochs
+        assert!(!batch.is_stored(Some("10")));
+        let old = ForwardedBatch { epoch: 0, ..batch };
+        assert!(old.is_stored(Some("10")));
+    }
+
+    /// sequence number and messages of a batch
+    type Batch = (u64, Vec<String>);
+
+    #[derive(Clone, Default)]
+    struct Upstream {
+        /// failing requests before the first success
+        failures: Arc<Mutex<u32>>,
+        received: Arc<Mutex<Vec<Batch>>>,
+    }
+
+    async fn ingest(
+        State(upstream): State<Upstream>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut failures = upstream.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        let seq = headers[BATCH_HEADER].to_str().unwrap().parse().unwrap();
+        let mut body_text = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut body_text)
+            .unwrap();
+        let messages = body_text
+            .lines()
+            .map(|line| serde_json::from_str::<LogEntry>(line).unwrap().message)
+            .collect();
+        upstream.received.lock().unwrap().push((seq, messages));
+        StatusCode::OK
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_failures() -> Result<()> {
+        let upstream = Upstream::default();
+        *upstream.failures.lock().unwrap() = 3;
+        let app = Router::new()
+            .route("/api/ingest", post(ingest))
+            .with_state(upstream.clone());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let path = test_spool("forward");
+        let config = ForwardConfig {
+            url,
+            spool: path.clone(),
+            sender: Some("test".to_string()),
+            batch_size: 2,
+            batch_interval_ms: 5,
+            retry_interval_ms: 10,
+            timeout_ms: 1000,
+        };
+        let forwarder = Forwarder::new(config).await?;
+        let dispatcher = Arc::new(LogDispatcher::new());
+        let job = tokio::spawn(forwarder.run(dispatcher.stream("test")));
+        for i in 0..6 {
+            dispatcher.send(entry(&i.to_string())).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while upstream
+                .received
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|b| b.1.len())
+                .sum::<usize>()
+                < 6
+            {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await?;
+        // sent in order, once, despite the failures
+        let received = upstream.received.lock().unwrap().clone();
+        let seqs = received.iter().map(|b| b.0).collect::<Vec<_>>();
+        assert_eq!(seqs, (0..received.len() as u64).collect::<Vec<_>>());
+        let messages = received.into_iter().flat_map(|b| b.1).collect::<Vec<_>>();
+        assert_eq!(messages, ["0", "1", "2", "3", "4", "5"]);
+        job.abort();
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_timeout() -> Result<()> {
+        // accepts the connections but never answers
+        let app = Router::new().route(
+            "/api/ingest",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                StatusCode::OK
+            }),
+        );
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let path = test_spool("timeout");
+        let config = ForwardConfig {
+            url,
+            spool: path.clone(),
+            sender: Some("test".to_string()),
+            batch_size: 1,
+            batch_interval_ms: 5,
+            retry_interval_ms: 10,
+            timeout_ms: 50,
+        };
+        let forwarder = Forwarder::new(config).await?;
+        let batch = SpoolBatch {
+            seq: 0,
+            entries: vec![entry("a")],
+        };
+        let sent = tokio::time::timeout(Duration::from_secs(5), forwarder.send(&batch)).await?;
+        assert!(matches!(sent, Err(SendError::Retry(_))));
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+}
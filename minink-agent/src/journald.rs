@@ -1,61 +1,155 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 
 use async_process::{Command, Stdio};
 
+use async_trait::async_trait;
+
 use chrono::NaiveDateTime;
 
 use futures_lite::io::BufReader;
-use futures_lite::{AsyncBufReadExt, StreamExt};
+use futures_lite::{AsyncBufReadExt, Stream, StreamExt};
 
 use serde::Deserialize;
 
-use minink_common::LogEntry;
+use minink_common::{Level, LogEntry};
 
-use crate::logdispatcher::LogDispatcher;
+use crate::{
+    config::JournaldConfig,
+    logdispatcher::{Checkpoint, LogDispatcher},
+    source::{LogSource, SourceHealth, SourcePosition},
+};
 
 #[derive(Debug)]
 pub struct JournaldLogSource {
-    dispatcher: Arc<LogDispatcher>,
+    name: String,
+    units: Vec<String>,
+    directory: Option<PathBuf>,
+    health: Mutex<SourceHealth>,
+    last_cursor: Mutex<Option<String>>,
 }
 
 impl JournaldLogSource {
-    pub fn new() -> (Self, Arc<LogDispatcher>) {
-        let dispatcher = Arc::new(LogDispatcher::new());
-        (
-            JournaldLogSource {
-                dispatcher: dispatcher.clone(),
-            },
-            dispatcher,
-        )
+    pub fn new(config: &JournaldConfig) -> Self {
+        JournaldLogSource {
+            name: config.name.clone(),
+            units: config.units.clone(),
+            directory: config.directory.clone(),
+            health: Mutex::new(SourceHealth::Starting),
+            last_cursor: Mutex::new(None),
+        }
     }
 
-    pub async fn follow(self, since_timestamp: Option<NaiveDateTime>) -> Result<()> {
-        let since_format = if let Some(since) = since_timestamp {
-            let now = chrono::Utc::now().naive_utc();
-            let duration = now - since;
-            let ms = duration.num_milliseconds();
-            format!("-{}s{}ms", ms / 1000, ms % 1000)
-        } else {
-            "1 day ago".to_string()
-        };
+    fn set_health(&self, health: SourceHealth) {
+        *self.health.lock().unwrap() = health;
+    }
+
+    /// Send the entries of the lines output by journalctl, until its output
+    /// ends. The invalid lines are skipped: the source would be restarted
+    /// at the same cursor and stop at the same line again.
+    async fn dispatch_lines(
+        &self,
+        mut lines: impl Stream<Item = std::io::Result<String>> + Unpin,
+        dispatcher: &LogDispatcher,
+    ) -> Result<()> {
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                    tracing::warn!("{}: skipping a journalctl line: {}", self.name, err);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let (entry, cursor) = match parse_log_entry(&line) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    tracing::warn!(
+                        "{}: skipping an invalid journalctl line: {}: {}",
+                        self.name,
+                        err,
+                        line
+                    );
+                    continue;
+                }
+            };
+            let checkpoint = Checkpoint {
+                source: self.name.clone(),
+                position: cursor.clone(),
+            };
+            *self.last_cursor.lock().unwrap() = Some(cursor);
+            dispatcher.send_with_checkpoint(entry, checkpoint).await;
+        }
+        Ok(())
+    }
+}
 
-        let mut child = Command::new("journalctl")
+#[async_trait]
+impl LogSource for JournaldLogSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn health(&self) -> SourceHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn position(&self) -> Option<SourcePosition> {
+        self.last_cursor
+            .lock()
+            .unwrap()
+            .clone()
+            .map(SourcePosition::Cursor)
+    }
+
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        position: Option<SourcePosition>,
+    ) -> Result<()> {
+        self.set_health(SourceHealth::Starting);
+
+        let mut command = Command::new("journalctl");
+        command
             .arg("--follow")
+            // without --output-fields, all the fields are output
             .arg("--output=json")
-            .arg("--output-fields=MESSAGE,_HOSTNAME,_SYSTEMD_UNIT,__REALTIME_TIMESTAMP,SYSLOG_IDENTIFIER,_EXE")
-            .arg("--all")
-            .arg(&format!("--since={}", since_format))
-            .stdout(Stdio::piped())
-            .spawn()?;
+            .arg("--all");
+        match position {
+            Some(SourcePosition::Cursor(cursor)) => {
+                command.arg(format!("--after-cursor={}", cursor));
+            }
+            // databases written before cursors were persisted only know the
+            // timestamp of their last entry
+            Some(SourcePosition::Timestamp(since)) => {
+                let now = chrono::Utc::now().naive_utc();
+                let duration = now - since;
+                let ms = duration.num_milliseconds();
+                command.arg(format!("--since=-{}s{}ms", ms / 1000, ms % 1000));
+            }
+            None => {
+                command.arg("--since=1 day ago");
+            }
+        }
+        if let Some(directory) = &self.directory {
+            command.arg("--directory").arg(directory);
+        }
+        for unit in &self.units {
+            command.arg(format!("--unit={}", unit));
+        }
+        // stopped along with the source
+        command.kill_on_drop(true);
+        let mut child = command.stdout(Stdio::piped()).spawn()?;
 
-        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let lines = BufReader::new(child.stdout.take().unwrap()).lines();
 
-        while let Some(line) = lines.next().await {
-            let entry = parse_log_entry(&line?)?;
-            self.dispatcher.send(entry);
-        }
+        self.set_health(SourceHealth::Running);
+        self.dispatch_lines(lines, &dispatcher).await?;
 
         Err(anyhow::format_err!(
             "journalctl exited: {:?}",
@@ -66,18 +160,12 @@ impl JournaldLogSource {
 
 #[derive(Debug, Deserialize)]
 struct JournaldRawLogEntry {
-    #[serde(rename = "MESSAGE")]
-    message: JournaldMessage,
-    #[serde(rename = "_HOSTNAME")]
-    hostname: String,
-    #[serde(rename = "_SYSTEMD_UNIT")]
-    systemd_unit: Option<String>,
+    #[serde(rename = "__CURSOR")]
+    cursor: String,
     #[serde(rename = "__REALTIME_TIMESTAMP")]
     timestamp: String,
-    #[serde(rename = "SYSLOG_IDENTIFIER")]
-    syslog_identifier: Option<String>,
-    #[serde(rename = "_EXE")]
-    exe: Option<String>,
+    #[serde(flatten)]
+    fields: BTreeMap<String, JournaldMessage>,
 }
 
 /// see journalctl(1) json format
@@ -105,20 +193,115 @@ impl std::fmt::Display for JournaldMessage {
     }
 }
 
-fn parse_log_entry(line: &str) -> Result<LogEntry> {
-    let raw: JournaldRawLogEntry = serde_json::from_str(line)?;
-    let timestamp = raw.timestamp.parse()?;
-    let timestamp = NaiveDateTime::from_timestamp_micros(timestamp).unwrap();
-    let service = raw
-        .syslog_identifier
-        .or(raw.systemd_unit)
-        .or(raw.exe)
+/// Value of a field that appears several times in an entry, like journalctl does.
+pub fn join_values(values: Vec<String>) -> String {
+    if values.len() == 1 {
+        return values.into_iter().next().unwrap();
+    }
+    values.into_iter().map(|v| v + ";").collect()
+}
+
+/// Build an entry from all the fields of a journal entry.
+/// The fields that are not part of `LogEntry` are kept in `LogEntry::fields`,
+/// except the ones added by journalctl itself (starting with `__`).
+pub fn log_entry(mut fields: BTreeMap<String, String>, timestamp: NaiveDateTime) -> LogEntry {
+    fields.retain(|name, _| !name.starts_with("__"));
+    let message = fields.remove("MESSAGE").unwrap_or_default();
+    let hostname = fields.remove("_HOSTNAME").unwrap_or_default();
+    let level = fields
+        .remove("PRIORITY")
+        .and_then(|p| p.parse().ok())
+        .and_then(Level::from_priority);
+    // from the most to the least specific field
+    let service = ["SYSLOG_IDENTIFIER", "_SYSTEMD_UNIT", "_EXE"]
+        .iter()
+        .find_map(|name| fields.get(*name).cloned())
         .unwrap_or_default();
-    let message = raw.message.to_string();
-    Ok(LogEntry {
+    LogEntry {
         message,
-        hostname: raw.hostname,
+        hostname,
         service,
         timestamp,
-    })
+        level,
+        fields,
+    }
+}
+
+fn parse_log_entry(line: &str) -> Result<(LogEntry, String)> {
+    let raw: JournaldRawLogEntry = serde_json::from_str(line)?;
+    let timestamp = raw.timestamp.parse()?;
+    let timestamp = NaiveDateTime::from_timestamp_micros(timestamp)
+        .ok_or_else(|| anyhow::format_err!("invalid timestamp {}", raw.timestamp))?;
+    let fields = raw
+        .fields
+        .into_iter()
+        .map(|(name, value)| (name, value.to_string()))
+        .collect();
+    Ok((log_entry(fields, timestamp), raw.cursor))
+}
+
+#[cfg(test)]
+mod tests {
+    use minink_common::Level;
+
+    use crate::{config::JournaldConfig, logdispatcher::LogDispatcher};
+
+    use super::{parse_log_entry, JournaldLogSource};
+
+    #[test]
+    fn test_parse_log_entry() {
+        let line = r#"{"__CURSOR":"s=9e26;i=5","__REALTIME_TIMESTAMP":"1792204020346300","__MONOTONIC_TIMESTAMP":"1430348317","_BOOT_ID":"9139","_HOSTNAME":"vm","PRIORITY":"3","SYSLOG_IDENTIFIER":"webapp","_SYSTEMD_UNIT":"webapp.service","MESSAGE":"request failed","REQUEST_ID":"abc-123","BINARY":[98,0,1]}"#;
+        let (entry, cursor) = parse_log_entry(line).unwrap();
+        assert_eq!(cursor, "s=9e26;i=5");
+        assert_eq!(entry.message, "request failed");
+        assert_eq!(entry.hostname, "vm");
+        assert_eq!(entry.service, "webapp");
+        assert_eq!(entry.level, Some(Level::Err));
+        assert_eq!(entry.timestamp.timestamp_micros(), 1792204020346300);
+        assert_eq!(
+            entry.fields.keys().collect::<Vec<_>>(),
+            [
+                "BINARY",
+                "REQUEST_ID",
+                "SYSLOG_IDENTIFIER",
+                "_BOOT_ID",
+                "_SYSTEMD_UNIT"
+            ]
+        );
+        assert_eq!(entry.fields["REQUEST_ID"], "abc-123");
+        assert_eq!(entry.fields["BINARY"], "b\0\u{1}");
+    }
+
+    #[tokio::test]
+    async fn test_skip_invalid_lines() {
+        let config: JournaldConfig = toml::from_str("").unwrap();
+        let source = JournaldLogSource::new(&config);
+        let dispatcher = LogDispatcher::new();
+        let mut stream = dispatcher.stream("test");
+        let line = |i: u32| {
+            format!(
+                r#"{{"__CURSOR":"s=1;i={i}","__REALTIME_TIMESTAMP":"1792204020346300","MESSAGE":"line {i}"}}"#
+            )
+        };
+        let lines = vec![
+            Ok(line(1)),
+            Ok(r#"{"__CURSOR":"s=1;i=2","MESSAGE":"no timestamp"}"#.to_string()),
+            Ok(
+                r#"{"__CURSOR":"s=1;i=3","__REALTIME_TIMESTAMP":"99999999999999999999"}"#
+                    .to_string(),
+            ),
+            Err(std::io::ErrorKind::InvalidData.into()),
+            Ok(line(4)),
+        ];
+        source
+            .dispatch_lines(futures_lite::stream::iter(lines), &dispatcher)
+            .await
+            .unwrap();
+        assert_eq!(stream.pull_one().await.unwrap().message, "line 1");
+        assert_eq!(stream.pull_one().await.unwrap().message, "line 4");
+        assert_eq!(
+            source.last_cursor.lock().unwrap().as_deref(),
+            Some("s=1;i=4")
+        );
+    }
 }
@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use chrono::NaiveDateTime;
+
+use minink_common::LogEntry;
+
+use crate::{
+    config::JournalDirectoryConfig,
+    journald::{join_values, log_entry},
+    journalfile::{EntryArrayPosition, JournalEntry, JournalFile},
+    logdispatcher::{Checkpoint, LogDispatcher},
+    source::{LogSource, SourceHealth, SourcePosition},
+};
+
+/// Follows the journal files of a directory by reading them directly,
+/// without journalctl. The directory can come from another machine.
+#[derive(Debug)]
+pub struct JournalDirectoryLogSource {
+    name: String,
+    directory: PathBuf,
+    poll_interval: Duration,
+    health: Mutex<SourceHealth>,
+    last_position: Mutex<Option<EntryPosition>>,
+}
+
+/// Entries of all the files are sent ordered by realtime, then by seqnum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct EntryPosition {
+    realtime: u64,
+    seqnum: u64,
+}
+
+impl EntryPosition {
+    fn of(entry: &JournalEntry) -> Self {
+        Self {
+            realtime: entry.realtime,
+            seqnum: entry.seqnum,
+        }
+    }
+
+    fn parse(cursor: &str) -> Option<Self> {
+        let (realtime, seqnum) = cursor.split_once(';')?;
+        Some(Self {
+            realtime: u64::from_str_radix(realtime.strip_prefix("t=")?, 16).ok()?,
+            seqnum: u64::from_str_radix(seqnum.strip_prefix("i=")?, 16).ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for EntryPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "t={:x};i={:x}", self.realtime, self.seqnum)
+    }
+}
+
+/// Entries read at once from a file, and sent at most by poll.
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Default)]
+struct FileState {
+    position: EntryArrayPosition,
+    /// entries read but not sent yet, in the order of the file
+    pending: VecDeque<JournalEntry>,
+    /// nothing more to read from the file in this poll
+    caught_up: bool,
+    /// archived files are not read again once read to the end
+    done: bool,
+}
+
+impl FileState {
+    /// Read the next entries of `file`, keeping the position on errors so
+    /// that they are read again by the next poll.
+    fn read_batch(&mut self, path: &Path, file: &mut JournalFile, batch_size: usize) {
+        // checked before reading, so that entries added in between are not missed
+        let archived = file.archived();
+        let mut position = self.position.clone();
+        let result = file
+            .next_entry_offsets(&mut position, batch_size)
+            .and_then(|offsets| {
+                offsets
+                    .into_iter()
+                    .map(|offset| file.read_entry(offset))
+                    .collect::<Result<Vec<_>>>()
+            });
+        match result {
+            Ok(entries) => {
+                self.caught_up = entries.len() < batch_size;
+                self.done = archived && self.caught_up;
+                self.position = position;
+                self.pending.extend(entries);
+            }
+            Err(err) => {
+                tracing::warn!("cannot read {}: {}", path.display(), err);
+                self.caught_up = true;
+                self.done = archived;
+            }
+        }
+    }
+}
+
+type FileStates = HashMap<[u8; 16], FileState>;
+
+fn journal_paths(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // files ending with '~' were not closed properly but are still readable
+        if name.ends_with(".journal") || name.ends_with(".journal~") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Read at most `batch_size` of the entries added to the files of `directory`
+/// since the last poll, merging the files by reading `batch_size` entries of
+/// each at a time.
+fn poll(directory: &Path, files: &mut FileStates, batch_size: usize) -> Result<Vec<JournalEntry>> {
+    let mut opened = vec![];
+    for path in journal_paths(directory)? {
+        let mut file = match JournalFile::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::warn!("cannot open {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        let state = files.entry(file.file_id()).or_default();
+        if state.done && state.pending.is_empty() {
+            continue;
+        }
+        if !state.done {
+            state.caught_up = false;
+            if state.pending.is_empty() {
+                state.read_batch(&path, &mut file, batch_size);
+            }
+        }
+        opened.push((path, file));
+    }
+
+    let mut entries = vec![];
+    while entries.len() < batch_size {
+        // the file with the earliest pending entry
+        let Some((path, file)) = opened
+            .iter_mut()
+            .filter(|(_, file)| !files[&file.file_id()].pending.is_empty())
+            .min_by_key(|(_, file)| EntryPosition::of(&files[&file.file_id()].pending[0]))
+        else {
+            break;
+        };
+        let state = files.get_mut(&file.file_id()).unwrap();
+        entries.extend(state.pending.pop_front());
+        // the next entries of the file may come before those of the others
+        if state.pending.is_empty() && !state.caught_up {
+            state.read_batch(path, file, batch_size);
+        }
+    }
+    Ok(entries)
+}
+
+fn to_log_entry(entry: &JournalEntry) -> LogEntry {
+    let mut values: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (name, value) in &entry.fields {
+        values
+            .entry(name)
+            .or_default()
+            .push(String::from_utf8_lossy(value).into_owned());
+    }
+    let fields = values
+        .into_iter()
+        .map(|(name, values)| (name.to_string(), join_values(values)))
+        .collect();
+    let timestamp = NaiveDateTime::from_timestamp_micros(entry.realtime as i64).unwrap_or_default();
+    log_entry(fields, timestamp)
+}
+
+impl JournalDirectoryLogSource {
+    pub fn new(config: &JournalDirectoryConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            directory: config.directory.clone(),
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            health: Mutex::new(SourceHealth::Starting),
+            last_position: Mutex::new(None),
+        }
+    }
+
+    fn set_health(&self, health: SourceHealth) {
+        *self.health.lock().unwrap() = health;
+    }
+}
+
+#[async_trait]
+impl LogSource for JournalDirectoryLogSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn health(&self) -> SourceHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn position(&self) -> Option<SourcePosition> {
+        self.last_position
+            .lock()
+            .unwrap()
+            .map(|p| SourcePosition::Cursor(p.to_string()))
+    }
+
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        position: Option<SourcePosition>,
+    ) -> Result<()> {
+        self.set_health(SourceHealth::Starting);
+
+        // without a position, the whole directory is read
+        let start = match position {
+            Some(SourcePosition::Cursor(cursor)) => Some(
+                EntryPosition::parse(&cursor)
+                    .ok_or_else(|| anyhow::format_err!("invalid cursor '{cursor}'"))?,
+            ),
+            Some(SourcePosition::Timestamp(t)) => Some(EntryPosition {
+                realtime: t.timestamp_micros() as u64,
+                seqnum: u64::MAX,
+            }),
+            None => None,
+        };
+
+        let mut files = FileStates::new();
+        loop {
+            let directory = self.directory.clone();
+            let (result, returned_files) = tokio::task::spawn_blocking(move || {
+                let result = poll(&directory, &mut files, BATCH_SIZE);
+                (result, files)
+            })
+            .await?;
+            files = returned_files;
+            self.set_health(SourceHealth::Running);
+
+            let entries = result?;
+            // a full batch may be followed by more entries already written
+            let caught_up = entries.len() < BATCH_SIZE;
+            for entry in entries {
+                let position = EntryPosition::of(&entry);
+                if start.is_some_and(|start| position <= start) {
+                    continue;
+                }
+                let checkpoint = Checkpoint {
+                    source: self.name.clone(),
+                    position: position.to_string(),
+                };
+                dispatcher
+                    .send_with_checkpoint(to_log_entry(&entry), checkpoint)
+                    .await;
+                *self.last_position.lock().unwrap() = Some(position);
+            }
+
+            if caught_up {
+                tokio::time::sleep(self.poll_interval).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use anyhow::Result;
+
+    use minink_common::{Level, LogEntry};
+
+    use crate::{
+        config::JournalDirectoryConfig,
+        logdispatcher::LogDispatcher,
+        source::{LogSource, SourcePosition},
+    };
+
+    use super::{poll, EntryPosition, FileStates, JournalDirectoryLogSource};
+
+    fn fixture(path: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/journal")
+            .join(path)
+    }
+
+    async fn follow(position: Option<SourcePosition>, n: usize) -> Result<Vec<LogEntry>> {
+        let config = JournalDirectoryConfig {
+            name: "copied".to_string(),
+            directory: fixture("compact"),
+            poll_interval_ms: 10,
+            multiline: None,
+        };
+        let source = Arc::new(JournalDirectoryLogSource::new(&config));
+        let dispatcher = Arc::new(LogDispatcher::new());
+        let mut stream = dispatcher.stream("test");
+        let job = tokio::spawn({
+            let source = source.clone();
+            async move { source.follow(dispatcher, position).await }
+        });
+
+        let mut entries = vec![];
+        for _ in 0..n {
+            let entry = tokio::time::timeout(Duration::from_secs(5), stream.pull_one()).await??;
+            entries.push(entry);
+        }
+        // nothing else comes
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), stream.pull_one())
+                .await
+                .is_err()
+        );
+        job.abort();
+        Ok(entries)
+    }
+
+    #[tokio::test]
+    async fn test_follow_directory() -> Result<()> {
+        let entries = follow(None, 13).await?;
+        assert!(entries.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert_eq!(entries[3].message, "hello from the fixture");
+        assert_eq!(entries[3].service, "fixture");
+        assert_eq!(entries[3].hostname, "vm");
+        assert_eq!(entries[4].level, Some(Level::Err));
+        assert_eq!(entries[6].level, None);
+        assert_eq!(entries[4].fields["REQUEST_ID"], "abc-123");
+        assert_eq!(entries[4].fields["SYSLOG_IDENTIFIER"], "webapp");
+        assert!(entries[4].fields.contains_key("_BOOT_ID"));
+        assert!(!entries[4].fields.contains_key("MESSAGE"));
+        // the last entries come from the online file
+        assert_eq!(entries[10].message, "after rotation");
+        assert_eq!(entries[12].message, "Journal stopped");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_cursor() -> Result<()> {
+        let entries = follow(None, 13).await?;
+        let timestamp = entries[10].timestamp.timestamp_micros();
+        let cursor = format!("t={timestamp:x};i=b");
+        let resumed = follow(Some(SourcePosition::Cursor(cursor)), 2).await?;
+        assert_eq!(resumed, entries[11..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_batches() -> Result<()> {
+        let all = poll(&fixture("compact"), &mut FileStates::new(), usize::MAX)?;
+        assert_eq!(all.len(), 13);
+
+        let mut files = FileStates::new();
+        let mut batches = vec![];
+        loop {
+            let batch = poll(&fixture("compact"), &mut files, 2)?;
+            assert!(batch.len() <= 2);
+            if batch.is_empty() {
+                break;
+            }
+            batches.extend(batch);
+        }
+        assert_eq!(batches, all);
+        assert!(all
+            .windows(2)
+            .all(|w| EntryPosition::of(&w[0]) <= EntryPosition::of(&w[1])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_retries_after_error() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("minink-journaldir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("system.journal");
+        let content = std::fs::read(fixture("compact/system.journal"))?;
+        // the first entry array is not an entry array anymore
+        let array = u64::from_le_bytes(content[176..184].try_into()?) as usize;
+        let mut corrupted = content.clone();
+        corrupted[array] = 0;
+        std::fs::write(&path, corrupted)?;
+
+        let mut files = FileStates::new();
+        assert!(poll(&dir, &mut files, 100)?.is_empty());
+        std::fs::write(&path, content)?;
+        let entries = poll(&dir, &mut files, 100)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(entries.len(), 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+//! Reader for the systemd journal file format,
+//! see https://systemd.io/JOURNAL_FILE_FORMAT/
+
+use std::{fs::File, io::Read, os::unix::fs::FileExt, path::Path};
+
+use anyhow::Result;
+
+const SIGNATURE: &[u8; 8] = b"LPKSHHRH";
+
+const HEADER_INCOMPATIBLE_COMPRESSED_XZ: u32 = 1 << 0;
+const HEADER_INCOMPATIBLE_COMPRESSED_LZ4: u32 = 1 << 1;
+const HEADER_INCOMPATIBLE_KEYED_HASH: u32 = 1 << 2;
+const HEADER_INCOMPATIBLE_COMPRESSED_ZSTD: u32 = 1 << 3;
+const HEADER_INCOMPATIBLE_COMPACT: u32 = 1 << 4;
+const HEADER_INCOMPATIBLE_SUPPORTED: u32 = HEADER_INCOMPATIBLE_COMPRESSED_XZ
+    | HEADER_INCOMPATIBLE_COMPRESSED_LZ4
+    | HEADER_INCOMPATIBLE_KEYED_HASH
+    | HEADER_INCOMPATIBLE_COMPRESSED_ZSTD
+    | HEADER_INCOMPATIBLE_COMPACT;
+
+const OBJECT_COMPRESSED_XZ: u8 = 1 << 0;
+const OBJECT_COMPRESSED_LZ4: u8 = 1 << 1;
+const OBJECT_COMPRESSED_ZSTD: u8 = 1 << 2;
+
+const OBJECT_DATA: u8 = 1;
+const OBJECT_ENTRY: u8 = 3;
+const OBJECT_ENTRY_ARRAY: u8 = 6;
+
+const OBJECT_HEADER_SIZE: u64 = 16;
+/// Header and `next_entry_array_offset` of an entry array.
+const ENTRY_ARRAY_MIN_SIZE: u64 = 24;
+/// Header, `seqnum`, `realtime`, `monotonic`, `boot_id` and `xor_hash` of an entry.
+const ENTRY_MIN_SIZE: u64 = 64;
+/// Header and fields of a data object before its payload, in regular and compact files.
+const DATA_PAYLOAD_OFFSET: u64 = 64;
+const DATA_PAYLOAD_OFFSET_COMPACT: u64 = 72;
+
+/// Largest ratio of an lz4 block, bounding the size it claims to decompress to.
+const LZ4_MAX_RATIO: usize = 255;
+/// Largest decompressed payload, a larger one is taken for a corrupted object.
+const MAX_PAYLOAD_SIZE: u64 = 16 * 1024 * 1024;
+
+const STATE_ARCHIVED: u8 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum JournalFileError {
+    #[error("not a journal file")]
+    InvalidSignature,
+    #[error("unsupported journal features: {0:#x}")]
+    UnsupportedFeatures(u32),
+    #[error("invalid object at offset {0}")]
+    InvalidObject(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Header {
+    incompatible_flags: u32,
+    state: u8,
+    file_id: [u8; 16],
+    entry_array_offset: u64,
+}
+
+impl Header {
+    fn parse(buf: &[u8; 184]) -> Result<Self> {
+        if &buf[0..8] != SIGNATURE {
+            return Err(JournalFileError::InvalidSignature.into());
+        }
+        let incompatible_flags = le32(buf, 12);
+        if incompatible_flags & !HEADER_INCOMPATIBLE_SUPPORTED != 0 {
+            return Err(JournalFileError::UnsupportedFeatures(incompatible_flags).into());
+        }
+        Ok(Self {
+            incompatible_flags,
+            state: buf[16],
+            file_id: buf[24..40].try_into().unwrap(),
+            entry_array_offset: le64(buf, 176),
+        })
+    }
+
+    fn compact(&self) -> bool {
+        self.incompatible_flags & HEADER_INCOMPATIBLE_COMPACT != 0
+    }
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// An entry of a journal file, with its fields in the order they were stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub seqnum: u64,
+    /// microseconds since the epoch
+    pub realtime: u64,
+    pub fields: Vec<(String, Vec<u8>)>,
+}
+
+/// Where to continue reading the entries of a file that is still being written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntryArrayPosition {
+    /// offset of the entry array being read, 0 if not started
+    array_offset: u64,
+    /// index of the next item to read in that array
+    index: u64,
+}
+
+#[derive(Debug)]
+pub struct JournalFile {
+    file: File,
+    header: Header,
+}
+
+impl JournalFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut buf = [0; 184];
+        (&file).read_exact(&mut buf)?;
+        let header = Header::parse(&buf)?;
+        Ok(Self { file, header })
+    }
+
+    pub fn file_id(&self) -> [u8; 16] {
+        self.header.file_id
+    }
+
+    /// Archived files are not written to anymore.
+    pub fn archived(&self) -> bool {
+        self.header.state == STATE_ARCHIVED
+    }
+
+    fn read_at(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len as usize];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    /// Read the object at `offset`, checking its type and that its size is
+    /// at least `min_size` and within the file, which may be corrupted.
+    fn read_object(&self, offset: u64, object_type: u8, min_size: u64) -> Result<Vec<u8>> {
+        let invalid = || JournalFileError::InvalidObject(offset);
+        let file_size = self.file.metadata()?.len();
+        if offset & 7 != 0 || offset.saturating_add(OBJECT_HEADER_SIZE) > file_size {
+            return Err(invalid().into());
+        }
+        let header = self.read_at(offset, OBJECT_HEADER_SIZE)?;
+        let size = le64(&header, 8);
+        if header[0] != object_type
+            || size < min_size.max(OBJECT_HEADER_SIZE)
+            || offset.saturating_add(size) > file_size
+        {
+            return Err(invalid().into());
+        }
+        self.read_at(offset, size)
+    }
+
+    /// Offsets of at most `limit` entries added since `position`, which is
+    /// advanced.
+    pub fn next_entry_offsets(
+        &mut self,
+        position: &mut EntryArrayPosition,
+        limit: usize,
+    ) -> Result<Vec<u64>> {
+        // the header is rewritten as the file grows
+        let mut buf = [0; 184];
+        self.file.read_exact_at(&mut buf, 0)?;
+        self.header = Header::parse(&buf)?;
+
+        if position.array_offset == 0 {
+            if self.header.entry_array_offset == 0 {
+                return Ok(vec![]);
+            }
+            position.array_offset = self.header.entry_array_offset;
+            position.index = 0;
+        }
+
+        let item_size = if self.header.compact() { 4 } else { 8 };
+        let mut offsets = vec![];
+        loop {
+            let array = self.read_object(
+                position.array_offset,
+                OBJECT_ENTRY_ARRAY,
+                ENTRY_ARRAY_MIN_SIZE,
+            )?;
+            let n_items = (array.len() as u64 - ENTRY_ARRAY_MIN_SIZE) / item_size;
+            while position.index < n_items {
+                if offsets.len() == limit {
+                    return Ok(offsets);
+                }
+                let at = (ENTRY_ARRAY_MIN_SIZE + position.index * item_size) as usize;
+                let offset = if self.header.compact() {
+                    le32(&array, at) as u64
+                } else {
+                    le64(&array, at)
+                };
+                if offset == 0 {
+                    // not written yet
+                    return Ok(offsets);
+                }
+                offsets.push(offset);
+                position.index += 1;
+            }
+            let next = le64(&array, 16);
+            if next == 0 {
+                return Ok(offsets);
+            }
+            // arrays are appended, a chain going back would loop
+            if next <= position.array_offset {
+                return Err(JournalFileError::InvalidObject(next).into());
+            }
+            position.array_offset = next;
+            position.index = 0;
+        }
+    }
+
+    pub fn read_entry(&self, offset: u64) -> Result<JournalEntry> {
+        let entry = self.read_object(offset, OBJECT_ENTRY, ENTRY_MIN_SIZE)?;
+        let seqnum = le64(&entry, 16);
+        let realtime = le64(&entry, 24);
+
+        let item_size = if self.header.compact() { 4 } else { 16 };
+        let fields = entry[ENTRY_MIN_SIZE as usize..]
+            .chunks_exact(item_size)
+            .map(|item| {
+                let data_offset = if self.header.compact() {
+                    le32(item, 0) as u64
+                } else {
+                    le64(item, 0)
+                };
+                self.read_data(data_offset)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(JournalEntry {
+            seqnum,
+            realtime,
+            fields,
+        })
+    }
+
+    fn read_data(&self, offset: u64) -> Result<(String, Vec<u8>)> {
+        let payload_offset = if self.header.compact() {
+            DATA_PAYLOAD_OFFSET_COMPACT
+        } else {
+            DATA_PAYLOAD_OFFSET
+        };
+        let data = self.read_object(offset, OBJECT_DATA, payload_offset)?;
+        let payload = decompress(data[1], &data[payload_offset as usize..])?;
+        let Some(separator) = payload.iter().position(|&c| c == b'=') else {
+            return Err(JournalFileError::InvalidObject(offset).into());
+        };
+        let name = String::from_utf8_lossy(&payload[..separator]).into_owned();
+        Ok((name, payload[separator + 1..].to_vec()))
+    }
+}
+
+fn decompress(flags: u8, payload: &[u8]) -> Result<Vec<u8>> {
+    if flags & OBJECT_COMPRESSED_XZ != 0 {
+        // lzma_rs decodes whole blocks in memory, so the size is checked first
+        let Some(size) = xz_uncompressed_size(payload) else {
+            anyhow::bail!("invalid xz payload");
+        };
+        if size > MAX_PAYLOAD_SIZE {
+            anyhow::bail!("xz payload larger than {MAX_PAYLOAD_SIZE} bytes");
+        }
+        let mut output = vec![];
+        lzma_rs::xz_decompress(&mut &payload[..], &mut output)?;
+        Ok(output)
+    } else if flags & OBJECT_COMPRESSED_LZ4 != 0 {
+        // the uncompressed size is stored before the lz4 block
+        if payload.len() < 8 {
+            anyhow::bail!("truncated lz4 payload");
+        }
+        let size = le64(payload, 0);
+        if size > (payload.len() * LZ4_MAX_RATIO) as u64 {
+            anyhow::bail!("invalid lz4 payload size {size}");
+        }
+        Ok(lz4_flex::block::decompress(&payload[8..], size as usize)?)
+    } else if flags & OBJECT_COMPRESSED_ZSTD != 0 {
+        let mut output = vec![];
+        zstd::stream::Decoder::with_buffer(payload)?
+            .take(MAX_PAYLOAD_SIZE + 1)
+            .read_to_end(&mut output)?;
+        if output.len() as u64 > MAX_PAYLOAD_SIZE {
+            anyhow::bail!("zstd payload larger than {MAX_PAYLOAD_SIZE} bytes");
+        }
+        Ok(output)
+    } else {
+        Ok(payload.to_vec())
+    }
+}
+
+/// Size of the data of an xz stream, summed from the headers of its LZMA2
+/// chunks, see https://tukaani.org/xz/xz-file-format.txt
+fn xz_uncompressed_size(payload: &[u8]) -> Option<u64> {
+    let be16 = |at: usize| {
+        Some(u16::from_be_bytes(
+            payload.get(at..at + 2)?.try_into().ok()?,
+        ))
+    };
+    let check_size = match payload.get(7)? & 0x0f {
+        0 => 0,
+        1 => 4,
+        4 => 8,
+        10 => 32,
+        _ => return None,
+    };
+    // after the stream header, blocks until the index, which starts with 0
+    let mut at = 12;
+    let mut size = 0;
+    while *payload.get(at)? != 0 {
+        let block = at;
+        at += (payload[at] as usize + 1) * 4;
+        loop {
+            let control = *payload.get(at)?;
+            match control {
+                0 => {
+                    at += 1;
+                    break;
+                }
+                // uncompressed chunk
+                1 | 2 => {
+                    let len = be16(at + 1)? as usize + 1;
+                    size += len as u64;
+                    at += 3 + len;
+                }
+                0x80.. => {
+                    size += ((control as u64 & 0x1f) << 16 | be16(at + 1)? as u64) + 1;
+                    let header_len = if control >= 0xc0 { 6 } else { 5 };
+                    at += header_len + be16(at + 3)? as usize + 1;
+                }
+                _ => return None,
+            }
+        }
+        // padding of the block to a multiple of 4, then its check
+        at += (4 - (at - block) % 4) % 4 + check_size;
+    }
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use anyhow::Result;
+
+    use super::{
+        decompress, xz_uncompressed_size, EntryArrayPosition, JournalEntry, JournalFile,
+        JournalFileError, MAX_PAYLOAD_SIZE,
+    };
+
+    impl JournalEntry {
+        fn field(&self, name: &str) -> Option<String> {
+            self.fields
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+        }
+    }
+
+    fn fixture(path: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/journal")
+            .join(path)
+    }
+
+    fn read_all(path: &str) -> Result<Vec<JournalEntry>> {
+        let mut file = JournalFile::open(&fixture(path))?;
+        let mut position = EntryArrayPosition::default();
+        file.next_entry_offsets(&mut position, usize::MAX)?
+            .into_iter()
+            .map(|offset| file.read_entry(offset))
+            .collect()
+    }
+
+    fn check_entries(entries: &[JournalEntry]) {
+        let messages = entries
+            .iter()
+            .filter(|e| e.field("SYSLOG_IDENTIFIER").as_deref() != Some("systemd-journald"))
+            .map(|e| e.field("MESSAGE").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(messages[0], "hello from the fixture");
+        assert_eq!(messages[1], "request failed");
+        // long enough to be compressed
+        assert!(messages[2].starts_with("Traceback (most recent call last):\n"));
+        assert!(messages[2].ends_with("\nValueError: boom"));
+        assert_eq!(messages[3], "binary \0\u{1} message");
+
+        let failed = entries
+            .iter()
+            .find(|e| e.field("MESSAGE").as_deref() == Some("request failed"))
+            .unwrap();
+        assert_eq!(failed.field("REQUEST_ID").unwrap(), "abc-123");
+        assert_eq!(failed.field("PRIORITY").unwrap(), "3");
+        assert_eq!(failed.field("_HOSTNAME").unwrap(), "vm");
+    }
+
+    #[test]
+    fn test_read_regular() -> Result<()> {
+        let entries = read_all("regular/system.journal")?;
+        assert_eq!(entries.len(), 8);
+        check_entries(&entries);
+        assert!(entries.windows(2).all(|w| w[0].seqnum < w[1].seqnum));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_compact_archived() -> Result<()> {
+        let path =
+            "compact/system@57aa67b925c1407da87658d1414786b4-0000000000000001-00065e0005381975.journal";
+        assert!(JournalFile::open(&fixture(path))?.archived());
+        let entries = read_all(path)?;
+        assert_eq!(entries.len(), 7);
+        check_entries(&entries);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_compact_incremental() -> Result<()> {
+        let mut file = JournalFile::open(&fixture("compact/system.journal"))?;
+        let mut position = EntryArrayPosition::default();
+        let mut offsets = file.next_entry_offsets(&mut position, 4)?;
+        assert_eq!(offsets.len(), 4);
+        offsets.extend(file.next_entry_offsets(&mut position, 4)?);
+        assert_eq!(offsets.len(), 6);
+        assert!(file.next_entry_offsets(&mut position, 4)?.is_empty());
+        let entry = file.read_entry(offsets[4])?;
+        assert_eq!(entry.field("MESSAGE").unwrap(), "last entry");
+        Ok(())
+    }
+
+    /// A copy of the regular fixture, changed by `corrupt`, such as the journal
+    /// of a machine that crashed.
+    fn corrupted(name: &str, corrupt: impl FnOnce(&mut Vec<u8>)) -> Result<JournalFile> {
+        let mut content = std::fs::read(fixture("regular/system.journal"))?;
+        corrupt(&mut content);
+        let dir = std::env::temp_dir().join(format!("minink-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{name}.journal"));
+        std::fs::write(&path, content)?;
+        let file = JournalFile::open(&path);
+        std::fs::remove_file(&path)?;
+        file
+    }
+
+    fn is_invalid_object<T>(result: Result<T>) -> bool {
+        result.is_err_and(|err| {
+            matches!(err.downcast_ref(), Some(JournalFileError::InvalidObject(_)))
+        })
+    }
+
+    #[test]
+    fn test_corrupted() -> Result<()> {
+        let mut original = JournalFile::open(&fixture("regular/system.journal"))?;
+        let array = original.header.entry_array_offset as usize;
+        let first_entry =
+            original.next_entry_offsets(&mut EntryArrayPosition::default(), usize::MAX)?[0];
+        let set_size = |at: usize, size: u64| {
+            move |content: &mut Vec<u8>| {
+                content[at + 8..at + 16].copy_from_slice(&size.to_le_bytes())
+            }
+        };
+
+        for (name, size) in [("huge", u64::MAX), ("beyond", 1 << 40), ("short", 20)] {
+            let mut file = corrupted(name, set_size(array, size))?;
+            let offsets = file.next_entry_offsets(&mut EntryArrayPosition::default(), usize::MAX);
+            assert!(is_invalid_object(offsets), "{name}");
+        }
+        let mut file = corrupted("truncated", |content| content.truncate(array + 20))?;
+        let offsets = file.next_entry_offsets(&mut EntryArrayPosition::default(), usize::MAX);
+        assert!(is_invalid_object(offsets));
+
+        for (name, next) in [("loop", array as u64), ("back", 8)] {
+            let mut file = corrupted(name, |content| {
+                content[array + 16..array + 24].copy_from_slice(&next.to_le_bytes())
+            })?;
+            let offsets = file.next_entry_offsets(&mut EntryArrayPosition::default(), usize::MAX);
+            assert!(is_invalid_object(offsets), "{name}");
+        }
+
+        let file = corrupted("entry", set_size(first_entry as usize, 40))?;
+        assert!(is_invalid_object(file.read_entry(first_entry)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_a_journal() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        assert!(JournalFile::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_decompress() -> Result<()> {
+        let data = b"MESSAGE=hello hello hello hello hello".to_vec();
+
+        assert_eq!(decompress(0, &data)?, data);
+
+        let mut lz4 = (data.len() as u64).to_le_bytes().to_vec();
+        lz4.extend(lz4_flex::block::compress(&data));
+        assert_eq!(decompress(2, &lz4)?, data);
+
+        let mut xz = vec![];
+        lzma_rs::xz_compress(&mut &data[..], &mut xz)?;
+        assert_eq!(decompress(1, &xz)?, data);
+        assert_eq!(xz_uncompressed_size(&xz), Some(data.len() as u64));
+
+        let zstd = zstd::stream::encode_all(&data[..], 0)?;
+        assert_eq!(decompress(4, &zstd)?, data);
+
+        // a corrupted size is not allocated
+        let mut lz4 = u64::MAX.to_le_bytes().to_vec();
+        lz4.extend(lz4_flex::block::compress(&data));
+        assert!(decompress(2, &lz4).is_err());
+        assert!(decompress(1, &xz[..xz.len() / 2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_too_large() -> Result<()> {
+        let data = vec![0; MAX_PAYLOAD_SIZE as usize + 1];
+        let zstd = zstd::stream::encode_all(&data[..], 0)?;
+        assert!(zstd.len() < 4096);
+        assert!(decompress(4, &zstd).is_err());
+        Ok(())
+    }
+}
@@ -1,35 +1,406 @@
-use std::sync::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
-use tokio::sync::mpsc::UnboundedSender;
+use serde::{Deserialize, Serialize};
+
+use tokio::sync::Notify;
 
 use minink_common::LogEntry;
 
 use crate::logstream::LogStream;
 
+/// Queue size of the internal subscribers, such as the database.
+const DEFAULT_QUEUE_SIZE: usize = 16 * 1024;
+
+/// Position of a source right after a given entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub source: String,
+    pub position: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub entry: LogEntry,
+    pub checkpoint: Option<Checkpoint>,
+    /// already written to the database, only for the live clients and the outputs
+    pub stored: bool,
+}
+
+/// What happens when the queue of a subscriber is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// the sources wait until the subscriber pulled some entries
+    #[default]
+    Block,
+    /// the oldest entry of the queue is dropped
+    DropOldest,
+    /// the subscriber is disconnected
+    Disconnect,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    records: VecDeque<LogRecord>,
+    /// no more records are pushed, the remaining ones can still be pulled
+    closed: bool,
+    /// closed because it was full, with the Disconnect policy
+    overflowed: bool,
+    dropped: u64,
+}
+
+/// Bounded queue between the dispatcher and a single subscriber.
+#[derive(Debug)]
+pub struct Queue {
+    name: String,
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<QueueState>,
+    /// a record was pushed, or the queue was closed
+    pushed: Notify,
+    /// a record was pulled, or the queue was closed
+    pulled: Notify,
+}
+
+/// Closed queue, nothing can be sent to it anymore.
+#[derive(Debug)]
+struct Closed;
+
+impl Queue {
+    /// Push without waiting, the record is given back if the queue is full
+    /// with the Block policy.
+    fn try_push(&self, record: LogRecord) -> Result<Option<LogRecord>, Closed> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        if state.records.len() < self.capacity {
+            state.records.push_back(record);
+            self.pushed.notify_one();
+            return Ok(None);
+        }
+        match self.policy {
+            OverflowPolicy::Block => Ok(Some(record)),
+            OverflowPolicy::DropOldest => {
+                state.records.pop_front();
+                state.records.push_back(record);
+                state.dropped += 1;
+                self.pushed.notify_one();
+                Ok(None)
+            }
+            OverflowPolicy::Disconnect => {
+                state.dropped += state.records.len() as u64 + 1;
+                state.records.clear();
+                state.closed = true;
+                state.overflowed = true;
+                self.pushed.notify_one();
+                Err(Closed)
+            }
+        }
+    }
+
+    async fn push(&self, mut record: LogRecord) -> Result<(), Closed> {
+        loop {
+            // registered before the state is checked, so that a pull or a
+            // close in between wakes it up, including `notify_waiters`
+            let pulled = self.pulled.notified();
+            tokio::pin!(pulled);
+            pulled.as_mut().enable();
+            match self.try_push(record)? {
+                None => return Ok(()),
+                Some(full) => record = full,
+            }
+            pulled.await;
+        }
+    }
+
+    /// The next record, or None once the queue is closed and empty.
+    pub async fn pull(&self) -> Option<LogRecord> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(record) = state.records.pop_front() {
+                    self.pulled.notify_one();
+                    return Some(record);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.pushed.notified().await;
+        }
+    }
+
+    /// The next record if one is queued, without waiting.
+    pub fn try_pull(&self) -> Option<LogRecord> {
+        let record = self.state.lock().unwrap().records.pop_front()?;
+        self.pulled.notify_one();
+        Some(record)
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.pushed.notify_one();
+        // every blocked source must see it
+        self.pulled.notify_waiters();
+    }
+
+    /// Number of entries dropped since the subscriber was created.
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.state.lock().unwrap().overflowed
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriberStatus {
+    pub name: String,
+    pub queued: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+}
+
 #[derive(Debug)]
 pub struct LogDispatcher {
-    senders: Mutex<Vec<UnboundedSender<LogEntry>>>,
+    queues: Mutex<Vec<Arc<Queue>>>,
 }
 
 impl LogDispatcher {
     pub fn new() -> LogDispatcher {
         LogDispatcher {
-            senders: Mutex::new(vec![]),
+            queues: Mutex::new(vec![]),
         }
     }
 
-    pub fn send(&self, entry: LogEntry) {
-        self.senders
+    /// Send an entry of a source that cannot resume from a position.
+    pub async fn send(&self, entry: LogEntry) {
+        self.send_record(LogRecord {
+            entry,
+            checkpoint: None,
+            stored: false,
+        })
+        .await;
+    }
+
+    /// Send an entry that was already written to the database.
+    pub async fn send_stored(&self, entry: LogEntry) {
+        self.send_record(LogRecord {
+            entry,
+            checkpoint: None,
+            stored: true,
+        })
+        .await;
+    }
+
+    pub async fn send_with_checkpoint(&self, entry: LogEntry, checkpoint: Checkpoint) {
+        self.send_record(LogRecord {
+            entry,
+            checkpoint: Some(checkpoint),
+            stored: false,
+        })
+        .await;
+    }
+
+    /// Waits while a subscriber with the Block policy has a full queue, once
+    /// the other subscribers received the record.
+    pub async fn send_record(&self, record: LogRecord) {
+        let queues = self.queues.lock().unwrap().clone();
+        let mut full = vec![];
+        for queue in queues {
+            match queue.try_push(record.clone()) {
+                Ok(None) => {}
+                Ok(Some(_)) => full.push(queue),
+                Err(Closed) => self.remove(&queue),
+            }
+        }
+        for queue in full {
+            if queue.push(record.clone()).await.is_err() {
+                self.remove(&queue);
+            }
+        }
+    }
+
+    fn remove(&self, queue: &Arc<Queue>) {
+        self.queues
             .lock()
             .unwrap()
-            .retain(|sender| sender.send(entry.clone()).is_ok());
+            .retain(|q| !Arc::ptr_eq(q, queue));
+    }
+
+    /// Close all the streams, once they received the entries already sent.
+    pub fn close(&self) {
+        for queue in self.queues.lock().unwrap().drain(..) {
+            queue.close();
+        }
+    }
+
+    /// A stream that blocks the sources when it is too slow.
+    pub fn stream(&self, name: &str) -> LogStream {
+        self.stream_with(name, DEFAULT_QUEUE_SIZE, OverflowPolicy::Block)
+    }
+
+    pub fn stream_with(&self, name: &str, capacity: usize, policy: OverflowPolicy) -> LogStream {
+        let queue = Arc::new(Queue {
+            name: name.to_string(),
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::new(QueueState::default()),
+            pushed: Notify::new(),
+            pulled: Notify::new(),
+        });
+
+        self.queues.lock().unwrap().push(queue.clone());
+
+        LogStream::new(queue)
+    }
+
+    pub fn subscribers(&self) -> Vec<SubscriberStatus> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|queue| {
+                let state = queue.state.lock().unwrap();
+                SubscriberStatus {
+                    name: queue.name.clone(),
+                    queued: state.records.len(),
+                    capacity: queue.capacity,
+                    dropped: state.dropped,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Like a closed channel, the streams end once the sources dropped the dispatcher.
+impl Drop for LogDispatcher {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveDateTime;
+
+    use minink_common::LogEntry;
+
+    use super::{LogDispatcher, OverflowPolicy};
+
+    fn entry(message: &str) -> LogEntry {
+        LogEntry {
+            message: message.to_string(),
+            hostname: "localhost".to_string(),
+            service: "test".to_string(),
+            timestamp: NaiveDateTime::default(),
+            level: None,
+            fields: Default::default(),
+        }
     }
 
-    pub fn stream(&self) -> LogStream {
-        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let dispatcher = LogDispatcher::new();
+        let mut stream = dispatcher.stream_with("slow", 2, OverflowPolicy::DropOldest);
+        for message in ["a", "b", "c"] {
+            dispatcher.send(entry(message)).await;
+        }
+        assert_eq!(stream.dropped(), 1);
+        assert_eq!(dispatcher.subscribers()[0].dropped, 1);
+        assert_eq!(stream.pull_one().await.unwrap().message, "b");
+        assert_eq!(stream.pull_one().await.unwrap().message, "c");
+    }
 
-        self.senders.lock().unwrap().push(sender);
+    #[tokio::test]
+    async fn test_disconnect() {
+        let dispatcher = LogDispatcher::new();
+        let mut stream = dispatcher.stream_with("slow", 2, OverflowPolicy::Disconnect);
+        let mut other = dispatcher.stream("other");
+        for message in ["a", "b", "c", "d"] {
+            dispatcher.send(entry(message)).await;
+        }
+        assert!(stream.pull_one().await.is_err());
+        assert!(stream.overflowed());
+        assert_eq!(dispatcher.subscribers().len(), 1);
+        assert_eq!(other.pull_one().await.unwrap().message, "a");
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let dispatcher = std::sync::Arc::new(LogDispatcher::new());
+        let mut stream = dispatcher.stream_with("slow", 1, OverflowPolicy::Block);
+        dispatcher.send(entry("a")).await;
+        let send = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.send(entry("b")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!send.is_finished());
+
+        assert_eq!(stream.pull_one().await.unwrap().message, "a");
+        send.await.unwrap();
+        assert_eq!(stream.pull_one().await.unwrap().message, "b");
+
+        // a dropped subscriber does not block the sources anymore
+        dispatcher.send(entry("c")).await;
+        drop(stream);
+        dispatcher.send(entry("d")).await;
+        assert!(dispatcher.subscribers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_block_others_receive() {
+        let dispatcher = std::sync::Arc::new(LogDispatcher::new());
+        let mut slow = dispatcher.stream_with("slow", 1, OverflowPolicy::Block);
+        let mut live = dispatcher.stream_with("live", 10, OverflowPolicy::DropOldest);
+        dispatcher.send(entry("a")).await;
+        let send = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.send(entry("b")).await }
+        });
+        // not delayed by the full queue before it
+        assert_eq!(live.pull_one().await.unwrap().message, "a");
+        assert_eq!(live.pull_one().await.unwrap().message, "b");
+        assert!(!send.is_finished());
+
+        assert_eq!(slow.pull_one().await.unwrap().message, "a");
+        send.await.unwrap();
+        assert_eq!(slow.pull_one().await.unwrap().message, "b");
+    }
+
+    #[tokio::test]
+    async fn test_close_wakes_blocked() {
+        let dispatcher = std::sync::Arc::new(LogDispatcher::new());
+        let _stream = dispatcher.stream_with("slow", 1, OverflowPolicy::Block);
+        dispatcher.send(entry("a")).await;
+        let send = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.send(entry("b")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // nothing is pulled anymore
+        dispatcher.close();
+        tokio::time::timeout(Duration::from_secs(5), send)
+            .await
+            .unwrap()
+            .unwrap();
+    }
 
-        LogStream::new(receiver)
+    #[tokio::test]
+    async fn test_close_drains() {
+        let dispatcher = LogDispatcher::new();
+        let mut stream = dispatcher.stream("database");
+        dispatcher.send(entry("a")).await;
+        dispatcher.close();
+        assert_eq!(stream.pull_one().await.unwrap().message, "a");
+        assert!(stream.pull_one().await.is_err());
     }
 }
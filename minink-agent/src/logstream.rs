@@ -1,41 +1,76 @@
-use anyhow::Result;
+use std::sync::Arc;
 
-use tokio::sync::mpsc::UnboundedReceiver;
+use anyhow::Result;
 
 use minink_common::{Filter, LogEntry};
 
+use crate::logdispatcher::{LogRecord, Queue};
+
 #[derive(thiserror::Error, Debug)]
 #[error("LogStream closed")]
 pub struct ClosedStream {}
 
 pub struct LogStream {
-    receiver: UnboundedReceiver<LogEntry>,
+    queue: Arc<Queue>,
     filter: Filter,
 }
 
 impl LogStream {
-    pub fn new(receiver: UnboundedReceiver<LogEntry>) -> Self {
+    pub fn new(queue: Arc<Queue>) -> Self {
         let filter = Filter::default();
-        Self { receiver, filter }
+        Self { queue, filter }
     }
 
-    pub fn with_filter(self, filter: Filter) -> Self {
-        Self {
-            receiver: self.receiver,
-            filter,
-        }
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
     }
 
     pub async fn pull_one(&mut self) -> Result<LogEntry, ClosedStream> {
+        Ok(self.pull_record().await?.entry)
+    }
+
+    pub async fn pull_record(&mut self) -> Result<LogRecord, ClosedStream> {
         loop {
-            match self.receiver.recv().await {
-                Some(entry) => {
-                    if self.filter.accept(&entry) {
-                        return Ok(entry);
+            match self.queue.pull().await {
+                Some(record) => {
+                    if self.filter.accept(&record.entry) {
+                        return Ok(record);
                     }
                 }
                 None => return Err(ClosedStream {}),
             }
         }
     }
+
+    /// The records already queued, at least one and at most `max`; only
+    /// waits for the first one.
+    pub async fn pull_batch(&mut self, max: usize) -> Result<Vec<LogRecord>, ClosedStream> {
+        let mut records = vec![self.pull_record().await?];
+        while records.len() < max {
+            let Some(record) = self.queue.try_pull() else {
+                break;
+            };
+            if self.filter.accept(&record.entry) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Number of entries lost because the stream was too slow.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    /// The stream was disconnected because it was too slow.
+    pub fn overflowed(&self) -> bool {
+        self.queue.overflowed()
+    }
+}
+
+impl Drop for LogStream {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
 }
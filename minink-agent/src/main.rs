@@ -1,24 +1,56 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use journald::JournaldLogSource;
+use config::Config;
+
+use logdispatcher::LogDispatcher;
 
 use logstream::LogStream;
 
 use server::ServerArgs;
 
+use source::SourcePosition;
+
+use supervisor::SourceSupervisor;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+};
+
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod archive;
+mod config;
 mod database;
+mod filetail;
+mod forward;
 mod journald;
+mod journaldir;
+mod journalfile;
 mod logdispatcher;
 mod logstream;
+mod memory;
+mod multiline;
+mod partition;
+mod retention;
 mod server;
+mod source;
+mod stdin;
+mod store;
+mod supervisor;
+mod syslog;
 
-use database::LogDatabase;
+use database::{Durability, LogDatabase, WriteOptions};
+
+use memory::MemoryStore;
+
+use partition::Partitioning;
+
+use store::{LogStore, StoreKind};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -28,21 +60,51 @@ struct Args {
     port: u16,
     #[arg(short, long)]
     assets_dir: Option<PathBuf>,
+    /// TOML file describing the log sources (defaults to a single journald source)
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// pending entries are written to the database at least that often
+    #[arg(long, default_value = "1000")]
+    flush_interval_ms: u64,
+    #[arg(long, value_enum, default_value_t = Durability::default())]
+    durability: Durability,
+    /// split the entries between database files by their timestamp
+    #[arg(long, value_enum, default_value_t = Partitioning::default())]
+    partition: Partitioning,
+    #[arg(long, value_enum, default_value_t = StoreKind::default())]
+    store: StoreKind,
+    /// entries kept by `--store memory`
+    #[arg(long, default_value = "100000")]
+    memory_entries: usize,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn ingest_logs_job(db: LogDatabase, mut logstream: LogStream) -> Result<()> {
-    loop {
-        let entry = logstream.pull_one().await?;
-        db.add_log(entry).await?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that every entry is indexed with its own service and message, then exit
+    Check,
+}
+
+/// Entries given to the store at once by the ingestion, at most.
+const INGEST_BATCH_SIZE: usize = 1024;
+
+/// Store the entries until the dispatcher is closed.
+async fn ingest_logs_job(store: Arc<dyn LogStore>, mut logstream: LogStream) -> Result<()> {
+    while let Ok(records) = logstream.pull_batch(INGEST_BATCH_SIZE).await {
+        store.insert(records).await?;
     }
+    Ok(())
 }
 
-async fn flatten<T>(handle: tokio::task::JoinHandle<Result<T>>) -> Result<T> {
-    match handle.await {
-        Ok(Ok(result)) => Ok(result),
-        Ok(Err(err)) => Err(err),
-        Err(err) => Err(err.into()),
+/// Completes on SIGTERM or SIGINT.
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {},
     }
+    Ok(())
 }
 
 #[tokio::main]
@@ -57,21 +119,143 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let database = LogDatabase::new(&args.database_path).await?;
-    let last_timestamp = database.last_timestamp().await?;
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // the archives hold whole partitions
+    anyhow::ensure!(
+        config.archive.is_none() || args.partition != Partitioning::None,
+        "archiving needs --partition day or hour"
+    );
 
-    let (logsource, dispatcher) = JournaldLogSource::new();
+    let write_options = WriteOptions {
+        durability: args.durability,
+        flush_interval: Duration::from_millis(args.flush_interval_ms),
+        partitioning: args.partition,
+    };
+    let (store, database): (Arc<dyn LogStore>, _) = match args.store {
+        StoreKind::Sqlite => {
+            let database = LogDatabase::new(&args.database_path, write_options).await?;
+            (Arc::new(database.clone()), Some(database))
+        }
+        StoreKind::Memory => {
+            anyhow::ensure!(
+                config.retention.is_none() && config.archive.is_none(),
+                "the retention and the archives need --store sqlite"
+            );
+            anyhow::ensure!(args.memory_entries > 0, "--memory-entries must be positive");
+            (Arc::new(MemoryStore::new(args.memory_entries)), None)
+        }
+    };
+    if let Some(Command::Check) = args.command {
+        let Some(database) = database else {
+            anyhow::bail!("only --store sqlite can be checked");
+        };
+        let problems = database.check().await?;
+        database.close().await?;
+        for problem in &problems {
+            tracing::error!("{}", problem);
+        }
+        anyhow::ensure!(problems.is_empty(), "the database is inconsistent");
+        tracing::info!("the database is consistent");
+        return Ok(());
+    }
+    let last_timestamp = store.last_timestamp().await?;
+
+    let dispatcher = Arc::new(LogDispatcher::new());
 
-    let j1 = tokio::spawn(ingest_logs_job(database.clone(), dispatcher.stream()));
-    let j2 = tokio::spawn(logsource.follow(last_timestamp));
+    let mut ingest = tokio::spawn(ingest_logs_job(
+        store.clone(),
+        dispatcher.stream("database"),
+    ));
+
+    let forwarder = match config.forward {
+        Some(forward) => {
+            let forwarder = forward::Forwarder::new(forward).await?;
+            Some(tokio::spawn(forwarder.run(dispatcher.stream("forward"))))
+        }
+        None => None,
+    };
+
+    let retention = config
+        .retention
+        .zip(database.clone())
+        .map(|(retention, database)| {
+            tokio::spawn(retention::Retention::new(database, retention).run())
+        });
+    let archiver = config
+        .archive
+        .zip(database)
+        .map(|(archive, database)| tokio::spawn(archive::Archiver::new(database, archive).run()));
+
+    let supervisors = config
+        .sources
+        .iter()
+        .map(|c| Arc::new(SourceSupervisor::new(c.build(), config.restart.clone())))
+        .collect::<Vec<_>>();
+    let mut sources = JoinSet::new();
+    for supervisor in &supervisors {
+        let position = match store.source_position(supervisor.name()).await? {
+            Some(cursor) => Some(SourcePosition::Cursor(cursor)),
+            None => last_timestamp.map(SourcePosition::Timestamp),
+        };
+        let supervisor = supervisor.clone();
+        let dispatcher = dispatcher.clone();
+        tracing::info!("starting source {}", supervisor.name());
+        // a failing source never stops the agent, it is restarted or marked as failed
+        sources.spawn(async move { supervisor.run(dispatcher, position).await });
+    }
 
     let server_args = ServerArgs {
         port: args.port,
         assets_dir: args.assets_dir,
+        live: config.live.clone(),
     };
-    let j3 = tokio::spawn(server::main(dispatcher, database, server_args));
+    let (stop_server, server_stopped) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(server::main(
+        dispatcher,
+        store.clone(),
+        supervisors,
+        server_args,
+        async {
+            let _ = server_stopped.await;
+        },
+    ));
+
+    tokio::select! {
+        result = shutdown_signal() => result?,
+        result = &mut server => {
+            result??;
+            anyhow::bail!("server stopped");
+        },
+        result = &mut ingest => {
+            result??;
+            anyhow::bail!("ingestion stopped");
+        },
+    }
+
+    tracing::info!("stopping");
+    // no new entries once the sources are stopped and the server answered
+    // the ingest requests in progress, then it closes the dispatcher; the
+    // streams end once the entries already dispatched are pulled
+    sources.shutdown().await;
+    let _ = stop_server.send(());
+    server.await??;
 
-    tokio::try_join!(flatten(j1), flatten(j2), flatten(j3))?;
+    ingest.await??;
+    if let Some(retention) = retention {
+        retention.abort();
+    }
+    if let Some(archiver) = archiver {
+        archiver.abort();
+    }
+    store.close().await?;
+    if let Some(forwarder) = forwarder {
+        forwarder.await??;
+    }
+    tracing::info!("stopped");
 
     Ok(())
 }
@@ -0,0 +1,272 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use chrono::NaiveDateTime;
+
+use minink_common::{ExtractPage, Filter, GroupBy, Histogram, LogEntry, Order};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    database::{Cursor, Page, MAX_PAGE_SIZE},
+    forward::ForwardedBatch,
+    logdispatcher::LogRecord,
+    store::{HistogramCounts, LogStore, StoreStats},
+};
+
+/// Entries read ahead of an export.
+const EXPORT_BUFFER: usize = 1024;
+
+/// The last entries received, in memory, for the hosts without a writable
+/// disk. The oldest received entries are dropped beyond the capacity, and
+/// everything is lost when the agent stops.
+#[derive(Debug)]
+pub struct MemoryStore {
+    capacity: usize,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// in the order they were received, with their id
+    entries: VecDeque<(i64, LogEntry)>,
+    /// of the next entry, the ids tell apart the entries with the same timestamp
+    next_id: i64,
+    /// estimated size of the entries
+    bytes: u64,
+    positions: BTreeMap<String, String>,
+}
+
+/// Approximate memory used by an entry.
+fn entry_size(entry: &LogEntry) -> u64 {
+    let fields: usize = entry
+        .fields
+        .iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum();
+    (std::mem::size_of::<(i64, LogEntry)>()
+        + entry.message.len()
+        + entry.hostname.len()
+        + entry.service.len()
+        + fields) as u64
+}
+
+fn cursor(id: i64, entry: &LogEntry) -> Cursor {
+    Cursor {
+        timestamp: entry.timestamp.timestamp_micros(),
+        id,
+    }
+}
+
+impl MemoryState {
+    fn push(&mut self, entry: LogEntry, capacity: usize) {
+        self.next_id += 1;
+        self.bytes += entry_size(&entry);
+        self.entries.push_back((self.next_id, entry));
+        while self.entries.len() > capacity {
+            if let Some((_, dropped)) = self.entries.pop_front() {
+                self.bytes -= entry_size(&dropped);
+            }
+        }
+    }
+
+    /// The entries matching `filter` after `after` in `order`, sorted.
+    fn matching(
+        &self,
+        filter: &Filter,
+        order: Order,
+        after: Option<Cursor>,
+    ) -> Vec<(Cursor, &LogEntry)> {
+        let position = |cursor: Cursor| (cursor.timestamp, cursor.id);
+        let mut rows: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(id, entry)| (cursor(*id, entry), entry))
+            .filter(|(cursor, entry)| {
+                let is_after = after.is_none_or(|after| match order {
+                    Order::Asc => position(*cursor) > position(after),
+                    Order::Desc => position(*cursor) < position(after),
+                });
+                is_after && filter.accept(entry)
+            })
+            .collect();
+        rows.sort_by_key(|(cursor, _)| position(*cursor));
+        if order == Order::Desc {
+            rows.reverse();
+        }
+        rows
+    }
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl LogStore for MemoryStore {
+    async fn insert(&self, records: Vec<LogRecord>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for record in records {
+            if let Some(checkpoint) = record.checkpoint {
+                state
+                    .positions
+                    .insert(checkpoint.source, checkpoint.position);
+            }
+            if !record.stored {
+                state.push(record.entry, self.capacity);
+            }
+        }
+        Ok(())
+    }
+
+    async fn insert_forwarded(&self, batch: &ForwardedBatch, entries: &[LogEntry]) -> Result<bool> {
+        let source = batch.source();
+        let mut state = self.state.lock().unwrap();
+        if batch.is_stored(state.positions.get(&source).map(String::as_str)) {
+            return Ok(false);
+        }
+        for entry in entries {
+            state.push(entry.clone(), self.capacity);
+        }
+        state.positions.insert(source, batch.position());
+        Ok(true)
+    }
+
+    async fn extract(&self, filter: &Filter, page: &Page) -> Result<ExtractPage> {
+        let size = page.size.clamp(1, MAX_PAGE_SIZE);
+        let state = self.state.lock().unwrap();
+        let rows = state.matching(filter, page.order, page.after);
+        // the cursor of the last entry of the page, if there are more
+        let next = (rows.len() > size).then(|| rows[size - 1].0.to_string());
+        Ok(ExtractPage {
+            entries: rows
+                .into_iter()
+                .take(size)
+                .map(|(_, entry)| entry.clone())
+                .collect(),
+            next,
+        })
+    }
+
+    fn export(&self, filter: Filter) -> mpsc::Receiver<Result<LogEntry>> {
+        let (sender, receiver) = mpsc::channel(EXPORT_BUFFER);
+        let entries: Vec<LogEntry> = {
+            let state = self.state.lock().unwrap();
+            let rows = state.matching(&filter, Order::Asc, None);
+            rows.into_iter().map(|(_, entry)| entry.clone()).collect()
+        };
+        tokio::spawn(async move {
+            for entry in entries {
+                if sender.send(Ok(entry)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+
+    async fn source_position(&self, source: &str) -> Result<Option<String>> {
+        Ok(self.state.lock().unwrap().positions.get(source).cloned())
+    }
+
+    async fn last_timestamp(&self) -> Result<Option<NaiveDateTime>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.entries.iter().map(|(_, entry)| entry.timestamp).max())
+    }
+
+    async fn histogram(
+        &self,
+        filter: &Filter,
+        interval_ms: u64,
+        group_by: Option<GroupBy>,
+    ) -> Result<Histogram> {
+        let mut counts = HistogramCounts::new(interval_ms, group_by);
+        let state = self.state.lock().unwrap();
+        for (_, entry) in &state.entries {
+            if filter.accept(entry) {
+                counts.add_entry(entry)?;
+            }
+        }
+        Ok(counts.finish())
+    }
+
+    async fn stats(&self) -> Result<StoreStats> {
+        let state = self.state.lock().unwrap();
+        Ok(StoreStats {
+            entries: state.entries.len() as u64,
+            bytes: state.bytes,
+        })
+    }
+
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::NaiveDateTime;
+
+    use minink_common::{Filter, LogEntry, Order};
+
+    use crate::{
+        database::Page,
+        logdispatcher::{Checkpoint, LogRecord},
+        store::LogStore,
+    };
+
+    use super::MemoryStore;
+
+    fn record(i: i64) -> LogRecord {
+        LogRecord {
+            entry: LogEntry {
+                message: format!("line {i}"),
+                hostname: "kiosk-1".to_string(),
+                service: "app".to_string(),
+                timestamp: NaiveDateTime::from_timestamp_opt(1_682_380_800 + i, 0).unwrap(),
+                level: None,
+                fields: Default::default(),
+            },
+            checkpoint: Some(Checkpoint {
+                source: "journald".to_string(),
+                position: format!("s={i}"),
+            }),
+            stored: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capacity() -> Result<()> {
+        let store = MemoryStore::new(3);
+        store.insert((0..5).map(record).collect()).await?;
+
+        let stats = store.stats().await?;
+        assert_eq!(stats.entries, 3);
+        let page = Page {
+            size: 10,
+            order: Order::Asc,
+            after: None,
+        };
+        let found = store.extract(&Filter::default(), &page).await?;
+        let messages: Vec<_> = found.entries.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["line 2", "line 3", "line 4"]);
+        // the positions are kept even for the dropped entries
+        assert_eq!(store.source_position("journald").await?.unwrap(), "s=4");
+
+        store.insert((5..10).map(record).collect()).await?;
+        assert_eq!(store.stats().await?, stats);
+        Ok(())
+    }
+}
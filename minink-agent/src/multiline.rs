@@ -0,0 +1,434 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use tokio::time::Instant;
+
+use minink_common::LogEntry;
+
+use crate::{
+    config::MultilineConfig,
+    logdispatcher::{Checkpoint, LogDispatcher, LogRecord},
+    source::{LogSource, SourceHealth, SourcePosition},
+};
+
+/// Groups the consecutive lines of a source belonging to the same message,
+/// such as stack traces, into a single entry with one line per line of the
+/// message. Lines are grouped by hostname and service, so that interleaved
+/// services do not mix.
+#[derive(Debug)]
+pub struct MultilineLogSource {
+    source: Arc<dyn LogSource>,
+    config: MultilineConfig,
+    /// position of the last checkpoint sent, None until the first one
+    last_position: Mutex<Option<String>>,
+}
+
+#[derive(Debug)]
+struct Group {
+    entry: LogEntry,
+    lines: usize,
+    /// order in which the groups were started
+    seq: u64,
+    /// checkpoint of the record before the first line of the group
+    before: Option<Checkpoint>,
+    deadline: Instant,
+}
+
+type GroupKey = (String, String);
+
+#[derive(Debug)]
+struct Grouper<'a> {
+    config: &'a MultilineConfig,
+    groups: HashMap<GroupKey, Group>,
+    next_seq: u64,
+    /// checkpoint of the last record received
+    latest: Option<Checkpoint>,
+    /// checkpoint of the last record sent
+    sent: Option<Checkpoint>,
+}
+
+impl<'a> Grouper<'a> {
+    fn new(config: &'a MultilineConfig) -> Self {
+        Self {
+            config,
+            groups: HashMap::new(),
+            next_seq: 0,
+            latest: None,
+            sent: None,
+        }
+    }
+
+    fn continues(&self, line: &str) -> bool {
+        if self.config.start.as_ref().is_some_and(|r| r.is_match(line)) {
+            return false;
+        }
+        match &self.config.continuation {
+            Some(continuation) => continuation.is_match(line),
+            // only the start pattern: every other line continues
+            None => self.config.start.is_some(),
+        }
+    }
+
+    /// Returns the records that can be sent after receiving `record`.
+    fn push(&mut self, record: LogRecord) -> Vec<LogRecord> {
+        let mut records = vec![];
+        let key = (record.entry.hostname.clone(), record.entry.service.clone());
+        let continues = self.continues(&record.entry.message);
+
+        match self.groups.get_mut(&key) {
+            Some(group) if continues => {
+                group.entry.message.push('\n');
+                group.entry.message.push_str(&record.entry.message);
+                group.lines += 1;
+                group.deadline = Instant::now() + self.config.flush_timeout();
+                if record.checkpoint.is_some() {
+                    self.latest = record.checkpoint;
+                }
+                if group.lines >= self.config.max_lines {
+                    records.extend(self.flush(&key));
+                }
+            }
+            _ => {
+                records.extend(self.flush(&key));
+                // the lines without checkpoint are after the last one received
+                let before = self.latest.clone();
+                if record.checkpoint.is_some() {
+                    self.latest = record.checkpoint;
+                }
+                self.groups.insert(
+                    key.clone(),
+                    Group {
+                        entry: record.entry,
+                        lines: 1,
+                        seq: self.next_seq,
+                        before,
+                        deadline: Instant::now() + self.config.flush_timeout(),
+                    },
+                );
+                self.next_seq += 1;
+                if self.config.max_lines <= 1 {
+                    records.extend(self.flush(&key));
+                }
+            }
+        }
+        records
+    }
+
+    fn flush(&mut self, key: &GroupKey) -> Option<LogRecord> {
+        let group = self.groups.remove(key)?;
+        // every line before the oldest pending group has been sent
+        let safe = match self.groups.values().min_by_key(|group| group.seq) {
+            Some(oldest) => oldest.before.clone(),
+            None => self.latest.clone(),
+        };
+        let checkpoint = if safe.is_some() && safe != self.sent {
+            self.sent = safe.clone();
+            safe
+        } else {
+            None
+        };
+        Some(LogRecord {
+            entry: group.entry,
+            checkpoint,
+            stored: false,
+        })
+    }
+
+    /// Flush the groups without new lines for `flush_timeout`, or all of them.
+    fn flush_expired(&mut self, all: bool) -> Vec<LogRecord> {
+        let now = Instant::now();
+        let mut expired = self
+            .groups
+            .iter()
+            .filter(|(_, group)| all || group.deadline <= now)
+            .map(|(key, group)| (group.seq, key.clone()))
+            .collect::<Vec<_>>();
+        expired.sort();
+        expired
+            .into_iter()
+            .filter_map(|(_, key)| self.flush(&key))
+            .collect()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.groups.values().map(|group| group.deadline).min()
+    }
+}
+
+impl MultilineLogSource {
+    pub fn new(source: Arc<dyn LogSource>, config: MultilineConfig) -> Self {
+        Self {
+            source,
+            config,
+            last_position: Mutex::new(None),
+        }
+    }
+
+    async fn send(&self, dispatcher: &LogDispatcher, records: Vec<LogRecord>) {
+        for record in records {
+            if let Some(checkpoint) = &record.checkpoint {
+                *self.last_position.lock().unwrap() = Some(checkpoint.position.clone());
+            }
+            dispatcher.send_record(record).await;
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for MultilineLogSource {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn health(&self) -> SourceHealth {
+        self.source.health()
+    }
+
+    /// The lines of the pending groups are read again after a restart.
+    fn position(&self) -> Option<SourcePosition> {
+        self.last_position
+            .lock()
+            .unwrap()
+            .clone()
+            .map(SourcePosition::Cursor)
+    }
+
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        position: Option<SourcePosition>,
+    ) -> Result<()> {
+        let lines = Arc::new(LogDispatcher::new());
+        let mut stream = lines.stream("multiline");
+        // the stream is closed once the source stopped and all its lines were pulled
+        let mut follow = Some(self.source.follow(lines, position));
+        let mut result = Ok(());
+        let mut grouper = Grouper::new(&self.config);
+
+        loop {
+            let deadline = grouper.next_deadline();
+            tokio::select! {
+                record = stream.pull_record() => match record {
+                    Ok(record) => {
+                        let records = grouper.push(record);
+                        self.send(&dispatcher, records).await;
+                    }
+                    Err(_) => break,
+                },
+                _ = async { tokio::time::sleep_until(deadline.unwrap()).await }, if deadline.is_some() => {
+                    let records = grouper.flush_expired(false);
+                    self.send(&dispatcher, records).await;
+                },
+                stopped = async { follow.as_mut().unwrap().await }, if follow.is_some() => {
+                    result = stopped;
+                    follow = None;
+                },
+            }
+        }
+
+        let records = grouper.flush_expired(true);
+        self.send(&dispatcher, records).await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use chrono::NaiveDateTime;
+    use regex::Regex;
+
+    use minink_common::LogEntry;
+
+    use crate::{
+        config::MultilineConfig,
+        logdispatcher::{Checkpoint, LogDispatcher, LogRecord},
+        source::{LogSource, SourceHealth, SourcePosition},
+    };
+
+    use super::MultilineLogSource;
+
+    /// Sends its lines, with their index as position, then stops
+    /// after `stop_after`, if set.
+    #[derive(Debug)]
+    struct LinesSource {
+        lines: Vec<(&'static str, &'static str)>,
+        stop_after: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl LogSource for LinesSource {
+        fn name(&self) -> &str {
+            "lines"
+        }
+
+        fn health(&self) -> SourceHealth {
+            SourceHealth::Running
+        }
+
+        fn position(&self) -> Option<SourcePosition> {
+            None
+        }
+
+        async fn follow(
+            &self,
+            dispatcher: Arc<LogDispatcher>,
+            _position: Option<SourcePosition>,
+        ) -> Result<()> {
+            for (i, (service, message)) in self.lines.iter().enumerate() {
+                let entry = LogEntry {
+                    message: message.to_string(),
+                    hostname: "localhost".to_string(),
+                    service: service.to_string(),
+                    timestamp: NaiveDateTime::default(),
+                    level: None,
+                    fields: Default::default(),
+                };
+                let checkpoint = Checkpoint {
+                    source: "lines".to_string(),
+                    position: i.to_string(),
+                };
+                dispatcher.send_with_checkpoint(entry, checkpoint).await;
+            }
+            match self.stop_after {
+                Some(duration) => tokio::time::sleep(duration).await,
+                None => std::future::pending().await,
+            }
+            anyhow::bail!("no more lines")
+        }
+    }
+
+    async fn group(
+        lines: Vec<(&'static str, &'static str)>,
+        config: MultilineConfig,
+    ) -> Vec<(String, Option<String>)> {
+        let source = LinesSource {
+            lines,
+            stop_after: Some(Duration::ZERO),
+        };
+        let source = MultilineLogSource::new(Arc::new(source), config);
+        let dispatcher = Arc::new(LogDispatcher::new());
+        let mut stream = dispatcher.stream("test");
+        assert!(source.follow(dispatcher, None).await.is_err());
+
+        let mut records = vec![];
+        // closed once the dispatcher is dropped by follow
+        while let Ok(record) = stream.pull_record().await {
+            let LogRecord {
+                entry, checkpoint, ..
+            } = record;
+            records.push((entry.message, checkpoint.map(|c| c.position)));
+        }
+        records
+    }
+
+    fn java_config() -> MultilineConfig {
+        MultilineConfig {
+            start: None,
+            continuation: Some(Regex::new(r"^(\s+at |Caused by:)").unwrap()),
+            max_lines: 3,
+            flush_timeout_ms: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_continuation_pattern() {
+        let records = group(
+            vec![
+                ("app", "Exception in thread main"),
+                ("app", "    at Main.run"),
+                ("app", "Caused by: IOException"),
+                ("app", "    at Other.run"),
+                ("app", "done"),
+            ],
+            java_config(),
+        )
+        .await;
+        assert_eq!(
+            records,
+            [
+                (
+                    "Exception in thread main\n    at Main.run\nCaused by: IOException".to_string(),
+                    Some("2".to_string())
+                ),
+                // over max_lines
+                ("    at Other.run".to_string(), Some("3".to_string())),
+                ("done".to_string(), Some("4".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_timeout() {
+        let config = MultilineConfig {
+            flush_timeout_ms: 10,
+            ..java_config()
+        };
+        let source = LinesSource {
+            lines: vec![("app", "Exception"), ("app", "    at Main.run")],
+            stop_after: None,
+        };
+        let source = Arc::new(MultilineLogSource::new(Arc::new(source), config));
+        let dispatcher = Arc::new(LogDispatcher::new());
+        let mut stream = dispatcher.stream("test");
+        let job = tokio::spawn({
+            let source = source.clone();
+            async move { source.follow(dispatcher, None).await }
+        });
+
+        let record = tokio::time::timeout(Duration::from_secs(5), stream.pull_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.entry.message, "Exception\n    at Main.run");
+        assert_eq!(
+            source.position(),
+            Some(SourcePosition::Cursor("1".to_string()))
+        );
+        job.abort();
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_services() {
+        let config = MultilineConfig {
+            start: Some(Regex::new(r"^\d{4}-").unwrap()),
+            continuation: None,
+            max_lines: 100,
+            flush_timeout_ms: 1000,
+        };
+        let records = group(
+            vec![
+                ("web", "2023-04-20 Traceback"),
+                ("db", "2023-04-20 checkpoint"),
+                ("web", "  File x.py"),
+                ("web", "ValueError"),
+                ("db", "2023-04-20 vacuum"),
+                ("web", "2023-04-20 ok"),
+            ],
+            config,
+        )
+        .await;
+        assert_eq!(
+            records,
+            [
+                // the traceback is not sent yet, position 0 is the last safe one
+                ("2023-04-20 checkpoint".to_string(), None),
+                (
+                    "2023-04-20 Traceback\n  File x.py\nValueError".to_string(),
+                    Some("3".to_string())
+                ),
+                ("2023-04-20 vacuum".to_string(), Some("4".to_string())),
+                ("2023-04-20 ok".to_string(), Some("5".to_string())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    ConnectOptions, Connection, SqlitePool,
+};
+
+use crate::{archive::Archive, database::ENABLE_WAL};
+
+/// Extension of the archives, such as logs.2023-04-25.archive next to logs.db.
+const ARCHIVE_EXTENSION: &str = ".archive";
+
+/// Connections of a partition are closed after being unused for that long.
+const PARTITION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the entries are split between database files, by their timestamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum Partitioning {
+    /// every entry in the database file itself
+    #[default]
+    None,
+    /// a file per day, such as logs.2023-04-25.db next to logs.db
+    Day,
+    /// a file per hour, such as logs.2023-04-25T13.db next to logs.db
+    Hour,
+}
+
+impl Partitioning {
+    fn name(self) -> &'static str {
+        match self {
+            Partitioning::None => "none",
+            Partitioning::Day => "day",
+            Partitioning::Hour => "hour",
+        }
+    }
+
+    /// Microseconds covered by each partition.
+    fn length(self) -> Option<i64> {
+        match self {
+            Partitioning::None => None,
+            Partitioning::Day => Some(24 * 3600 * 1_000_000),
+            Partitioning::Hour => Some(3600 * 1_000_000),
+        }
+    }
+
+    fn suffix(self, start: i64) -> String {
+        let start = NaiveDateTime::from_timestamp_micros(start).unwrap_or_default();
+        match self {
+            Partitioning::None => String::new(),
+            Partitioning::Day => start.format("%Y-%m-%d").to_string(),
+            Partitioning::Hour => start.format("%Y-%m-%dT%H").to_string(),
+        }
+    }
+
+    /// The partitioning and the start of a partition from its suffix.
+    fn parse_suffix(suffix: &str) -> Option<(Partitioning, i64)> {
+        let (date, hour) = match suffix.split_once('T') {
+            Some((date, hour)) if hour.len() == 2 => (date, Some(hour.parse().ok()?)),
+            Some(_) => return None,
+            None => (suffix, None),
+        };
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+        let start = date
+            .and_hms_opt(hour.unwrap_or(0), 0, 0)?
+            .timestamp_micros();
+        match hour {
+            Some(_) => Some((Partitioning::Hour, start)),
+            None => Some((Partitioning::Day, start)),
+        }
+    }
+}
+
+/// Database holding the entries of a time range, the main database itself if
+/// the entries are not partitioned.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// start of the range in microseconds, None for the main database
+    pub key: Option<i64>,
+    /// end of the range in microseconds, excluded
+    pub end: i64,
+    pub pool: SqlitePool,
+}
+
+impl Partition {
+    /// Start of the range in microseconds.
+    pub fn start(&self) -> i64 {
+        self.key.unwrap_or(i64::MIN)
+    }
+}
+
+/// The entries of a time range: a partition, its archive, or both while the
+/// entries received after the partition was archived are not archived yet.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// in microseconds, `end` excluded
+    pub start: i64,
+    pub end: i64,
+    pub partition: Option<Partition>,
+    pub archive: Option<Arc<Archive>>,
+}
+
+#[derive(Debug, Default)]
+struct Files {
+    partitions: BTreeMap<i64, Partition>,
+    archives: BTreeMap<i64, Arc<Archive>>,
+}
+
+/// The partitions of a database and their archives. They cover disjoint time
+/// ranges, so that the entries of the partitions taken in order are in
+/// timestamp order.
+#[derive(Debug)]
+pub struct Partitions {
+    partitioning: Partitioning,
+    main: Partition,
+    /// options of the main database, for the files of the partitions
+    options: SqliteConnectOptions,
+    path: Option<PathBuf>,
+    files: Mutex<Files>,
+}
+
+/// Path of the database of a `sqlite://` URL, None if in memory.
+fn database_path(url: &str) -> Option<PathBuf> {
+    let path = url
+        .trim_start_matches("sqlite://")
+        .trim_start_matches("sqlite:");
+    let path = path.split('?').next().unwrap_or_default();
+    match path {
+        "" | ":memory:" => None,
+        path => Some(PathBuf::from(path)),
+    }
+}
+
+/// Directory, prefix and suffix of the partition files of `path`, such as
+/// `logs.` and `.db` for `logs.db`.
+fn file_parts(path: &Path) -> (PathBuf, String, String) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = match path.extension() {
+        Some(extension) => format!(".{}", extension.to_string_lossy()),
+        None => String::new(),
+    };
+    (dir, format!("{stem}."), extension)
+}
+
+impl Partitions {
+    /// Find the partitions and archives of the main database and migrate the
+    /// partitions. A database cannot be opened with another partitioning than
+    /// the one of its files.
+    pub async fn open(
+        url: &str,
+        main: SqlitePool,
+        options: SqliteConnectOptions,
+        partitioning: Partitioning,
+    ) -> Result<Self> {
+        let path = database_path(url);
+        let main = Partition {
+            key: None,
+            end: i64::MAX,
+            pool: main,
+        };
+        let partitions = Self {
+            partitioning,
+            main,
+            // only the writer creates partitions, see `get_or_create`
+            options: options.create_if_missing(false),
+            path,
+            files: Mutex::new(Files::default()),
+        };
+
+        let Some(path) = &partitions.path else {
+            anyhow::ensure!(
+                partitioning == Partitioning::None,
+                "an in-memory database cannot be partitioned"
+            );
+            return Ok(partitions);
+        };
+        let (dir, prefix, extension) = file_parts(path);
+        let mut files = Files::default();
+        for file in std::fs::read_dir(&dir)? {
+            let name = file?.file_name();
+            let name = name.to_string_lossy();
+            let Some(name) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            // checked first, the extension of the database may be empty
+            let (suffix, archived) = match name.strip_suffix(ARCHIVE_EXTENSION) {
+                Some(suffix) => (suffix, true),
+                None => match name.strip_suffix(&extension) {
+                    Some(suffix) => (suffix, false),
+                    None => continue,
+                },
+            };
+            let Some((found, start)) = Partitioning::parse_suffix(suffix) else {
+                continue;
+            };
+            anyhow::ensure!(
+                found == partitioning,
+                "{} is partitioned by {}, start with --partition {}",
+                path.display(),
+                found.name(),
+                found.name()
+            );
+            if archived {
+                let archive = Archive::open(&dir.join(format!("{prefix}{name}")))?;
+                files.archives.insert(start, Arc::new(archive));
+                continue;
+            }
+            let partition = partitions.connect(start);
+            sqlx::migrate!().run(&partition.pool).await?;
+            files.partitions.insert(start, partition);
+        }
+        *partitions.files.lock().unwrap() = files;
+        Ok(partitions)
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.partitioning != Partitioning::None
+    }
+
+    /// Key of the partition of an entry, None if it belongs to the main database.
+    pub fn key(&self, micros: i64) -> Option<i64> {
+        let length = self.partitioning.length()?;
+        Some(micros.div_euclid(length) * length)
+    }
+
+    fn path(&self, start: i64) -> PathBuf {
+        let path = self.path.as_deref().unwrap_or(Path::new(""));
+        let (dir, prefix, extension) = file_parts(path);
+        dir.join(format!(
+            "{prefix}{}{extension}",
+            self.partitioning.suffix(start)
+        ))
+    }
+
+    /// Path of the archive of the partition starting at `start`.
+    pub fn archive_path(&self, start: i64) -> PathBuf {
+        let path = self.path.as_deref().unwrap_or(Path::new(""));
+        let (dir, prefix, _) = file_parts(path);
+        dir.join(format!(
+            "{prefix}{}{ARCHIVE_EXTENSION}",
+            self.partitioning.suffix(start)
+        ))
+    }
+
+    fn end(&self, start: i64) -> i64 {
+        let length = self.partitioning.length().unwrap_or(i64::MAX);
+        start.saturating_add(length)
+    }
+
+    /// Connections are only opened when needed, the pool of a removed
+    /// partition does not create its file again.
+    fn connect(&self, start: i64) -> Partition {
+        let pool = SqlitePoolOptions::new()
+            .min_connections(0)
+            .idle_timeout(PARTITION_IDLE_TIMEOUT)
+            .connect_lazy_with(self.options.clone().filename(self.path(start)));
+        Partition {
+            key: Some(start),
+            end: self.end(start),
+            pool,
+        }
+    }
+
+    /// The partitions holding the entries, oldest first.
+    pub fn all(&self) -> Vec<Partition> {
+        if !self.is_partitioned() {
+            return vec![self.main.clone()];
+        }
+        let files = self.files.lock().unwrap();
+        files.partitions.values().cloned().collect()
+    }
+
+    /// The archives, oldest first.
+    pub fn archives(&self) -> Vec<Segment> {
+        let files = self.files.lock().unwrap();
+        files
+            .archives
+            .iter()
+            .map(|(&start, archive)| Segment {
+                start,
+                end: self.end(start),
+                partition: None,
+                archive: Some(archive.clone()),
+            })
+            .collect()
+    }
+
+    pub fn archive(&self, start: i64) -> Option<Arc<Archive>> {
+        self.files.lock().unwrap().archives.get(&start).cloned()
+    }
+
+    /// The partitions and archives that may hold entries between `from` and
+    /// `to` included, in microseconds, oldest first.
+    pub fn segments(&self, from: Option<i64>, to: Option<i64>) -> Vec<Segment> {
+        let mut segments = BTreeMap::new();
+        if !self.is_partitioned() {
+            segments.insert(
+                i64::MIN,
+                Segment {
+                    start: i64::MIN,
+                    end: i64::MAX,
+                    partition: Some(self.main.clone()),
+                    archive: None,
+                },
+            );
+        }
+        let files = self.files.lock().unwrap();
+        for (&start, partition) in &files.partitions {
+            let segment = Segment {
+                start,
+                end: partition.end,
+                partition: Some(partition.clone()),
+                archive: None,
+            };
+            segments.insert(start, segment);
+        }
+        for (&start, archive) in &files.archives {
+            let segment = segments.entry(start).or_insert_with(|| Segment {
+                start,
+                end: self.end(start),
+                partition: None,
+                archive: None,
+            });
+            segment.archive = Some(archive.clone());
+        }
+        segments
+            .into_values()
+            .filter(|segment| {
+                from.is_none_or(|from| segment.end > from)
+                    && to.is_none_or(|to| segment.start <= to)
+            })
+            .collect()
+    }
+
+    /// Every database file, the main one first.
+    pub fn databases(&self) -> Vec<Partition> {
+        let mut databases = vec![self.main.clone()];
+        if self.is_partitioned() {
+            databases.extend(self.all());
+        }
+        databases
+    }
+
+    pub fn contains(&self, start: i64) -> bool {
+        self.files.lock().unwrap().partitions.contains_key(&start)
+    }
+
+    /// Name of a partition in messages.
+    pub fn name(&self, partition: &Partition) -> String {
+        match partition.key {
+            Some(start) => self.path(start).display().to_string(),
+            None => match &self.path {
+                Some(path) => path.display().to_string(),
+                None => ":memory:".to_string(),
+            },
+        }
+    }
+
+    /// The partition starting at `start`, created if needed. Only the writer
+    /// creates and removes partitions.
+    pub async fn get_or_create(&self, start: i64) -> Result<Partition> {
+        if let Some(partition) = self.files.lock().unwrap().partitions.get(&start) {
+            return Ok(partition.clone());
+        }
+        let mut conn = self
+            .options
+            .clone()
+            .filename(self.path(start))
+            .create_if_missing(true)
+            .connect()
+            .await?;
+        sqlx::query(ENABLE_WAL).execute(&mut conn).await?;
+        conn.close().await?;
+
+        let partition = self.connect(start);
+        sqlx::migrate!().run(&partition.pool).await?;
+        tracing::info!("created partition {}", self.name(&partition));
+        let mut files = self.files.lock().unwrap();
+        files.partitions.insert(start, partition.clone());
+        Ok(partition)
+    }
+
+    /// Delete the files of a partition. The readers still using it finish
+    /// reading the deleted files.
+    pub fn remove(&self, start: i64) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        self.remove_partition(&mut files, start)
+    }
+
+    fn remove_partition(&self, files: &mut Files, start: i64) -> Result<()> {
+        let Some(partition) = files.partitions.remove(&start) else {
+            return Ok(());
+        };
+        let path = self.path(start);
+        tracing::info!("removing partition {}", path.display());
+        for suffix in ["-wal", "-shm", ""] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            remove_file(Path::new(&file))?;
+        }
+        tokio::spawn(async move { partition.pool.close().await });
+        Ok(())
+    }
+
+    /// Put a new archive of the partition starting at `start` in place, and
+    /// remove the partition at the same time if all its entries are archived,
+    /// so that the readers see the entries in either of them.
+    pub fn archived(&self, start: i64, archive: Archive, remove_partition: bool) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let archive = archive.persist(&self.archive_path(start))?;
+        tracing::info!("archived partition {}", archive.path().display());
+        files.archives.insert(start, Arc::new(archive));
+        if remove_partition {
+            self.remove_partition(&mut files, start)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_archive(&self, start: i64) -> Result<()> {
+        let Some(archive) = self.files.lock().unwrap().archives.remove(&start) else {
+            return Ok(());
+        };
+        tracing::info!("removing archive {}", archive.path().display());
+        remove_file(archive.path())
+    }
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{database_path, file_parts, Partitioning};
+
+    #[test]
+    fn test_database_path() {
+        assert_eq!(database_path(":memory:"), None);
+        assert_eq!(
+            database_path("sqlite:///var/lib/minink/logs.db?mode=rwc"),
+            Some(PathBuf::from("/var/lib/minink/logs.db"))
+        );
+        assert_eq!(
+            file_parts(&PathBuf::from("logs.db")),
+            (PathBuf::from("."), "logs.".to_string(), ".db".to_string())
+        );
+    }
+
+    #[test]
+    fn test_suffix() {
+        let day = Partitioning::Day.length().unwrap();
+        let start = 19_472 * day;
+        for partitioning in [Partitioning::Day, Partitioning::Hour] {
+            let suffix = partitioning.suffix(start);
+            assert_eq!(
+                Partitioning::parse_suffix(&suffix),
+                Some((partitioning, start))
+            );
+        }
+        assert_eq!(
+            Partitioning::Hour.suffix(start + 3600 * 1_000_000 * 13),
+            "2023-04-25T13"
+        );
+        assert_eq!(Partitioning::parse_suffix("2023-04-25T1"), None);
+        assert_eq!(Partitioning::parse_suffix("backup"), None);
+    }
+}
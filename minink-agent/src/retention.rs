@@ -0,0 +1,271 @@
+use anyhow::Result;
+
+use chrono::{NaiveDateTime, Utc};
+
+use crate::{
+    config::RetentionConfig,
+    database::{Expired, LogDatabase},
+};
+
+/// Deletes the entries older than their maximum age, then the oldest ones
+/// while the database is larger than its maximum size.
+#[derive(Debug)]
+pub struct Retention {
+    database: LogDatabase,
+    config: RetentionConfig,
+}
+
+impl Retention {
+    pub fn new(database: LogDatabase, config: RetentionConfig) -> Self {
+        Self { database, config }
+    }
+
+    /// Enforce the retention every interval, until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.config.interval());
+        loop {
+            interval.tick().await;
+            match self.enforce(Utc::now().naive_utc()).await {
+                Ok(0) => {}
+                Ok(deleted) => tracing::info!("retention deleted {} entries", deleted),
+                // the next attempt may succeed, the ingestion goes on anyway
+                Err(err) => tracing::error!("retention failed: {:#}", err),
+            }
+        }
+    }
+
+    /// Returns the number of deleted entries.
+    async fn enforce(&self, now: NaiveDateTime) -> Result<u64> {
+        let mut deleted = 0;
+
+        for service in &self.config.services {
+            let Some(before) = service
+                .max_age()
+                .and_then(|age| now.checked_sub_signed(age))
+            else {
+                continue;
+            };
+            let expired = Expired::Service {
+                service: service.service.clone(),
+                before,
+            };
+            deleted += self.delete(expired, u64::MAX).await?;
+        }
+
+        if let Some(before) = self
+            .config
+            .max_age()
+            .and_then(|age| now.checked_sub_signed(age))
+        {
+            let except = self
+                .config
+                .services
+                .iter()
+                .map(|service| service.service.clone())
+                .collect();
+            deleted += self
+                .delete(Expired::Others { except, before }, u64::MAX)
+                .await?;
+        }
+
+        if let Some(max_size) = self.config.max_size() {
+            let size = self.database.size().await?;
+            if size.used > max_size && size.entries > 0 {
+                // the space of the deleted entries is only known once they are
+                // deleted, so the excess is estimated from the average entry and
+                // checked again at the next interval
+                let excess = (size.used - max_size) as f64 / size.used as f64;
+                let count = (excess * size.entries as f64).ceil() as u64;
+                deleted += self.delete(Expired::Oldest, count).await?;
+            }
+        }
+
+        if deleted > 0 {
+            self.database.vacuum().await?;
+        }
+        Ok(deleted)
+    }
+
+    /// Delete up to `count` entries in batches, so that the entries received
+    /// in the meantime are written between them.
+    async fn delete(&self, expired: Expired, count: u64) -> Result<u64> {
+        let batch_size = self.config.batch_size as u64;
+        let mut deleted = 0;
+        while deleted < count {
+            let limit = batch_size.min(count - deleted) as usize;
+            let batch = self.database.delete_expired(expired.clone(), limit).await?;
+            deleted += batch;
+            if batch < limit as u64 {
+                break;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{Duration, NaiveDateTime};
+
+    use minink_common::{Filter, LogEntry};
+
+    use crate::{
+        config::{RetentionConfig, ServiceRetentionConfig},
+        database::{LogDatabase, WriteOptions},
+        logdispatcher::LogRecord,
+        partition::Partitioning,
+    };
+
+    use super::Retention;
+
+    fn config() -> RetentionConfig {
+        RetentionConfig {
+            max_age_days: None,
+            max_size_mb: None,
+            services: vec![],
+            interval_ms: 1000,
+            batch_size: 7,
+        }
+    }
+
+    async fn prep_db(services: &[&str], count: i64, now: NaiveDateTime) -> Result<LogDatabase> {
+        let db = LogDatabase::new(":memory:", WriteOptions::default()).await?;
+        insert_hourly(&db, services, count, now).await?;
+        Ok(db)
+    }
+
+    /// `count` entries of each service, one per hour until `now`.
+    async fn insert_hourly(
+        db: &LogDatabase,
+        services: &[&str],
+        count: i64,
+        now: NaiveDateTime,
+    ) -> Result<()> {
+        for service in services {
+            for i in 0..count {
+                let entry = LogEntry {
+                    message: format!("line {i} of {service}"),
+                    hostname: "localhost".to_string(),
+                    service: service.to_string(),
+                    timestamp: now - Duration::hours(count - 1 - i),
+                    level: None,
+                    fields: Default::default(),
+                };
+                db.add_log(LogRecord {
+                    entry,
+                    checkpoint: None,
+                    stored: false,
+                })
+                .await?;
+            }
+        }
+        db.sync_logs().await
+    }
+
+    async fn remaining(db: &LogDatabase, service: &str) -> Result<Vec<LogEntry>> {
+        let mut receiver = db.export(Filter {
+            services: Some(vec![service.to_string()]),
+            ..Default::default()
+        });
+        let mut entries = vec![];
+        while let Some(entry) = receiver.recv().await {
+            entries.push(entry?);
+        }
+        Ok(entries)
+    }
+
+    #[tokio::test]
+    async fn test_max_age() -> Result<()> {
+        let now = NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap();
+        let db = prep_db(&["nginx", "kernel", "audit"], 24 * 5, now).await?;
+        let config = RetentionConfig {
+            max_age_days: Some(3),
+            services: vec![
+                ServiceRetentionConfig {
+                    service: "nginx".to_string(),
+                    max_age_days: Some(1),
+                },
+                ServiceRetentionConfig {
+                    service: "audit".to_string(),
+                    max_age_days: None,
+                },
+            ],
+            ..config()
+        };
+        let retention = Retention::new(db.clone(), config);
+        // one more than the number of hours of each age is kept
+        assert_eq!(retention.enforce(now).await?, (5 * 24 - 25) + (5 * 24 - 73));
+
+        let nginx = remaining(&db, "nginx").await?;
+        assert_eq!(nginx.len(), 24 + 1);
+        assert_eq!(nginx[0].timestamp, now - Duration::days(1));
+        assert_eq!(remaining(&db, "kernel").await?.len(), 3 * 24 + 1);
+        assert_eq!(remaining(&db, "audit").await?.len(), 5 * 24);
+        // the full-text index lost the deleted entries too
+        assert!(db.check().await?.is_empty());
+
+        assert_eq!(retention.enforce(now).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_size() -> Result<()> {
+        let now = NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap();
+        let db = prep_db(&["kernel"], 20_000, now).await?;
+        let size = db.size().await?;
+        let config = RetentionConfig {
+            max_size_mb: Some(size.used / 2 / (1024 * 1024)),
+            batch_size: 1000,
+            ..config()
+        };
+        let max_size = config.max_size().unwrap();
+        let retention = Retention::new(db.clone(), config);
+        for _ in 0..5 {
+            retention.enforce(now).await?;
+        }
+
+        let after = db.size().await?;
+        assert!(after.used <= max_size, "{after:?} over {max_size}");
+        assert!(after.entries > 0);
+        let kernel = remaining(&db, "kernel").await?;
+        // the oldest ones were deleted
+        assert_eq!(kernel.last().unwrap().timestamp, now);
+        assert_eq!(kernel.len() as u64, after.entries);
+        assert!(db.check().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_partitions() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("minink-retention-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let url = format!("sqlite://{}/logs.db?mode=rwc", dir.display());
+        let options = WriteOptions {
+            partitioning: Partitioning::Day,
+            ..Default::default()
+        };
+        let db = LogDatabase::new(&url, options).await?;
+        // 2023-04-25 12:00 to 2023-04-29 12:00
+        let now = NaiveDateTime::from_timestamp_opt(1_682_769_600, 0).unwrap();
+        insert_hourly(&db, &["kernel"], 4 * 24 + 1, now).await?;
+
+        let config = RetentionConfig {
+            max_age_days: Some(2),
+            batch_size: 1000,
+            ..config()
+        };
+        let retention = Retention::new(db.clone(), config);
+        assert_eq!(retention.enforce(now).await?, 2 * 24);
+        // the two oldest days were removed, half of the third one deleted
+        assert!(!dir.join("logs.2023-04-26.db").exists());
+        assert!(dir.join("logs.2023-04-27.db").exists());
+        let kernel = remaining(&db, "kernel").await?;
+        assert_eq!(kernel[0].timestamp, now - Duration::days(2));
+        assert!(db.check().await?.is_empty());
+        db.close().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
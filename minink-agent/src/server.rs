@@ -1,47 +1,84 @@
 use anyhow::Result;
 
 use axum::{
+    body::{Bytes, StreamBody},
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Query, State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, DefaultBodyLimit, Query, State,
     },
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
 use chrono::NaiveDateTime;
-use minink_common::{Filter, LogEntry, ServiceName};
-use serde::Deserialize;
+use minink_common::{
+    parse_level_range, DroppedNotice, ExtractPage, Filter, GroupBy, Histogram, Level, LogEntry,
+    Order, ServiceName,
+};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap, future::Future, io::Read, net::SocketAddr, ops::Bound, path::PathBuf,
+    sync::Arc, time::Duration,
+};
 
-use std::{net::SocketAddr, ops::Bound, path::PathBuf, sync::Arc};
+use tokio::sync::mpsc;
 
 use tower_http::{
+    compression::CompressionLayer,
     cors::CorsLayer,
     services::ServeDir,
     trace::{DefaultMakeSpan, TraceLayer},
 };
 
-use crate::{database::LogDatabase, logdispatcher::LogDispatcher, logstream::LogStream};
+use crate::{
+    config::LiveConfig,
+    database::{Page, DEFAULT_PAGE_SIZE},
+    forward::{ForwardedBatch, BATCH_HEADER, EPOCH_HEADER, SENDER_HEADER},
+    logdispatcher::{LogDispatcher, SubscriberStatus},
+    logstream::LogStream,
+    source::SourceHealth,
+    store::{LogStore, StoreStats, TooManyBuckets},
+    supervisor::SourceSupervisor,
+};
 
 pub struct ServerArgs {
     pub port: u16,
     pub assets_dir: Option<PathBuf>,
+    pub live: LiveConfig,
 }
 
+/// WebSocket clients are given that long to get their last entries on shutdown.
+const WEBSOCKETS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 struct AppState {
     dispatcher: Arc<LogDispatcher>,
-    database: LogDatabase,
+    store: Arc<dyn LogStore>,
+    sources: Arc<Vec<Arc<SourceSupervisor>>>,
+    live: LiveConfig,
+    /// held by every WebSocket connection, to know when they are all closed
+    websockets: mpsc::Sender<()>,
 }
 
+/// Serve until `shutdown` completes. Once the requests in progress are
+/// answered, nothing is sent anymore: the dispatcher is closed, then the
+/// WebSocket clients get their last entries.
 pub async fn main(
     logdispatcher: Arc<LogDispatcher>,
-    database: LogDatabase,
+    store: Arc<dyn LogStore>,
+    sources: Vec<Arc<SourceSupervisor>>,
     args: ServerArgs,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<()> {
+    let (websockets, mut websockets_closed) = mpsc::channel(1);
     let appstate = AppState {
-        dispatcher: logdispatcher,
-        database,
+        dispatcher: logdispatcher.clone(),
+        store,
+        sources: Arc::new(sources),
+        live: args.live,
+        websockets,
     };
 
     let assets_dir = args
@@ -59,6 +96,18 @@ pub async fn main(
         .route("/ws/live", get(ws_handler))
         .route("/api/extract", get(extract))
         .route("/api/extract", post(post_extract))
+        .route("/api/histogram", get(histogram).post(post_histogram))
+        .route(
+            "/api/export",
+            get(export).post(post_export).layer(CompressionLayer::new()),
+        )
+        .route("/api/sources", get(list_sources))
+        .route("/api/subscribers", get(list_subscribers))
+        .route("/api/stats", get(stats))
+        .route(
+            "/api/ingest",
+            post(ingest).layer(DefaultBodyLimit::max(MAX_INGEST_SIZE as usize)),
+        )
         .with_state(appstate)
         .layer(cors)
         .layer(
@@ -70,7 +119,15 @@ pub async fn main(
     tracing::debug!("listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
         .await?;
+    logdispatcher.close();
+
+    // the state and its sender were dropped along with the server
+    let closed = tokio::time::timeout(WEBSOCKETS_CLOSE_TIMEOUT, websockets_closed.recv()).await;
+    if closed.is_err() {
+        tracing::warn!("some websocket clients were not closed");
+    }
     Ok(())
 }
 
@@ -79,7 +136,13 @@ struct WSParams {
     #[serde(default)]
     services: Option<String>,
     #[serde(default)]
+    hosts: Option<String>,
+    #[serde(default)]
     message_keywords: Option<String>,
+    #[serde(default)]
+    level: Option<String>,
+    #[serde(default)]
+    fields: Option<String>,
 }
 
 fn parse_query_list(services: Option<String>) -> Option<Vec<String>> {
@@ -91,35 +154,93 @@ fn parse_query_list(services: Option<String>) -> Option<Vec<String>> {
     })
 }
 
+/// `level=err` for errors and worse, or `level=warning..err`;
+/// an invalid range is ignored like invalid timestamps
+fn parse_query_levels(level: Option<String>) -> (Bound<Level>, Bound<Level>) {
+    level
+        .and_then(|level| parse_level_range(&level).ok())
+        .unwrap_or((Bound::Unbounded, Bound::Unbounded))
+}
+
+/// `fields=REQUEST_ID=abc-123,_PID=42`; pairs without '=' are ignored
+fn parse_query_fields(fields: Option<String>) -> Option<BTreeMap<String, String>> {
+    fields.map(|fields| {
+        fields
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    })
+}
+
 #[axum_macros::debug_handler]
 async fn ws_handler(
     ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Query(params): Query<WSParams>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
     let filter = Filter {
         services: parse_query_list(params.services),
+        hosts: parse_query_list(params.hosts),
         message_keywords: parse_query_list(params.message_keywords),
+        levels: parse_query_levels(params.level),
+        fields: parse_query_fields(params.fields),
         ..Default::default()
     };
-    let logstream = state.dispatcher.stream();
-    let logstream = logstream.with_filter(filter);
-    ws.on_upgrade(move |socket| handle_socket(socket, logstream))
+    let logstream = state
+        .dispatcher
+        .stream_with(
+            &format!("websocket {addr}"),
+            state.live.queue_size,
+            state.live.overflow,
+        )
+        .with_filter(filter);
+    let websocket = state.websockets;
+    ws.on_upgrade(move |socket| async move {
+        handle_socket(socket, logstream).await;
+        drop(websocket);
+    })
 }
 
 async fn handle_socket(socket: WebSocket, logstream: LogStream) {
     // {"filter":{"services":null,"message_keywords":null,"timerange":["Unbounded","Unbounded"]}}
     // {"filter":{"services":null,"message_keywords":["aa"],"timerange":["Unbounded","Unbounded"]}}
+    // {"filter":{"services":null,"message_keywords":null,"timerange":["Unbounded","Unbounded"],"levels":["Unbounded",{"Included":"err"}]}}
+    // {"filter":{"services":null,"message_keywords":null,"timerange":["Unbounded","Unbounded"],"fields":{"REQUEST_ID":"abc-123"}}}
+    // {"filter":{"services":null,"hosts":["web-"],"message_keywords":null,"timerange":["Unbounded","Unbounded"]}}
     #[derive(Debug, Deserialize)]
     struct ClientCommand {
         filter: Filter,
     }
 
     async fn work(mut socket: WebSocket, mut logstream: LogStream) -> Result<()> {
+        let mut notified = 0;
         loop {
             tokio::select! {
                 entry = logstream.pull_one() => {
-                    let entry = entry?;
+                    let Ok(entry) = entry else {
+                        let frame = if logstream.overflowed() {
+                            CloseFrame {
+                                code: close_code::AGAIN,
+                                reason: "client too slow".into(),
+                            }
+                        } else {
+                            // the dispatcher was closed, the agent is stopping
+                            CloseFrame {
+                                code: close_code::AWAY,
+                                reason: "agent stopping".into(),
+                            }
+                        };
+                        socket.send(Message::Close(Some(frame))).await?;
+                        return Ok(());
+                    };
+                    let dropped = logstream.dropped();
+                    if dropped > notified {
+                        let notice = DroppedNotice { dropped: dropped - notified };
+                        socket.send(Message::Text(serde_json::to_string(&notice)?)).await?;
+                        notified = dropped;
+                    }
                     let payload = serde_json::to_string(&entry)?;
                     socket.send(Message::Text(payload)).await?;
                 },
@@ -147,11 +268,17 @@ struct ExtractParams {
     #[serde(default)]
     services: Option<String>,
     #[serde(default)]
+    hosts: Option<String>,
+    #[serde(default)]
     message_keywords: Option<String>,
     #[serde(default)]
     start: Option<i64>,
     #[serde(default)]
     end: Option<i64>,
+    #[serde(default)]
+    level: Option<String>,
+    #[serde(default)]
+    fields: Option<String>,
 }
 
 impl From<ExtractParams> for Filter {
@@ -159,8 +286,11 @@ impl From<ExtractParams> for Filter {
         let timerange = value.timerange();
         Self {
             services: parse_query_list(value.services),
+            hosts: parse_query_list(value.hosts),
             message_keywords: parse_query_list(value.message_keywords),
             timerange,
+            levels: parse_query_levels(value.level),
+            fields: parse_query_fields(value.fields),
         }
     }
 }
@@ -187,26 +317,484 @@ impl ExtractParams {
     }
 }
 
+/// `?size=50&order=desc&cursor=...`, the cursor being the `next` of the previous page.
+/// Also in the query string for `POST /api/extract`, whose body is the filter.
+#[derive(Debug, Deserialize)]
+struct PageParams {
+    #[serde(default)]
+    size: Option<usize>,
+    #[serde(default)]
+    order: Order,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+impl TryFrom<PageParams> for Page {
+    type Error = (StatusCode, String);
+
+    fn try_from(value: PageParams) -> Result<Self, Self::Error> {
+        let after = value
+            .cursor
+            .map(|cursor| cursor.parse())
+            .transpose()
+            .map_err(|err: anyhow::Error| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        Ok(Self {
+            size: value.size.unwrap_or(DEFAULT_PAGE_SIZE),
+            order: value.order,
+            after,
+        })
+    }
+}
+
+async fn extract_page(
+    store: &dyn LogStore,
+    filter: &Filter,
+    page: PageParams,
+) -> Result<Json<ExtractPage>, (StatusCode, String)> {
+    let page = page.try_into()?;
+    let entries = store
+        .extract(filter, &page)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(entries))
+}
+
 #[axum_macros::debug_handler]
 async fn extract(
     Query(params): Query<ExtractParams>,
+    Query(page): Query<PageParams>,
     State(state): State<AppState>,
-) -> Json<Vec<LogEntry>> {
+) -> Result<Json<ExtractPage>, (StatusCode, String)> {
     let filter = params.into();
+    extract_page(state.store.as_ref(), &filter, page).await
+}
 
-    let db = state.database;
-    let entries = { db.extract(&filter).await.unwrap() };
+#[axum_macros::debug_handler]
+async fn post_extract(
+    Query(page): Query<PageParams>,
+    State(state): State<AppState>,
+    Json(filter): Json<Filter>,
+) -> Result<Json<ExtractPage>, (StatusCode, String)> {
+    extract_page(state.store.as_ref(), &filter, page).await
+}
 
-    Json(entries)
+/// `?interval_ms=60000&group_by=level`, `group_by` being `service`, `host` or
+/// `level`. Also in the query string for `POST /api/histogram`.
+#[derive(Debug, Deserialize)]
+struct HistogramParams {
+    interval_ms: u64,
+    #[serde(default)]
+    group_by: Option<GroupBy>,
+}
+
+async fn histogram_response(
+    store: &dyn LogStore,
+    filter: &Filter,
+    params: HistogramParams,
+) -> Result<Json<Histogram>, (StatusCode, String)> {
+    if params.interval_ms == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "interval_ms must be positive".to_string(),
+        ));
+    }
+    let histogram = store
+        .histogram(filter, params.interval_ms, params.group_by)
+        .await
+        .map_err(|err| {
+            let status = if err.is::<TooManyBuckets>() {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            (status, err.to_string())
+        })?;
+    Ok(Json(histogram))
 }
 
 #[axum_macros::debug_handler]
-async fn post_extract(
+async fn histogram(
+    Query(filter): Query<ExtractParams>,
+    Query(params): Query<HistogramParams>,
+    State(state): State<AppState>,
+) -> Result<Json<Histogram>, (StatusCode, String)> {
+    histogram_response(state.store.as_ref(), &filter.into(), params).await
+}
+
+#[axum_macros::debug_handler]
+async fn post_histogram(
+    Query(params): Query<HistogramParams>,
     State(state): State<AppState>,
     Json(filter): Json<Filter>,
-) -> Json<Vec<LogEntry>> {
-    let db = state.database;
-    let entries = { db.extract(&filter).await.unwrap() };
+) -> Result<Json<Histogram>, (StatusCode, String)> {
+    histogram_response(state.store.as_ref(), &filter, params).await
+}
+
+/// Body chunks of an export are about that large.
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Ndjson,
+    Csv,
+}
+
+impl ExportFormat {
+    /// `format=ndjson` or `format=csv`, otherwise from the Accept header.
+    fn negotiate(param: Option<Self>, headers: &HeaderMap) -> Self {
+        let csv = || {
+            headers
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("text/csv"))
+        };
+        match param {
+            Some(format) => format,
+            None if csv() => Self::Csv,
+            None => Self::Ndjson,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Ndjson => "ndjson",
+            Self::Csv => "csv",
+        }
+    }
+
+    fn header(self) -> &'static [u8] {
+        match self {
+            Self::Ndjson => b"",
+            Self::Csv => b"timestamp,hostname,service,level,message,fields\r\n",
+        }
+    }
+
+    fn encode(self, entry: &LogEntry, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Self::Ndjson => {
+                serde_json::to_writer(&mut *buf, entry)?;
+                buf.push(b'\n');
+            }
+            Self::Csv => {
+                let fields = if entry.fields.is_empty() {
+                    String::new()
+                } else {
+                    serde_json::to_string(&entry.fields)?
+                };
+                let timestamp = entry.timestamp.format("%Y-%m-%dT%H:%M:%S%.f").to_string();
+                let level = entry.level.map(|l| l.to_string()).unwrap_or_default();
+                let values = [
+                    &timestamp,
+                    &entry.hostname,
+                    &entry.service,
+                    &level,
+                    &entry.message,
+                    &fields,
+                ];
+                for (i, value) in values.into_iter().enumerate() {
+                    if i > 0 {
+                        buf.push(b',');
+                    }
+                    write_csv_value(value, buf);
+                }
+                buf.extend_from_slice(b"\r\n");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quoted when needed, as in RFC 4180.
+fn write_csv_value(value: &str, buf: &mut Vec<u8>) {
+    if value.contains([',', '"', '\n', '\r']) {
+        buf.push(b'"');
+        buf.extend_from_slice(value.replace('"', "\"\"").as_bytes());
+        buf.push(b'"');
+    } else {
+        buf.extend_from_slice(value.as_bytes());
+    }
+}
 
-    Json(entries)
+#[derive(Debug, Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: Option<ExportFormat>,
+}
+
+/// Stream the entries as they are read from the store, in chunks; a
+/// store error interrupts the response.
+fn export_response(
+    store: &dyn LogStore,
+    filter: Filter,
+    format: ExportFormat,
+) -> impl IntoResponse {
+    let entries = store.export(filter);
+    let chunks = futures_lite::stream::unfold(
+        (entries, format.header().to_vec()),
+        move |(mut entries, mut buf)| async move {
+            let entry = entries.recv().await;
+            if entry.is_none() && buf.is_empty() {
+                return None;
+            }
+            let mut next = entry;
+            while let Some(entry) = next {
+                if let Err(err) = entry.and_then(|entry| format.encode(&entry, &mut buf)) {
+                    return Some((Err(err), (entries, vec![])));
+                }
+                if buf.len() >= EXPORT_CHUNK_SIZE {
+                    break;
+                }
+                next = entries.try_recv().ok();
+            }
+            let chunk = Bytes::from(std::mem::take(&mut buf));
+            Some((Ok(chunk), (entries, buf)))
+        },
+    );
+    let disposition = format!("attachment; filename=\"export.{}\"", format.extension());
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        StreamBody::new(chunks),
+    )
+}
+
+#[axum_macros::debug_handler]
+async fn export(
+    Query(params): Query<ExtractParams>,
+    Query(export): Query<ExportParams>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let format = ExportFormat::negotiate(export.format, &headers);
+    export_response(state.store.as_ref(), params.into(), format)
+}
+
+#[axum_macros::debug_handler]
+async fn post_export(
+    Query(export): Query<ExportParams>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(filter): Json<Filter>,
+) -> impl IntoResponse {
+    let format = ExportFormat::negotiate(export.format, &headers);
+    export_response(state.store.as_ref(), filter, format)
+}
+
+#[derive(Debug, Serialize)]
+struct SourceStatus {
+    name: String,
+    health: SourceHealth,
+}
+
+#[axum_macros::debug_handler]
+async fn list_sources(State(state): State<AppState>) -> Json<Vec<SourceStatus>> {
+    let statuses = state
+        .sources
+        .iter()
+        .map(|source| SourceStatus {
+            name: source.name().to_string(),
+            health: source.health(),
+        })
+        .collect();
+    Json(statuses)
+}
+
+/// Queues of the database, the forwarder and the live clients.
+#[axum_macros::debug_handler]
+async fn list_subscribers(State(state): State<AppState>) -> Json<Vec<SubscriberStatus>> {
+    Json(state.dispatcher.subscribers())
+}
+
+#[axum_macros::debug_handler]
+async fn stats(State(state): State<AppState>) -> Result<Json<StoreStats>, (StatusCode, String)> {
+    let stats = state
+        .store
+        .stats()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(stats))
+}
+
+/// A body larger than this, once decompressed, is rejected.
+const MAX_INGEST_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+struct IngestResult {
+    accepted: usize,
+    /// the batch was already received from the same forwarding agent
+    duplicate: bool,
+}
+
+/// Parse a body of newline-delimited `LogEntry` objects, possibly gzipped.
+/// The whole body is rejected if one line is invalid.
+fn parse_ingest_body(body: &[u8], gzip: bool) -> Result<Vec<LogEntry>, String> {
+    let mut decompressed = vec![];
+    let body = if gzip {
+        flate2::read::GzDecoder::new(body)
+            .take(MAX_INGEST_SIZE + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|err| format!("invalid gzip body: {err}"))?;
+        if decompressed.len() as u64 > MAX_INGEST_SIZE {
+            return Err("body too large".to_string());
+        }
+        &decompressed[..]
+    } else {
+        body
+    };
+
+    let mut entries = vec![];
+    for (i, line) in body.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let entry: LogEntry =
+            serde_json::from_slice(line).map_err(|err| format!("line {}: {}", i + 1, err))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Sender, epoch and sequence number of a batch sent by another agent's forwarder.
+fn forwarded_batch(headers: &HeaderMap) -> Result<Option<ForwardedBatch>, String> {
+    let number = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(|| format!("invalid {name}"))
+            })
+            .transpose()
+    };
+    let (Some(sender), Some(seq)) = (headers.get(SENDER_HEADER), number(BATCH_HEADER)?) else {
+        return Ok(None);
+    };
+    let sender = sender
+        .to_str()
+        .map_err(|_| format!("invalid {SENDER_HEADER}"))?;
+    Ok(Some(ForwardedBatch {
+        sender: sender.to_string(),
+        epoch: number(EPOCH_HEADER)?.unwrap_or(0),
+        seq,
+    }))
+}
+
+#[axum_macros::debug_handler]
+async fn ingest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<IngestResult>, (StatusCode, String)> {
+    let gzip = headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"gzip"));
+    let entries = parse_ingest_body(&body, gzip).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let accepted = entries.len();
+
+    match forwarded_batch(&headers).map_err(|err| (StatusCode::BAD_REQUEST, err))? {
+        // stored before answering, so that the sending agent can drop the batch
+        Some(batch) => {
+            let inserted = state
+                .store
+                .insert_forwarded(&batch, &entries)
+                .await
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            if !inserted {
+                return Ok(Json(IngestResult {
+                    accepted: 0,
+                    duplicate: true,
+                }));
+            }
+            for entry in entries {
+                state.dispatcher.send_stored(entry).await;
+            }
+        }
+        None => {
+            for entry in entries {
+                state.dispatcher.send(entry).await;
+            }
+        }
+    }
+    Ok(Json(IngestResult {
+        accepted,
+        duplicate: false,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    use super::{parse_ingest_body, ExportFormat};
+
+    const BODY: &str = r#"{"message":"job started","hostname":"ci-1","service":"build","timestamp":"2023-04-20T10:00:00"}
+
+{"message":"job failed","hostname":"ci-1","service":"build","timestamp":"2023-04-20T10:01:00","level":"err","fields":{"JOB":"42"}}
+"#;
+
+    #[test]
+    fn test_parse_ingest_body() {
+        let entries = parse_ingest_body(BODY.as_bytes(), false).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].message, "job failed");
+        assert_eq!(entries[1].fields["JOB"], "42");
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(BODY.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert_eq!(parse_ingest_body(&gzipped, true).unwrap(), entries);
+        assert!(parse_ingest_body(BODY.as_bytes(), true).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_line() {
+        let body = format!("{BODY}{{\"message\":\"no hostname\"}}\n");
+        let err = parse_ingest_body(body.as_bytes(), false).unwrap_err();
+        assert!(err.starts_with("line 4:"), "{err}");
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let mut entries = parse_ingest_body(BODY.as_bytes(), false).unwrap();
+        entries[1].message = "failed, \"badly\"\nreally".to_string();
+        let mut buf = ExportFormat::Csv.header().to_vec();
+        for entry in &entries {
+            ExportFormat::Csv.encode(entry, &mut buf).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "timestamp,hostname,service,level,message,fields\r\n\
+            2023-04-20T10:00:00,ci-1,build,,job started,\r\n\
+            2023-04-20T10:01:00,ci-1,build,err,\"failed, \"\"badly\"\"\nreally\",\"{\"\"JOB\"\":\"\"42\"\"}\"\r\n"
+        );
+    }
+
+    #[test]
+    fn test_export_format() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            ExportFormat::negotiate(None, &headers),
+            ExportFormat::Ndjson
+        );
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/csv"));
+        assert_eq!(ExportFormat::negotiate(None, &headers), ExportFormat::Csv);
+        assert_eq!(
+            ExportFormat::negotiate(Some(ExportFormat::Ndjson), &headers),
+            ExportFormat::Ndjson
+        );
+    }
 }
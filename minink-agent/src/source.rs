@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use chrono::NaiveDateTime;
+
+use serde::Serialize;
+
+use crate::logdispatcher::LogDispatcher;
+
+/// Where a source should restart from after the agent restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourcePosition {
+    /// Opaque position persisted by the source along with its entries.
+    Cursor(String),
+    /// Timestamp of the most recent entry in the database, for sources
+    /// that have not persisted a cursor yet.
+    Timestamp(NaiveDateTime),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum SourceHealth {
+    Starting,
+    Running,
+    /// the source stopped and will be restarted after a delay
+    Restarting {
+        attempt: u32,
+        last_error: String,
+    },
+    /// the source stopped and will not be restarted
+    Failed {
+        last_error: String,
+    },
+}
+
+#[async_trait]
+pub trait LogSource: Send + Sync + std::fmt::Debug {
+    /// Name of the source, unique within an agent.
+    fn name(&self) -> &str;
+
+    fn health(&self) -> SourceHealth;
+
+    /// Position right after the last entry sent to the dispatcher, if any.
+    fn position(&self) -> Option<SourcePosition>;
+
+    /// Follow the source starting at `position` (or at the source's default
+    /// starting point) and send every entry to the dispatcher.
+    /// Only returns when the source cannot produce entries anymore.
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        position: Option<SourcePosition>,
+    ) -> Result<()>;
+}
+
+/// Name of this machine, for the entries which do not come with one.
+pub fn local_hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_default()
+}
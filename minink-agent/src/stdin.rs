@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+
+use minink_common::LogEntry;
+
+use crate::{
+    config::StdinConfig,
+    logdispatcher::LogDispatcher,
+    source::{local_hostname, LogSource, SourceHealth, SourcePosition},
+};
+
+/// A line longer than that is split.
+const MAX_LINE_LEN: u64 = 1024 * 1024;
+
+/// Reads the lines written to the standard input of the agent, such as the
+/// output of `app | minink-agent`.
+#[derive(Debug)]
+pub struct StdinLogSource {
+    name: String,
+    service: String,
+    hostname: String,
+    health: Mutex<SourceHealth>,
+}
+
+impl StdinLogSource {
+    pub fn new(config: &StdinConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            service: config
+                .service
+                .clone()
+                .unwrap_or_else(|| config.name.clone()),
+            hostname: local_hostname(),
+            health: Mutex::new(SourceHealth::Starting),
+        }
+    }
+
+    /// Send an entry for every line of `input`, until its end.
+    async fn dispatch_lines(
+        &self,
+        mut input: impl AsyncBufRead + Unpin,
+        dispatcher: &LogDispatcher,
+    ) -> Result<()> {
+        loop {
+            let mut line = vec![];
+            (&mut input)
+                .take(MAX_LINE_LEN)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let entry = LogEntry {
+                message: String::from_utf8_lossy(line).into_owned(),
+                hostname: self.hostname.clone(),
+                service: self.service.clone(),
+                timestamp: chrono::Utc::now().naive_utc(),
+                level: None,
+                fields: BTreeMap::new(),
+            };
+            dispatcher.send(entry).await;
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for StdinLogSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn health(&self) -> SourceHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// The lines already read cannot be read again.
+    fn position(&self) -> Option<SourcePosition> {
+        None
+    }
+
+    /// Never returns at the end of the input, the source would be restarted
+    /// to read nothing more.
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        _position: Option<SourcePosition>,
+    ) -> Result<()> {
+        *self.health.lock().unwrap() = SourceHealth::Running;
+        self.dispatch_lines(BufReader::new(tokio::io::stdin()), &dispatcher)
+            .await?;
+        tracing::info!("{}: end of the standard input", self.name);
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::StdinConfig, logdispatcher::LogDispatcher};
+
+    use super::StdinLogSource;
+
+    #[tokio::test]
+    async fn test_dispatch_lines() {
+        let config: StdinConfig = toml::from_str(r#"service = "app""#).unwrap();
+        let source = StdinLogSource::new(&config);
+        let dispatcher = LogDispatcher::new();
+        let mut stream = dispatcher.stream("test");
+
+        let input: &[u8] = b"started\r\n\nin\xffvalid\nno newline";
+        source.dispatch_lines(input, &dispatcher).await.unwrap();
+        let mut messages = vec![];
+        for _ in 0..4 {
+            let entry = stream.pull_one().await.unwrap();
+            assert_eq!(entry.service, "app");
+            messages.push(entry.message);
+        }
+        assert_eq!(messages, ["started", "", "in\u{fffd}valid", "no newline"]);
+    }
+}
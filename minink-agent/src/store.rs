@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use chrono::NaiveDateTime;
+
+use minink_common::{ExtractPage, Filter, GroupBy, Histogram, HistogramBucket, Level, LogEntry};
+
+use serde::Serialize;
+
+use tokio::sync::mpsc;
+
+use crate::{
+    database::{LogDatabase, Page},
+    forward::ForwardedBatch,
+    logdispatcher::LogRecord,
+};
+
+/// Where the entries are kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum StoreKind {
+    /// in the SQLite database of `--database-path`
+    #[default]
+    Sqlite,
+    /// in memory, only the last `--memory-entries`, lost when the agent stops
+    Memory,
+}
+
+/// What `/api/stats` returns.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StoreStats {
+    pub entries: u64,
+    /// used by the entries, on disk or in memory
+    pub bytes: u64,
+}
+
+/// Most intervals with entries in a histogram.
+pub const MAX_HISTOGRAM_BUCKETS: usize = 10_000;
+
+/// A histogram would have more than `MAX_HISTOGRAM_BUCKETS` intervals.
+#[derive(Debug, thiserror::Error)]
+#[error("more than {MAX_HISTOGRAM_BUCKETS} intervals with entries, use a larger interval")]
+pub struct TooManyBuckets;
+
+/// Group of the entries of `level` in a histogram grouped by level.
+pub fn level_group(level: Option<Level>) -> &'static str {
+    level.map_or("none", Level::name)
+}
+
+/// Counts of a histogram, added by group of entries or one entry at a time.
+#[derive(Debug)]
+pub struct HistogramCounts {
+    interval_ms: u64,
+    group_by: Option<GroupBy>,
+    /// by start of the interval in microseconds, then by group
+    buckets: BTreeMap<i64, BTreeMap<String, u64>>,
+}
+
+impl HistogramCounts {
+    pub fn new(interval_ms: u64, group_by: Option<GroupBy>) -> Self {
+        Self {
+            interval_ms,
+            group_by,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /// Length of the intervals in microseconds.
+    pub fn interval(&self) -> i64 {
+        i64::try_from(self.interval_ms)
+            .unwrap_or(i64::MAX)
+            .saturating_mul(1000)
+            .max(1)
+    }
+
+    /// Add `count` entries of `group` to the interval starting at `start`,
+    /// the group being ignored if the histogram is not grouped.
+    pub fn add(&mut self, start: i64, group: &str, count: u64) -> Result<(), TooManyBuckets> {
+        if !self.buckets.contains_key(&start) && self.buckets.len() >= MAX_HISTOGRAM_BUCKETS {
+            return Err(TooManyBuckets);
+        }
+        let group = if self.group_by.is_some() { group } else { "" };
+        *self
+            .buckets
+            .entry(start)
+            .or_default()
+            .entry(group.to_string())
+            .or_default() += count;
+        Ok(())
+    }
+
+    pub fn add_entry(&mut self, entry: &LogEntry) -> Result<(), TooManyBuckets> {
+        let group = match self.group_by {
+            None => "",
+            Some(GroupBy::Service) => &entry.service,
+            Some(GroupBy::Host) => &entry.hostname,
+            Some(GroupBy::Level) => level_group(entry.level),
+        };
+        let micros = entry.timestamp.timestamp_micros();
+        let start = micros - micros.rem_euclid(self.interval());
+        self.add(start, group, 1)
+    }
+
+    pub fn finish(self) -> Histogram {
+        let grouped = self.group_by.is_some();
+        let buckets = self
+            .buckets
+            .into_iter()
+            .filter_map(|(start, groups)| {
+                Some(HistogramBucket {
+                    start: NaiveDateTime::from_timestamp_micros(start)?,
+                    count: groups.values().sum(),
+                    groups: if grouped { groups } else { BTreeMap::new() },
+                })
+            })
+            .collect();
+        Histogram {
+            interval_ms: self.interval_ms,
+            buckets,
+        }
+    }
+}
+
+/// Stores the entries and the positions of the sources, and searches them.
+#[async_trait]
+pub trait LogStore: Send + Sync + std::fmt::Debug {
+    /// Store entries received from the sources, along with the positions of
+    /// their sources. The entries already stored are skipped.
+    async fn insert(&self, records: Vec<LogRecord>) -> Result<()>;
+
+    /// Store a batch forwarded by another agent, unless a batch with the same
+    /// or a later sequence number from the same spool of the sender was
+    /// already stored. Returns false for such duplicates.
+    async fn insert_forwarded(&self, batch: &ForwardedBatch, entries: &[LogEntry]) -> Result<bool>;
+
+    async fn extract(&self, filter: &Filter, page: &Page) -> Result<ExtractPage>;
+
+    /// Every entry matching `filter`, oldest first, until the receiver is dropped.
+    fn export(&self, filter: Filter) -> mpsc::Receiver<Result<LogEntry>>;
+
+    /// Position of the last entry of `source` that was stored.
+    async fn source_position(&self, source: &str) -> Result<Option<String>>;
+
+    /// Timestamp of the newest entry, where the sources without a position start.
+    async fn last_timestamp(&self) -> Result<Option<NaiveDateTime>>;
+
+    /// Number of entries matching `filter` in each interval of `interval_ms`,
+    /// and for each service, host or level with `group_by`.
+    async fn histogram(
+        &self,
+        filter: &Filter,
+        interval_ms: u64,
+        group_by: Option<GroupBy>,
+    ) -> Result<Histogram>;
+
+    async fn stats(&self) -> Result<StoreStats>;
+
+    /// Store the pending entries before the agent stops.
+    async fn close(&self) -> Result<()>;
+}
+
+#[async_trait]
+impl LogStore for LogDatabase {
+    async fn insert(&self, records: Vec<LogRecord>) -> Result<()> {
+        // batched by the writer
+        for record in records {
+            self.add_log(record).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_forwarded(&self, batch: &ForwardedBatch, entries: &[LogEntry]) -> Result<bool> {
+        LogDatabase::insert_forwarded(self, batch, entries).await
+    }
+
+    async fn extract(&self, filter: &Filter, page: &Page) -> Result<ExtractPage> {
+        LogDatabase::extract(self, filter, page).await
+    }
+
+    fn export(&self, filter: Filter) -> mpsc::Receiver<Result<LogEntry>> {
+        LogDatabase::export(self, filter)
+    }
+
+    async fn source_position(&self, source: &str) -> Result<Option<String>> {
+        LogDatabase::source_position(self, source).await
+    }
+
+    async fn last_timestamp(&self) -> Result<Option<NaiveDateTime>> {
+        LogDatabase::last_timestamp(self).await
+    }
+
+    async fn histogram(
+        &self,
+        filter: &Filter,
+        interval_ms: u64,
+        group_by: Option<GroupBy>,
+    ) -> Result<Histogram> {
+        LogDatabase::histogram(self, filter, interval_ms, group_by).await
+    }
+
+    async fn stats(&self) -> Result<StoreStats> {
+        let size = self.size().await?;
+        Ok(StoreStats {
+            entries: size.entries,
+            bytes: size.used,
+        })
+    }
+
+    async fn close(&self) -> Result<()> {
+        LogDatabase::close(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::NaiveDateTime;
+
+    use minink_common::{Filter, GroupBy, LogEntry, Order};
+
+    use crate::{
+        database::{Durability, LogDatabase, Page, WriteOptions},
+        forward::ForwardedBatch,
+        logdispatcher::{Checkpoint, LogRecord},
+        memory::MemoryStore,
+    };
+
+    use super::LogStore;
+
+    fn entry(service: &str, second: i64) -> LogEntry {
+        LogEntry {
+            message: format!("{service} at {second}"),
+            hostname: "localhost".to_string(),
+            service: service.to_string(),
+            timestamp: NaiveDateTime::from_timestamp_opt(1_682_380_800 + second, 0).unwrap(),
+            level: None,
+            fields: Default::default(),
+        }
+    }
+
+    async fn pages(store: &dyn LogStore, filter: &Filter, order: Order) -> Result<Vec<LogEntry>> {
+        let mut page = Page {
+            size: 2,
+            order,
+            after: None,
+        };
+        let mut found = vec![];
+        loop {
+            let result = store.extract(filter, &page).await?;
+            found.extend(result.entries);
+            match result.next {
+                Some(next) => page.after = Some(next.parse()?),
+                None => return Ok(found),
+            }
+        }
+    }
+
+    /// Both stores answer the same way.
+    async fn check_store(store: &dyn LogStore) -> Result<()> {
+        // not in timestamp order, some at the same time
+        let entries = [
+            entry("nginx", 3),
+            entry("kernel", 1),
+            entry("nginx", 1),
+            entry("kernel", 5),
+            entry("nginx", 2),
+        ];
+        let records = entries
+            .iter()
+            .map(|entry| LogRecord {
+                entry: entry.clone(),
+                checkpoint: Some(Checkpoint {
+                    source: "journald".to_string(),
+                    position: entry.message.clone(),
+                }),
+                stored: false,
+            })
+            .collect();
+        store.insert(records).await?;
+
+        let mut sorted = entries.to_vec();
+        sorted.sort_by_key(|entry| entry.timestamp);
+        assert_eq!(pages(store, &Filter::default(), Order::Asc).await?, sorted);
+        sorted.reverse();
+        assert_eq!(pages(store, &Filter::default(), Order::Desc).await?, sorted);
+
+        let nginx = Filter {
+            services: Some(vec!["nginx".to_string()]),
+            ..Default::default()
+        };
+        let mut exported = store.export(nginx.clone());
+        let mut found = vec![];
+        while let Some(entry) = exported.recv().await {
+            found.push(entry?.message);
+        }
+        assert_eq!(found, ["nginx at 1", "nginx at 2", "nginx at 3"]);
+
+        let histogram = store
+            .histogram(&Filter::default(), 2000, Some(GroupBy::Service))
+            .await?;
+        let buckets: Vec<_> = histogram
+            .buckets
+            .iter()
+            .map(|bucket| {
+                let groups: Vec<_> = bucket
+                    .groups
+                    .iter()
+                    .map(|(g, n)| (g.as_str(), *n))
+                    .collect();
+                (bucket.start, bucket.count, groups)
+            })
+            .collect();
+        assert_eq!(
+            buckets,
+            [
+                (entry("", 0).timestamp, 2, vec![("kernel", 1), ("nginx", 1)]),
+                (entry("", 2).timestamp, 2, vec![("nginx", 2)]),
+                (entry("", 4).timestamp, 1, vec![("kernel", 1)]),
+            ]
+        );
+        let levels = store
+            .histogram(&nginx, 60_000, Some(GroupBy::Level))
+            .await?;
+        assert_eq!(levels.buckets.len(), 1);
+        assert_eq!(levels.buckets[0].groups["none"], 3);
+        let total = store.histogram(&Filter::default(), 60_000, None).await?;
+        assert_eq!(total.buckets[0].count, 5);
+        assert!(total.buckets[0].groups.is_empty());
+
+        let batch = ForwardedBatch {
+            sender: "web-1".to_string(),
+            epoch: 1,
+            seq: 1,
+        };
+        assert!(store.insert_forwarded(&batch, &[entry("app", 9)]).await?);
+        assert!(!store.insert_forwarded(&batch, &[entry("app", 9)]).await?);
+        assert_eq!(
+            store.last_timestamp().await?,
+            Some(entry("app", 9).timestamp)
+        );
+        assert_eq!(
+            store.source_position("journald").await?.unwrap(),
+            "nginx at 2"
+        );
+        assert_eq!(store.stats().await?.entries, 6);
+        store.close().await
+    }
+
+    /// Field names that cannot be written in a JSON path are matched too.
+    async fn check_fields(store: &dyn LogStore) -> Result<()> {
+        let names = ["a\"b", "a", "a\\\"b", "a.b", "$", "x'"];
+        let records = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let mut entry = entry(name, i as i64);
+                entry.fields.insert(name.to_string(), "1".to_string());
+                LogRecord {
+                    entry,
+                    checkpoint: None,
+                    stored: false,
+                }
+            })
+            .collect();
+        store.insert(records).await?;
+
+        for name in names {
+            let filter = Filter {
+                fields: Some([(name.to_string(), "1".to_string())].into()),
+                ..Default::default()
+            };
+            let found = pages(store, &filter, Order::Asc).await?;
+            assert_eq!(found.len(), 1, "{name}");
+            assert!(filter.accept(&found[0]));
+            assert_eq!(found[0].service, name);
+        }
+        store.close().await
+    }
+
+    #[tokio::test]
+    async fn test_stores() -> Result<()> {
+        let options = WriteOptions {
+            // every entry is written before `insert` returns
+            durability: Durability::Full,
+            ..Default::default()
+        };
+        check_store(&LogDatabase::new(":memory:", options).await?).await?;
+        check_store(&MemoryStore::new(100)).await
+    }
+
+    #[tokio::test]
+    async fn test_field_filters() -> Result<()> {
+        let options = WriteOptions {
+            durability: Durability::Full,
+            ..Default::default()
+        };
+        check_fields(&LogDatabase::new(":memory:", options).await?).await?;
+        check_fields(&MemoryStore::new(100)).await
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    config::RestartConfig,
+    logdispatcher::LogDispatcher,
+    source::{LogSource, SourceHealth, SourcePosition},
+};
+
+/// A source that ran at least this long before stopping is not considered
+/// to be failing repeatedly.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Runs a source and restarts it with an exponential backoff when it stops,
+/// so that a failing source does not bring down the rest of the agent.
+#[derive(Debug)]
+pub struct SourceSupervisor {
+    source: Arc<dyn LogSource>,
+    config: RestartConfig,
+    /// None while the source is being followed
+    stopped: Mutex<Option<SourceHealth>>,
+}
+
+impl SourceSupervisor {
+    pub fn new(source: Arc<dyn LogSource>, config: RestartConfig) -> Self {
+        Self {
+            source,
+            config,
+            stopped: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    pub fn health(&self) -> SourceHealth {
+        match &*self.stopped.lock().unwrap() {
+            Some(health) => health.clone(),
+            None => self.source.health(),
+        }
+    }
+
+    fn set_stopped(&self, health: Option<SourceHealth>) {
+        *self.stopped.lock().unwrap() = health;
+    }
+
+    /// Follow the source until it fails more than `max_attempts` times in a row.
+    pub async fn run(&self, dispatcher: Arc<LogDispatcher>, position: Option<SourcePosition>) {
+        let mut attempt = 0;
+        let mut backoff = self.config.initial_backoff();
+
+        loop {
+            // on restart, continue right after what was already dispatched
+            let position = self.source.position().or_else(|| position.clone());
+
+            self.set_stopped(None);
+            let started = Instant::now();
+            let result = self.source.follow(dispatcher.clone(), position).await;
+            let last_error = match result {
+                Ok(()) => "source stopped".to_string(),
+                Err(err) => err.to_string(),
+            };
+
+            if started.elapsed() >= STABLE_RUN {
+                attempt = 0;
+                backoff = self.config.initial_backoff();
+            }
+            attempt += 1;
+
+            if let Some(max_attempts) = self.config.max_attempts {
+                if attempt > max_attempts {
+                    tracing::error!("source {} failed: {}", self.name(), last_error);
+                    self.set_stopped(Some(SourceHealth::Failed { last_error }));
+                    return;
+                }
+            }
+
+            tracing::warn!(
+                "source {} stopped: {}, restarting in {:?}",
+                self.name(),
+                last_error,
+                backoff
+            );
+            self.set_stopped(Some(SourceHealth::Restarting {
+                attempt,
+                last_error,
+            }));
+            tokio::time::sleep(backoff).await;
+            backoff = Duration::min(backoff * 2, self.config.max_backoff());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    use crate::{
+        config::RestartConfig,
+        logdispatcher::LogDispatcher,
+        source::{LogSource, SourceHealth, SourcePosition},
+    };
+
+    use super::SourceSupervisor;
+
+    #[derive(Debug, Default)]
+    struct FailingSource {
+        runs: AtomicU32,
+    }
+
+    #[async_trait]
+    impl LogSource for FailingSource {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn health(&self) -> SourceHealth {
+            SourceHealth::Running
+        }
+
+        fn position(&self) -> Option<SourcePosition> {
+            None
+        }
+
+        async fn follow(
+            &self,
+            _dispatcher: Arc<LogDispatcher>,
+            _position: Option<SourcePosition>,
+        ) -> Result<()> {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            anyhow::bail!("failure {run}")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_until_failed() {
+        let source = Arc::new(FailingSource::default());
+        let config = RestartConfig {
+            initial_backoff_ms: 1,
+            max_backoff_ms: 4,
+            max_attempts: Some(3),
+        };
+        let supervisor = SourceSupervisor::new(source.clone(), config);
+        assert_eq!(supervisor.health(), SourceHealth::Running);
+
+        supervisor.run(Arc::new(LogDispatcher::new()), None).await;
+
+        assert_eq!(source.runs.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            supervisor.health(),
+            SourceHealth::Failed {
+                last_error: "failure 3".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinSet,
+};
+
+use minink_common::{Level, LogEntry};
+
+use crate::{
+    config::SyslogConfig,
+    logdispatcher::LogDispatcher,
+    source::{LogSource, SourceHealth, SourcePosition},
+};
+
+/// Larger messages close the TCP connection, UDP datagrams are limited anyway.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Longer octet counts close the TCP connection, a valid one has at most 5 digits.
+const MAX_LEN_DIGITS: u64 = 10;
+
+/// Receives syslog messages (RFC 5424 or RFC 3164) over UDP and/or TCP.
+#[derive(Debug)]
+pub struct SyslogLogSource {
+    name: String,
+    udp: Option<SocketAddr>,
+    tcp: Option<SocketAddr>,
+    max_connections: usize,
+    health: Mutex<SourceHealth>,
+}
+
+impl SyslogLogSource {
+    pub fn new(config: &SyslogConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            udp: config.udp,
+            tcp: config.tcp,
+            max_connections: config.max_connections,
+            health: Mutex::new(SourceHealth::Starting),
+        }
+    }
+
+    fn set_health(&self, health: SourceHealth) {
+        *self.health.lock().unwrap() = health;
+    }
+}
+
+#[async_trait]
+impl LogSource for SyslogLogSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn health(&self) -> SourceHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Messages are pushed by the senders, there is nothing to resume from.
+    fn position(&self) -> Option<SourcePosition> {
+        None
+    }
+
+    async fn follow(
+        &self,
+        dispatcher: Arc<LogDispatcher>,
+        _position: Option<SourcePosition>,
+    ) -> Result<()> {
+        self.set_health(SourceHealth::Starting);
+        if self.udp.is_none() && self.tcp.is_none() {
+            anyhow::bail!("no udp or tcp address to listen on");
+        }
+
+        let udp = match self.udp {
+            Some(addr) => Some(UdpSocket::bind(addr).await?),
+            None => None,
+        };
+        let tcp = match self.tcp {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        // dropped, and so aborted, with the source
+        let mut connections = JoinSet::new();
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+
+        self.set_health(SourceHealth::Running);
+        loop {
+            // the other connections wait in the backlog of the listener
+            let accepting = tcp.is_some() && connections.len() < self.max_connections;
+            tokio::select! {
+                received = async { udp.as_ref().unwrap().recv_from(&mut buf).await }, if udp.is_some() => {
+                    let (len, peer) = received?;
+                    let entry = parse_message(&buf[..len], &peer.ip().to_string(), Local::now());
+                    dispatcher.send(entry).await;
+                },
+                accepted = async { tcp.as_ref().unwrap().accept().await }, if accepting => {
+                    let (stream, peer) = accepted?;
+                    connections.spawn(handle_connection(stream, peer, dispatcher.clone()));
+                },
+                Some(result) = connections.join_next() => {
+                    if let Ok(Err(err)) = result {
+                        tracing::info!("syslog connection closed: {}", err);
+                    }
+                },
+            }
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    dispatcher: Arc<LogDispatcher>,
+) -> Result<()> {
+    let peer = peer.ip().to_string();
+    let mut reader = BufReader::new(stream);
+    while let Some(frame) = read_frame(&mut reader).await? {
+        dispatcher
+            .send(parse_message(&frame, &peer, Local::now()))
+            .await;
+    }
+    Ok(())
+}
+
+/// Read the next message of a TCP stream (RFC 6587), either octet-counted
+/// (`LEN SP MSG`) or terminated by a newline. The framing is detected
+/// for every message. Returns None at the end of the stream.
+async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let first = match reader.fill_buf().await?.first() {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+
+    let mut frame = vec![];
+    if first.is_ascii_digit() {
+        let mut len = vec![];
+        (&mut *reader)
+            .take(MAX_LEN_DIGITS + 1)
+            .read_until(b' ', &mut len)
+            .await?;
+        let Some(len) = len.strip_suffix(b" ") else {
+            anyhow::bail!("invalid octet count");
+        };
+        let len: usize = std::str::from_utf8(len)?.parse()?;
+        if len > MAX_MESSAGE_LEN {
+            anyhow::bail!("message too long: {} bytes", len);
+        }
+        frame.resize(len, 0);
+        reader.read_exact(&mut frame).await?;
+    } else {
+        (&mut *reader)
+            .take(MAX_MESSAGE_LEN as u64)
+            .read_until(b'\n', &mut frame)
+            .await?;
+        if frame.len() == MAX_MESSAGE_LEN && frame.last() != Some(&b'\n') {
+            anyhow::bail!("message too long");
+        }
+    }
+    Ok(Some(frame))
+}
+
+/// Build an entry from a syslog message, in either format.
+/// `peer` is the hostname used when the message does not include one,
+/// `received` the timestamp when it has none.
+fn parse_message(message: &[u8], peer: &str, received: DateTime<Local>) -> LogEntry {
+    let message = String::from_utf8_lossy(message);
+    let message = message.trim_end_matches(['\n', '\r', '\0']);
+
+    let mut entry = LogEntry {
+        message: message.to_string(),
+        hostname: peer.to_string(),
+        service: String::new(),
+        timestamp: received.naive_utc(),
+        // RFC 3164 4.3.3: messages without PRI are user.notice
+        level: Some(Level::Notice),
+        fields: BTreeMap::from([("SYSLOG_FACILITY".to_string(), "1".to_string())]),
+    };
+
+    let Some((pri, rest)) = parse_pri(message) else {
+        return entry;
+    };
+    entry.level = Level::from_priority(pri % 8);
+    entry
+        .fields
+        .insert("SYSLOG_FACILITY".to_string(), (pri / 8).to_string());
+
+    match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest, &mut entry),
+        None => parse_rfc3164(rest, received, &mut entry),
+    }
+    entry
+}
+
+/// `<PRI>` at the start of the message
+fn parse_pri(message: &str) -> Option<(u8, &str)> {
+    let (pri, rest) = message.strip_prefix('<')?.split_once('>')?;
+    if pri.is_empty() || pri.len() > 3 || !pri.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let pri = pri.parse().ok().filter(|pri| *pri <= 191)?;
+    Some((pri, rest))
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after the version.
+/// Missing parts are left as set by `parse_message`.
+fn parse_rfc5424(message: &str, entry: &mut LogEntry) {
+    let mut parts = message.splitn(6, ' ');
+    let mut next = || parts.next().filter(|part| *part != "-");
+
+    if let Some(timestamp) = next().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+        entry.timestamp = timestamp.naive_utc();
+    }
+    if let Some(hostname) = next() {
+        entry.hostname = hostname.to_string();
+    }
+    if let Some(app_name) = next() {
+        entry.service = app_name.to_string();
+        entry
+            .fields
+            .insert("SYSLOG_IDENTIFIER".to_string(), app_name.to_string());
+    }
+    if let Some(procid) = next() {
+        entry
+            .fields
+            .insert("SYSLOG_PID".to_string(), procid.to_string());
+    }
+    if let Some(msgid) = next() {
+        entry
+            .fields
+            .insert("SYSLOG_MSGID".to_string(), msgid.to_string());
+    }
+
+    let rest = parts.next().unwrap_or_default();
+    let rest = match rest.strip_prefix('-') {
+        Some(rest) => rest,
+        None => parse_structured_data(rest, &mut entry.fields),
+    };
+    let rest = rest.strip_prefix(' ').unwrap_or(rest);
+    entry.message = rest.strip_prefix('\u{feff}').unwrap_or(rest).to_string();
+}
+
+/// Add the `[id name="value" ...]` elements to the fields as `id.name`,
+/// and return what follows them.
+fn parse_structured_data<'a>(mut data: &'a str, fields: &mut BTreeMap<String, String>) -> &'a str {
+    while let Some(element) = data.strip_prefix('[') {
+        let Some((id, mut params)) = element.split_once([' ', ']']) else {
+            return data;
+        };
+        // split_once consumed the ']' of an element without parameters
+        if element.as_bytes()[id.len()] == b']' {
+            data = params;
+            continue;
+        }
+        loop {
+            params = params.trim_start_matches(' ');
+            if let Some(rest) = params.strip_prefix(']') {
+                data = rest;
+                break;
+            }
+            let Some((name, value)) = params.split_once("=\"") else {
+                return data;
+            };
+            let Some((value, rest)) = parse_param_value(value) else {
+                return data;
+            };
+            fields.insert(format!("{id}.{name}"), value);
+            params = rest;
+        }
+    }
+    data
+}
+
+/// Value of a parameter up to its closing quote, where `"`, `\` and `]` are escaped.
+fn parse_param_value(data: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = data.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &data[i + 1..])),
+            '\\' => match chars.next()? {
+                (_, c @ ('"' | '\\' | ']')) => value.push(c),
+                (_, c) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG`, after the PRI.
+/// The timestamp has no year nor time zone, the local time zone of the agent
+/// is assumed, and the year which puts it closest to `received`.
+fn parse_rfc3164(message: &str, received: DateTime<Local>, entry: &mut LogEntry) {
+    let Some((timestamp, rest)) = parse_rfc3164_timestamp(message, received) else {
+        entry.message = message.to_string();
+        return;
+    };
+    entry.timestamp = timestamp;
+
+    // some senders omit the hostname, the first word is then the tag
+    let rest = match rest.split_once(' ') {
+        Some((hostname, rest)) if !hostname.ends_with(':') && !hostname.contains('[') => {
+            entry.hostname = hostname.to_string();
+            rest
+        }
+        _ => rest,
+    };
+
+    let tag_len = rest.find(['[', ':', ' ']).unwrap_or(rest.len());
+    let (tag, mut rest) = rest.split_at(tag_len);
+    if !tag.is_empty() {
+        entry.service = tag.to_string();
+        entry
+            .fields
+            .insert("SYSLOG_IDENTIFIER".to_string(), tag.to_string());
+    }
+    if let Some((pid, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        entry
+            .fields
+            .insert("SYSLOG_PID".to_string(), pid.to_string());
+        rest = after;
+    }
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    entry.message = rest.strip_prefix(' ').unwrap_or(rest).to_string();
+}
+
+fn parse_rfc3164_timestamp(
+    message: &str,
+    received: DateTime<Local>,
+) -> Option<(NaiveDateTime, &str)> {
+    // "Oct 11 22:14:15 ", the day is padded with a space
+    let timestamp = message.get(..15)?;
+    let rest = message[15..].strip_prefix(' ')?;
+    let year = received.year();
+    let parse = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{year} {timestamp}"), "%Y %b %e %H:%M:%S")
+            .ok()
+            .and_then(|t| Local.from_local_datetime(&t).earliest())
+    };
+    let timestamp = [year - 1, year, year + 1]
+        .into_iter()
+        .filter_map(parse)
+        .min_by_key(|t| (*t - received).num_seconds().abs())?;
+    Some((timestamp.naive_utc(), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Local, NaiveDate, TimeZone};
+
+    use minink_common::Level;
+
+    use super::{parse_message, read_frame};
+
+    fn received() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2023, 1, 2, 3, 4, 5).unwrap()
+    }
+
+    #[test]
+    fn test_parse_rfc5424() {
+        let message = br#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventSource="Appli\"cation" eventID="1011"][examplePriority@32473 class="high"] An application event log entry..."#;
+        let entry = parse_message(message, "10.0.0.1", received());
+        assert_eq!(entry.hostname, "mymachine.example.com");
+        assert_eq!(entry.service, "evntslog");
+        assert_eq!(entry.level, Some(Level::Notice));
+        assert_eq!(
+            entry.timestamp,
+            NaiveDate::from_ymd_opt(2003, 10, 11)
+                .unwrap()
+                .and_hms_milli_opt(22, 14, 15, 3)
+                .unwrap()
+        );
+        assert_eq!(entry.message, "An application event log entry...");
+        assert_eq!(entry.fields["SYSLOG_FACILITY"], "20");
+        assert_eq!(entry.fields["SYSLOG_MSGID"], "ID47");
+        assert!(!entry.fields.contains_key("SYSLOG_PID"));
+        assert_eq!(entry.fields["exampleSDID@32473.iut"], "3");
+        assert_eq!(
+            entry.fields["exampleSDID@32473.eventSource"],
+            "Appli\"cation"
+        );
+        assert_eq!(entry.fields["examplePriority@32473.class"], "high");
+    }
+
+    #[test]
+    fn test_parse_rfc5424_nil_values() {
+        let message = "<34>1 - - su - - - \u{feff}'su root' failed\n".as_bytes();
+        let entry = parse_message(message, "10.0.0.1", received());
+        assert_eq!(entry.hostname, "10.0.0.1");
+        assert_eq!(entry.service, "su");
+        assert_eq!(entry.level, Some(Level::Crit));
+        assert_eq!(entry.timestamp, received().naive_utc());
+        assert_eq!(entry.message, "'su root' failed");
+    }
+
+    #[test]
+    fn test_parse_rfc3164() {
+        let message =
+            b"<34>Dec 31 22:14:15 mymachine su[123]: 'su root' failed for lonvick on /dev/pts/8";
+        let entry = parse_message(message, "10.0.0.1", received());
+        assert_eq!(entry.hostname, "mymachine");
+        assert_eq!(entry.service, "su");
+        assert_eq!(entry.level, Some(Level::Crit));
+        assert_eq!(entry.fields["SYSLOG_FACILITY"], "4");
+        assert_eq!(entry.fields["SYSLOG_PID"], "123");
+        assert_eq!(entry.message, "'su root' failed for lonvick on /dev/pts/8");
+        // closer to the time it was received than the next year's date
+        let expected = Local.with_ymd_and_hms(2022, 12, 31, 22, 14, 15).unwrap();
+        assert_eq!(entry.timestamp, expected.naive_utc());
+    }
+
+    #[test]
+    fn test_parse_rfc3164_without_hostname() {
+        let entry = parse_message(b"<13>Jan  2 03:00:00 sshd: hello", "10.0.0.1", received());
+        assert_eq!(entry.hostname, "10.0.0.1");
+        assert_eq!(entry.service, "sshd");
+        assert_eq!(entry.message, "hello");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let entry = parse_message(b"no header at all", "10.0.0.1", received());
+        assert_eq!(entry.message, "no header at all");
+        assert_eq!(entry.level, Some(Level::Notice));
+        assert_eq!(entry.timestamp, received().naive_utc());
+
+        let entry = parse_message(b"<13>not a date", "10.0.0.1", received());
+        assert_eq!(entry.message, "not a date");
+        assert_eq!(entry.service, "");
+    }
+
+    #[tokio::test]
+    async fn test_read_frames() {
+        let mut data: &[u8] = b"11 <13>1 - - -first\n<13>second\r\n<13>last";
+        let mut frames = vec![];
+        while let Some(frame) = read_frame(&mut data).await.unwrap() {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+        assert_eq!(
+            frames,
+            ["<13>1 - - -", "first\n", "<13>second\r\n", "<13>last"]
+        );
+
+        let mut data: &[u8] = b"100000 <13>";
+        assert!(read_frame(&mut data).await.is_err());
+
+        // an octet count that never ends is not read further
+        let mut data = [b'1'; 1000].as_slice();
+        assert!(read_frame(&mut data).await.is_err());
+        assert_eq!(data.len(), 1000 - 11);
+        let mut data: &[u8] = b"12";
+        assert!(read_frame(&mut data).await.is_err());
+    }
+}
@@ -1,37 +1,217 @@
-use std::ops::{Bound, RangeBounds};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    str::FromStr,
+};
 
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
 pub type ServiceName = String;
 
+/// Severity of an entry, as the syslog priority: `Emerg` (0) is the most severe
+/// and `Debug` (7) the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Level {
+    pub const ALL: [Level; 8] = [
+        Level::Emerg,
+        Level::Alert,
+        Level::Crit,
+        Level::Err,
+        Level::Warning,
+        Level::Notice,
+        Level::Info,
+        Level::Debug,
+    ];
+
+    pub fn from_priority(priority: u8) -> Option<Self> {
+        Self::ALL.get(priority as usize).copied()
+    }
+
+    pub fn priority(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Emerg => "emerg",
+            Level::Alert => "alert",
+            Level::Crit => "crit",
+            Level::Err => "err",
+            Level::Warning => "warning",
+            Level::Notice => "notice",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLevelError(String);
+
+impl std::fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid level '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseLevelError {}
+
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    /// Accepts the names used by journalctl or the numeric priority.
+    fn from_str(s: &str) -> Result<Self, ParseLevelError> {
+        let level = match s.to_lowercase().as_str() {
+            "emerg" => Some(Level::Emerg),
+            "alert" => Some(Level::Alert),
+            "crit" => Some(Level::Crit),
+            "err" | "error" => Some(Level::Err),
+            "warning" | "warn" => Some(Level::Warning),
+            "notice" => Some(Level::Notice),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            s => s.parse().ok().and_then(Level::from_priority),
+        };
+        level.ok_or_else(|| ParseLevelError(s.to_string()))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LogEntry {
     pub message: String,
     pub hostname: String,
     pub service: ServiceName,
     pub timestamp: NaiveDateTime,
+    #[serde(default)]
+    pub level: Option<Level>,
+    /// other structured fields of the entry, such as `_PID` or `CODE_FILE` for journald
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Order of the entries returned by `/api/extract`, which is also the
+/// direction of the following pages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// oldest first
+    #[default]
+    Asc,
+    /// newest first
+    Desc,
+}
+
+/// A page of entries returned by `/api/extract`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtractPage {
+    pub entries: Vec<LogEntry>,
+    /// given as `cursor` to get the next page, None on the last one
+    pub next: Option<String>,
+}
+
+/// What the entries of a histogram are counted by, besides their time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Service,
+    Host,
+    Level,
+}
+
+/// Number of entries per time interval, returned by `/api/histogram`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub interval_ms: u64,
+    /// only the intervals with entries, oldest first
+    pub buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    /// the intervals start at multiples of the interval since the epoch
+    pub start: NaiveDateTime,
+    pub count: u64,
+    /// entries of each service, host or level if grouped, the entries
+    /// without level in `none`
+    #[serde(default)]
+    pub groups: BTreeMap<String, u64>,
+}
+
+/// Sent to a live client instead of an entry, when the client was too slow and
+/// some entries were dropped since the previous notice.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DroppedNotice {
+    pub dropped: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Filter {
     /// if Some, filter logs with only specific services
     pub services: Option<Vec<ServiceName>>,
+    /// if Some, filter logs from hosts whose name starts with one of these
+    #[serde(default)]
+    pub hosts: Option<Vec<String>>,
     /// if Some, filter logs with that contains one of the keywords in the message
     pub message_keywords: Option<Vec<String>>,
     pub timerange: (Bound<NaiveDateTime>, Bound<NaiveDateTime>),
+    /// range of levels in priority order, for example `(Unbounded, Included(Level::Err))`
+    /// for errors and worse; entries without level are only accepted if unbounded
+    #[serde(default = "unbounded")]
+    pub levels: (Bound<Level>, Bound<Level>),
+    /// if Some, filter logs having all these fields with exactly these values
+    #[serde(default)]
+    pub fields: Option<BTreeMap<String, String>>,
+}
+
+fn unbounded<T>() -> (Bound<T>, Bound<T>) {
+    (Bound::Unbounded, Bound::Unbounded)
 }
 
 impl Default for Filter {
     fn default() -> Self {
         Self {
             services: Default::default(),
+            hosts: Default::default(),
             message_keywords: Default::default(),
-            timerange: (Bound::Unbounded, Bound::Unbounded),
+            timerange: unbounded(),
+            levels: unbounded(),
+            fields: Default::default(),
         }
     }
 }
 
+/// Parse a level range like journalctl's `--priority`: either a single level,
+/// meaning that level and more severe ones, or `FROM..TO`.
+pub fn parse_level_range(s: &str) -> Result<(Bound<Level>, Bound<Level>), ParseLevelError> {
+    match s.split_once("..") {
+        Some((from, to)) => {
+            let from: Level = from.parse()?;
+            let to: Level = to.parse()?;
+            Ok((Bound::Included(from.min(to)), Bound::Included(from.max(to))))
+        }
+        None => Ok((Bound::Unbounded, Bound::Included(s.parse()?))),
+    }
+}
+
 fn tokenize(line: &str) -> Vec<String> {
     let tokens: Vec<String> = line
         .split_terminator(|c: char| !c.is_alphanumeric())
@@ -40,19 +220,32 @@ fn tokenize(line: &str) -> Vec<String> {
     tokens
 }
 
+fn matches_patterns(tokens: &[String], patterns: &[String]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| tokens.iter().any(|token| token.starts_with(pattern)))
+}
+
 impl Filter {
-    pub fn accept(&self, entry: &LogEntry) -> bool {
-        let matches_patterns = |tokens: &[String], patterns: &[String]| -> bool {
-            patterns
-                .iter()
-                .any(|pattern| tokens.iter().any(|token| token.starts_with(pattern)))
-        };
+    /// Whether the entries of `service` may be accepted.
+    pub fn accept_service(&self, service: &str) -> bool {
+        match &self.services {
+            Some(services) => matches_patterns(&tokenize(service), services),
+            None => true,
+        }
+    }
 
-        if let Some(services) = &self.services {
-            let entry_service_tokens = tokenize(&entry.service);
-            if !matches_patterns(&entry_service_tokens, services) {
-                return false;
-            }
+    /// Whether the entries of `hostname` may be accepted.
+    pub fn accept_host(&self, hostname: &str) -> bool {
+        match &self.hosts {
+            Some(hosts) => hosts.iter().any(|host| hostname.starts_with(host)),
+            None => true,
+        }
+    }
+
+    pub fn accept(&self, entry: &LogEntry) -> bool {
+        if !self.accept_service(&entry.service) || !self.accept_host(&entry.hostname) {
+            return false;
         }
 
         if let Some(message_keywords) = &self.message_keywords {
@@ -66,6 +259,22 @@ impl Filter {
             return false;
         }
 
+        if self.levels != unbounded() {
+            match entry.level {
+                Some(level) if self.levels.contains(&level) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(fields) = &self.fields {
+            let matches = fields
+                .iter()
+                .all(|(name, value)| entry.fields.get(name) == Some(value));
+            if !matches {
+                return false;
+            }
+        }
+
         true
     }
 }
@@ -1,9 +1,9 @@
 use anyhow::Result;
 use futures::{
-    stream::{FuturesUnordered, SplitSink, SplitStream},
-    StreamExt,
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
 };
-use minink_common::{Filter, LogEntry};
+use minink_common::{DroppedNotice, ExtractPage, Filter, Level, LogEntry};
 use ratatui::widgets::TableState;
 use tokio::net::TcpStream;
 use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
@@ -53,6 +53,15 @@ impl<T> StatefulTable<T> {
         }
     }
 
+    /// Insert older items before the others, keeping the same one selected.
+    pub fn prepend(&mut self, items: Vec<T>) {
+        let count = items.len();
+        self.items.splice(0..0, items);
+        if let Some(selected) = self.state.selected() {
+            self.state.select(Some(selected + count));
+        }
+    }
+
     pub fn push(&mut self, entry: T) {
         let following = self.following();
         self.items.push(entry);
@@ -64,13 +73,14 @@ impl<T> StatefulTable<T> {
 }
 
 pub struct EndpointConnection {
-    write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-    read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    read: WebSocketStream<MaybeTlsStream<TcpStream>>,
 }
 
 pub struct Endpoint {
     pub url: String,
     pub connection: Option<EndpointConnection>,
+    /// cursor of the next older page of entries, if there are more
+    pub next_page: Option<String>,
 }
 
 impl Endpoint {
@@ -78,10 +88,23 @@ impl Endpoint {
         Self {
             url: url.to_string(),
             connection: None,
+            next_page: None,
         }
     }
 }
 
+/// A page of the entries matching `filter`, newest first.
+async fn fetch_page(url: &str, filter: &Filter, cursor: Option<&str>) -> Result<ExtractPage> {
+    let mut request = reqwest::Client::new()
+        .post(format!("{url}/api/extract"))
+        .query(&[("order", "desc")])
+        .json(filter);
+    if let Some(cursor) = cursor {
+        request = request.query(&[("cursor", cursor)]);
+    }
+    Ok(request.send().await?.json().await?)
+}
+
 pub struct App {
     pub endpoints: Vec<Endpoint>,
     pub logs: StatefulTable<LogEntry>,
@@ -123,6 +146,9 @@ impl App {
             'r' => {
                 self.refresh().await?;
             }
+            'm' => {
+                self.fetch_older().await?;
+            }
             _ => {}
         }
         Ok(())
@@ -134,15 +160,30 @@ impl App {
         let mut futures = FuturesUnordered::new();
         for e in &mut self.endpoints {
             if let Some(connection) = &mut e.connection {
-                let f = connection.read.next();
+                let url = &e.url;
+                let f = connection.read.next().map(move |message| (url, message));
                 futures.push(f);
             }
         }
 
-        if let Some(a) = futures.next().await {
+        if let Some((url, a)) = futures.next().await {
             match a {
                 Some(Ok(Message::Text(t))) => {
-                    let entry: LogEntry = serde_json::from_str(&t)?;
+                    let entry = match serde_json::from_str::<LogEntry>(&t) {
+                        Ok(entry) => entry,
+                        // the agent dropped entries because we were too slow
+                        Err(_) => {
+                            let notice: DroppedNotice = serde_json::from_str(&t)?;
+                            LogEntry {
+                                message: format!("{} entries were dropped", notice.dropped),
+                                hostname: url.clone(),
+                                service: "minink".to_string(),
+                                timestamp: chrono::Utc::now().naive_utc(),
+                                level: Some(Level::Warning),
+                                fields: Default::default(),
+                            }
+                        }
+                    };
                     self.logs.push(entry);
                 }
                 _ => {
@@ -160,23 +201,28 @@ impl App {
         self.logs.items.clear();
         let filter = &self.filter;
         for e in &mut self.endpoints {
-            let client = reqwest::Client::new();
-            let res = client
-                .post(format!("{}/api/extract", e.url))
-                .json(filter)
-                .send()
-                .await?
-                .json::<Vec<LogEntry>>()
-                .await?;
-
-            self.logs.items.extend(res);
+            let page = fetch_page(&e.url, filter, None).await?;
+            e.next_page = page.next;
+            self.logs.items.extend(page.entries.into_iter().rev());
 
             let ws_url = e.url.replace("http", "ws") + "/ws/live";
             let (ws_stream, _) = tokio_tungstenite::connect_async(&ws_url).await?;
-            let (write, read) = ws_stream.split();
-            e.connection = Some(EndpointConnection { write, read });
+            e.connection = Some(EndpointConnection { read: ws_stream });
         }
 
         Ok(())
     }
+
+    /// Add the previous page of every endpoint before the entries.
+    pub async fn fetch_older(&mut self) -> Result<()> {
+        for e in &mut self.endpoints {
+            let Some(cursor) = e.next_page.take() else {
+                continue;
+            };
+            let page = fetch_page(&e.url, &self.filter, Some(&cursor)).await?;
+            e.next_page = page.next;
+            self.logs.prepend(page.entries.into_iter().rev().collect());
+        }
+        Ok(())
+    }
 }
@@ -11,7 +11,7 @@ use crate::form::FormComponent;
 
 use crate::logtable::LogTable;
 
-use minink_common::LogEntry;
+use minink_common::{ExtractPage, LogEntry};
 type Result<T> = core::result::Result<T, JsError>;
 
 pub enum Msg {
@@ -61,7 +61,7 @@ impl Component for App {
 
         Self {
             entries: vec![],
-            hosts: hosts,
+            hosts,
             services: None
         }
     }
@@ -129,14 +129,15 @@ async fn fetch_logs(hosts: &[String], services : &Option<String>) -> Result<Vec<
             Some(v) => v,
             None => ""
             
-        })]);
+        }), ("order", "desc")]);
         
-        let entries: Vec<LogEntry> = request.method(gloo_net::http::Method::GET)
+        let page: ExtractPage = request.method(gloo_net::http::Method::GET)
             .send()
             .await?
             .json()
             .await?;
-        allentries.extend(entries);
+        // newest first
+        allentries.extend(page.entries.into_iter().rev());
     }
     Ok(allentries)
 }
@@ -1,12 +1,6 @@
 use yew::prelude::*;
 use gloo_console::log;
 use web_sys::{HtmlInputElement};
-#[derive(Properties, PartialEq)]
-pub struct FormProps {
-    pub hosts: Vec<String>,
-    pub callback_hosts : Callback<Vec<String>>
-}
-
 #[derive(Properties, PartialEq)]
 pub struct Props {
     pub host: String,
@@ -18,7 +12,7 @@ pub enum Msg {
     UpdateHosts(String),
     UpdateFilterServices(String),
     UpdateFilterKeywords(String),
-    UpdateLive(bool),
+    UpdateLive,
     Validate
 }
 
@@ -61,7 +55,7 @@ impl Component for FormComponent {
 
                 true
             },
-            Msg::UpdateLive(_)=> {
+            Msg::UpdateLive=> {
                 self.is_live = !self.is_live;
                 log!(format!("{}", self.is_live.clone()));
                 true
@@ -92,9 +86,9 @@ impl Component for FormComponent {
             <div class="row">
             <label for="live"> { "LIVE" }</label>
             <input type="checkbox" name="live" id="live-button" 
-            checked={self.is_live.clone()}
+            checked={self.is_live}
             onclick={ctx.link().callback(|_ : MouseEvent| {
-                Msg::UpdateLive(true)})}/>
+                Msg::UpdateLive})}/>
             </div>
 
             <div id="filter">